@@ -0,0 +1,98 @@
+//! QuickJS bytecode for scripts compiled once and evaluated in many contexts.
+//!
+//! rquickjs only serializes modules, and module code is always strict, so plain scripts
+//! go through the raw QuickJS API here. QuickJS does not validate bytecode when reading
+//! it, so `load_script` must only ever see bytes produced by `compile_script` in this
+//! process, never bytes from a request.
+
+use rquickjs::{qjs, Ctx, Error, Value};
+use std::ffi::{CStr, CString};
+
+/// File name compiled scripts report in stack traces, matching `Ctx::eval`
+const FILE_NAME: &CStr = c"eval_script";
+
+/// Compile `source` as a global script without running any of it, returning the owned
+/// compiled function, or an exception value with the syntax error left pending
+///
+/// # Safety
+/// `raw` must be a live context; a non-exception result must be freed exactly once.
+unsafe fn compile_only(raw: *mut qjs::JSContext, source: &CString) -> qjs::JSValue {
+    let eval_flags = (qjs::JS_EVAL_TYPE_GLOBAL | qjs::JS_EVAL_FLAG_COMPILE_ONLY) as i32;
+    qjs::JS_Eval(
+        raw,
+        source.as_ptr(),
+        source.as_bytes().len() as _,
+        FILE_NAME.as_ptr(),
+        eval_flags,
+    )
+}
+
+/// Check that `source` parses as a global script, without running it. A syntax error
+/// is left pending on the context, so callers can `.catch()` it.
+pub fn check_syntax(ctx: &Ctx<'_>, source: &str) -> rquickjs::Result<()> {
+    let source = CString::new(source)?;
+    let raw = ctx.as_raw().as_ptr();
+
+    // SAFETY: `raw` is the live context behind `ctx`, and the compiled function is
+    // freed exactly once
+    unsafe {
+        let compiled = compile_only(raw, &source);
+        if qjs::JS_IsException(compiled) {
+            return Err(Error::Exception);
+        }
+        qjs::JS_FreeValue(raw, compiled);
+    }
+    Ok(())
+}
+
+/// Compile `source` as a global script without running it and serialize the result.
+/// A syntax error is left pending on the context, so callers can `.catch()` it.
+pub fn compile_script(ctx: &Ctx<'_>, source: &str) -> rquickjs::Result<Vec<u8>> {
+    let source = CString::new(source)?;
+    let raw = ctx.as_raw().as_ptr();
+
+    // SAFETY: `raw` is the live context behind `ctx`, `source` outlives the call, and the
+    // compiled function is freed exactly once below
+    unsafe {
+        let compiled = compile_only(raw, &source);
+        if qjs::JS_IsException(compiled) {
+            return Err(Error::Exception);
+        }
+
+        let mut len = 0;
+        let write_flags = qjs::JS_WRITE_OBJ_BYTECODE as i32;
+        let buffer = qjs::JS_WriteObject(raw, &mut len, compiled, write_flags);
+        qjs::JS_FreeValue(raw, compiled);
+        if buffer.is_null() {
+            return Err(Error::Exception);
+        }
+        let bytecode = std::slice::from_raw_parts(buffer, len as usize).to_vec();
+        qjs::js_free(raw, buffer.cast());
+        Ok(bytecode)
+    }
+}
+
+/// Evaluate bytecode from `compile_script`, returning the script's completion value
+pub fn load_script<'js>(ctx: &Ctx<'js>, bytecode: &[u8]) -> rquickjs::Result<Value<'js>> {
+    let raw = ctx.as_raw().as_ptr();
+
+    // SAFETY: the bytes come from `compile_script` with the same QuickJS build (see the
+    // module docs); JS_EvalFunction takes ownership of the function it is given, and the
+    // returned value's reference is handed to `Value`
+    unsafe {
+        let function = qjs::JS_ReadObject(
+            raw,
+            bytecode.as_ptr(),
+            bytecode.len() as _,
+            qjs::JS_READ_OBJ_BYTECODE as i32,
+        );
+        if qjs::JS_IsException(function) {
+            return Err(Error::Exception);
+        }
+        let value = qjs::JS_EvalFunction(raw, function);
+        if qjs::JS_IsException(value) {
+            return Err(Error::Exception);
+        }
+        Ok(Value::from_raw(ctx.clone(), value))
+    }
+}
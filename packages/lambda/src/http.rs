@@ -0,0 +1,159 @@
+//! The transport behind the sandbox's fetch.
+//!
+//! fetch applies every policy check (allowlist, path rules, limits, budgets) before a
+//! request reaches an [`HttpClient`], so a replacement transport only has to move bytes.
+//! Tests inject a mock to exercise fetch without the network.
+
+use anyhow::Result;
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+/// Default timeout for a single request when the script sets none
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Size of each read while draining a response body
+const BODY_READ_CHUNK_SIZE: usize = 16 * 1024;
+
+/// A fetch request that passed every sandbox check
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    /// Uppercase method name
+    pub method: String,
+    pub url: String,
+    /// Headers in send order, with default headers appended; repeated names are all sent
+    pub headers: Vec<(String, String)>,
+    pub body: Option<String>,
+    /// Overrides the transport's default timeout
+    pub timeout: Option<Duration>,
+    /// The execution deadline; transports stop reading the body once it passes, so a
+    /// slow trickle of bytes can't outlive the run
+    pub deadline: Option<Instant>,
+    /// Largest body the script accepts; transports stop reading once the body grows past
+    /// it, so an oversized response is never buffered whole
+    pub max_body_bytes: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: u16,
+    /// Lowercase header names in received order, one entry per value
+    pub headers: Vec<(String, String)>,
+    /// The decoded (decompressed) body
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HttpError {
+    /// The request did not complete within its timeout
+    #[error("HTTP request failed: {0}")]
+    Timeout(String),
+
+    /// The request could not be sent or no response arrived
+    #[error("HTTP request failed: {0}")]
+    Request(String),
+
+    /// The response arrived but its body could not be read
+    #[error("Failed to read response: {0}")]
+    Body(String),
+
+    /// The response body is larger than the request's `max_body_bytes`
+    #[error("Response body exceeds maximum size of {0} bytes")]
+    BodyTooLarge(usize),
+}
+
+/// Read a response body in chunks, failing with a timeout if `deadline` passes between
+/// chunks and as soon as the body grows past `max_bytes`
+pub fn read_body(
+    mut reader: impl Read,
+    deadline: Option<Instant>,
+    max_bytes: Option<usize>,
+) -> Result<Vec<u8>, HttpError> {
+    let mut body = Vec::new();
+    let mut chunk = vec![0; BODY_READ_CHUNK_SIZE];
+    loop {
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(HttpError::Timeout("response body incomplete at the deadline".to_string()));
+        }
+        match reader.read(&mut chunk) {
+            Ok(0) => return Ok(body),
+            Ok(read) => {
+                body.extend_from_slice(&chunk[..read]);
+                if let Some(max_bytes) = max_bytes.filter(|max| body.len() > *max) {
+                    return Err(HttpError::BodyTooLarge(max_bytes));
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(HttpError::Body(e.to_string())),
+        }
+    }
+}
+
+/// Sends the requests made by fetch; blocking, like the fetch it backs
+pub trait HttpClient: Send + Sync {
+    fn send(&self, request: HttpRequest) -> std::result::Result<HttpResponse, HttpError>;
+}
+
+/// The production transport: reqwest with gzip, brotli and deflate decoding
+pub struct ReqwestClient {
+    client: reqwest::blocking::Client,
+}
+
+impl ReqwestClient {
+    pub fn new() -> Result<Self> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(DEFAULT_REQUEST_TIMEOUT)
+            .gzip(true)
+            .brotli(true)
+            .deflate(true)
+            .build()
+            .map_err(|e| anyhow::anyhow!("Failed to create HTTP client: {}", e))?;
+        Ok(ReqwestClient { client })
+    }
+}
+
+impl HttpClient for ReqwestClient {
+    fn send(&self, request: HttpRequest) -> std::result::Result<HttpResponse, HttpError> {
+        let method = reqwest::Method::from_bytes(request.method.as_bytes())
+            .map_err(|e| HttpError::Request(e.to_string()))?;
+        let mut builder = self.client.request(method, &request.url);
+        for (name, value) in &request.headers {
+            builder = builder.header(name, value);
+        }
+        if let Some(body) = request.body {
+            builder = builder.body(body);
+        }
+        if let Some(timeout) = request.timeout {
+            builder = builder.timeout(timeout);
+        }
+
+        let deadline = request.deadline;
+        let max_body_bytes = request.max_body_bytes;
+        let to_error = |e: reqwest::Error| {
+            if e.is_timeout() {
+                HttpError::Timeout(e.to_string())
+            } else {
+                HttpError::Request(e.to_string())
+            }
+        };
+        let response = builder.send().map_err(to_error)?;
+
+        // A declared length over the limit fails before any of the body is read
+        if let Some(max_bytes) = max_body_bytes {
+            if response.content_length().is_some_and(|length| length > max_bytes as u64) {
+                return Err(HttpError::BodyTooLarge(max_bytes));
+            }
+        }
+
+        let status = response.status().as_u16();
+        let headers = response
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                (name.as_str().to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned())
+            })
+            .collect();
+        let body = read_body(response, deadline, max_body_bytes)?;
+
+        Ok(HttpResponse { status, headers, body })
+    }
+}
@@ -0,0 +1,308 @@
+//! Lightweight source instrumentation for user code.
+//!
+//! This is a tokenizer, not a parser: it only needs to find the opening brace of
+//! every function body and catch block while skipping strings, template literals,
+//! comments and regular expressions. Injected code never contains newlines, so line
+//! numbers in error messages still match the user's source.
+
+/// Keywords whose parenthesised clause is followed by a plain block, not a function body
+/// (catch clauses are recognised by the try block before them instead)
+const CONTROL_KEYWORDS: &[&str] = &["if", "for", "while", "switch", "with"];
+
+/// Keywords after which a `/` starts a regular expression literal rather than a division
+const REGEX_PREFIX_KEYWORDS: &[&str] = &[
+    "return", "typeof", "instanceof", "in", "of", "new", "delete", "void", "throw", "case", "do",
+    "else", "yield", "await",
+];
+
+/// The last significant token seen, which is all the context the scanner needs
+enum Token {
+    Start,
+    Word(String),
+    Punct(char),
+    Arrow,
+    /// A closing paren, with the token that preceded its opening paren
+    CloseParen(Option<String>),
+    /// The closing brace of a try block
+    TryBlockEnd,
+    /// `catch` right after a try block, as opposed to a method or property named catch
+    CatchKeyword,
+    /// The closing paren of a catch clause, with the parameter text inside it
+    CatchClause(String),
+    Literal,
+}
+
+/// What an open brace belongs to
+#[derive(PartialEq)]
+enum Brace {
+    /// A `${` substitution inside a template literal
+    Template,
+    Try,
+    Other,
+}
+
+/// Insert `statement` at the start of every function body in `code`.
+pub fn inject_function_prologue(code: &str, statement: &str) -> String {
+    inject_block_prologues(code, |prev| is_function_body(prev).then(|| statement.to_string()))
+}
+
+/// Insert `prologue(name)` at the start of every catch block whose parameter is a plain
+/// identifier `name`; destructured and omitted parameters are left alone.
+pub fn inject_catch_prologue(code: &str, prologue: impl Fn(&str) -> String) -> String {
+    inject_block_prologues(code, |prev| match prev {
+        Token::CatchClause(param) if is_identifier(param) => Some(prologue(param)),
+        _ => None,
+    })
+}
+
+/// Insert whatever `prologue` returns for the token before each opening brace
+fn inject_block_prologues(code: &str, prologue: impl Fn(&Token) -> Option<String>) -> String {
+    let chars: Vec<char> = code.chars().collect();
+    let mut out = String::with_capacity(code.len());
+    let mut prev = Token::Start;
+    // Token before each open paren, whether it opens a catch clause, and where its
+    // contents start in `out`, so a close paren knows what it closed
+    let mut parens: Vec<(Option<String>, bool, usize)> = Vec::new();
+    let mut braces: Vec<Brace> = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+
+        if c == '/' && next == Some('/') {
+            let end = chars[i..].iter().position(|&ch| ch == '\n').map_or(chars.len(), |p| i + p);
+            out.extend(&chars[i..end]);
+            i = end;
+        } else if c == '/' && next == Some('*') {
+            let end = find_from(&chars, i + 2, &['*', '/']).map_or(chars.len(), |p| p + 2);
+            out.extend(&chars[i..end]);
+            i = end;
+        } else if c == '\'' || c == '"' {
+            i = copy_string(&chars, i, &mut out);
+            prev = Token::Literal;
+        } else if c == '`' {
+            out.push(c);
+            let (end, substitution) = copy_template(&chars, i + 1, &mut out);
+            i = end;
+            if substitution {
+                braces.push(Brace::Template);
+                prev = Token::Punct('{');
+            } else {
+                prev = Token::Literal;
+            }
+        } else if c == '/' && regex_allowed(&prev) {
+            match copy_regex(&chars, i, &mut out) {
+                Some(end) => {
+                    i = end;
+                    prev = Token::Literal;
+                }
+                None => {
+                    out.push(c);
+                    i += 1;
+                    prev = Token::Punct(c);
+                }
+            }
+        } else if c == '{' {
+            out.push(c);
+            if let Some(statement) = prologue(&prev) {
+                out.push_str(&statement);
+            }
+            braces.push(match &prev {
+                Token::Word(word) if word == "try" => Brace::Try,
+                _ => Brace::Other,
+            });
+            i += 1;
+            prev = Token::Punct(c);
+        } else if c == '}' {
+            out.push(c);
+            i += 1;
+            match braces.pop() {
+                Some(Brace::Template) => {
+                    // End of a template substitution; continue with the template text
+                    let (end, substitution) = copy_template(&chars, i, &mut out);
+                    i = end;
+                    if substitution {
+                        braces.push(Brace::Template);
+                        prev = Token::Punct('{');
+                    } else {
+                        prev = Token::Literal;
+                    }
+                }
+                Some(Brace::Try) => prev = Token::TryBlockEnd,
+                _ => prev = Token::Punct(c),
+            }
+        } else if c == '(' {
+            let owner = match &prev {
+                Token::Word(word) => Some(word.clone()),
+                Token::CatchKeyword => Some("catch".to_string()),
+                Token::Punct('*') => Some("*".to_string()),
+                _ => None,
+            };
+            let is_catch_clause = matches!(prev, Token::CatchKeyword);
+            out.push(c);
+            parens.push((owner, is_catch_clause, out.len()));
+            i += 1;
+            prev = Token::Punct(c);
+        } else if c == ')' {
+            prev = match parens.pop() {
+                Some((_, true, start)) => Token::CatchClause(out[start..].trim().to_string()),
+                Some((owner, false, _)) => Token::CloseParen(owner),
+                None => Token::CloseParen(None),
+            };
+            out.push(c);
+            i += 1;
+        } else if c == '=' && next == Some('>') {
+            out.push_str("=>");
+            i += 2;
+            prev = Token::Arrow;
+        } else if c.is_alphabetic() || c == '_' || c == '$' {
+            let end = scan_while(&chars, i, |ch| ch.is_alphanumeric() || ch == '_' || ch == '$');
+            let word: String = chars[i..end].iter().collect();
+            out.push_str(&word);
+            i = end;
+            prev = match prev {
+                Token::TryBlockEnd if word == "catch" => Token::CatchKeyword,
+                _ => Token::Word(word),
+            };
+        } else if c.is_ascii_digit() {
+            let end = scan_while(&chars, i, |ch| ch.is_alphanumeric() || ch == '_' || ch == '.');
+            out.extend(&chars[i..end]);
+            i = end;
+            prev = Token::Literal;
+        } else {
+            out.push(c);
+            i += 1;
+            if !c.is_whitespace() {
+                prev = Token::Punct(c);
+            }
+        }
+    }
+
+    out
+}
+
+fn is_function_body(prev: &Token) -> bool {
+    match prev {
+        Token::Arrow => true,
+        Token::CloseParen(Some(owner)) => !CONTROL_KEYWORDS.contains(&owner.as_str()),
+        _ => false,
+    }
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    chars.next().is_some_and(|c| c.is_alphabetic() || c == '_' || c == '$')
+        && chars.all(|c| c.is_alphanumeric() || c == '_' || c == '$')
+}
+
+fn regex_allowed(prev: &Token) -> bool {
+    match prev {
+        Token::Start | Token::Arrow => true,
+        Token::Punct(c) => !matches!(c, ')' | ']' | '}'),
+        Token::Word(word) => REGEX_PREFIX_KEYWORDS.contains(&word.as_str()),
+        Token::CloseParen(_)
+        | Token::TryBlockEnd
+        | Token::CatchKeyword
+        | Token::CatchClause(_)
+        | Token::Literal => false,
+    }
+}
+
+fn scan_while(chars: &[char], start: usize, pred: impl Fn(char) -> bool) -> usize {
+    chars[start..].iter().position(|&ch| !pred(ch)).map_or(chars.len(), |p| start + p)
+}
+
+fn find_from(chars: &[char], start: usize, needle: &[char]) -> Option<usize> {
+    (start..chars.len()).find(|&p| chars[p..].starts_with(needle))
+}
+
+/// Copy a quoted string starting at `start`, returning the index after it
+fn copy_string(chars: &[char], start: usize, out: &mut String) -> usize {
+    let quote = chars[start];
+    let mut i = start + 1;
+    while i < chars.len() && chars[i] != quote && chars[i] != '\n' {
+        i += if chars[i] == '\\' { 2 } else { 1 };
+    }
+    let end = (i + 1).min(chars.len());
+    out.extend(&chars[start..end]);
+    end
+}
+
+/// Copy template literal text up to and including the closing backtick or the next
+/// `${`, returning the index after it and whether a substitution was opened
+fn copy_template(chars: &[char], start: usize, out: &mut String) -> (usize, bool) {
+    let mut i = start;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '`' => {
+                out.extend(&chars[start..=i]);
+                return (i + 1, false);
+            }
+            '$' if chars.get(i + 1) == Some(&'{') => {
+                out.extend(&chars[start..i + 2]);
+                return (i + 2, true);
+            }
+            _ => i += 1,
+        }
+    }
+    let end = i.min(chars.len());
+    out.extend(&chars[start..end]);
+    (end, false)
+}
+
+/// Copy a regular expression literal with its flags, or None if `start` does not begin one
+fn copy_regex(chars: &[char], start: usize, out: &mut String) -> Option<usize> {
+    let mut i = start + 1;
+    let mut in_class = false;
+    loop {
+        match chars.get(i)? {
+            '\n' => return None,
+            '\\' => i += 1,
+            '[' => in_class = true,
+            ']' => in_class = false,
+            '/' if !in_class => break,
+            _ => {}
+        }
+        i += 1;
+    }
+    let end = scan_while(chars, i + 1, |ch| ch.is_alphanumeric());
+    out.extend(&chars[start..end]);
+    Some(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_injects_into_function_bodies_only() {
+        let code = r#"function f(a) { if (a) { return `${a}{`; } return '{' + /[{]/.source; }
+const g = (x) => { return { x }; };
+class C { m() { for (;;) { break; } } catch(x) { try {} catch (e) {} } }"#;
+        let expected = r#"function f(a) {P; if (a) { return `${a}{`; } return '{' + /[{]/.source; }
+const g = (x) => {P; return { x }; };
+class C { m() {P; for (;;) { break; } } catch(x) {P; try {} catch (e) {} } }"#;
+        assert_eq!(inject_function_prologue(code, "P;"), expected);
+    }
+
+    #[test]
+    fn test_injects_into_catch_blocks() {
+        let code = r#"try { if (a) { f(); } } catch (e) { g(e); }
+try { f(); } catch ({ message }) { g(message); } finally { h(); }
+try { f(); } catch { g(); }
+p.catch((err) => { g(err); });
+p.catch(h)
+{ g(h); }
+class C { catch(x) { g(x); } }"#;
+        let expected = r#"try { if (a) { f(); } } catch (e) {C(e); g(e); }
+try { f(); } catch ({ message }) { g(message); } finally { h(); }
+try { f(); } catch { g(); }
+p.catch((err) => { g(err); });
+p.catch(h)
+{ g(h); }
+class C { catch(x) { g(x); } }"#;
+        assert_eq!(inject_catch_prologue(code, |name| format!("C({});", name)), expected);
+    }
+}
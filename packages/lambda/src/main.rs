@@ -1,15 +1,28 @@
+mod bytecode;
+mod http;
+mod instrument;
+mod parsers;
 mod sandbox;
 
+use base64::prelude::*;
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
-use tracing::info;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use tracing::{info, info_span};
 
-#[derive(Deserialize)]
+#[derive(Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct ExecuteRequest {
-    /// The JavaScript code to execute
+    /// The JavaScript code to execute (may be omitted when `batch` is set)
+    #[serde(default)]
     code: String,
 
+    /// Encoding of `code`; "base64" avoids JSON escaping issues (default: plain text)
+    #[serde(default)]
+    code_encoding: Option<CodeEncoding>,
+
     /// Optional timeout in milliseconds (default: 5000, max: 25000)
     #[serde(default = "default_timeout")]
     timeout_ms: u64,
@@ -22,9 +35,258 @@ struct ExecuteRequest {
     #[serde(default)]
     allowed_domains: Vec<String>,
 
+    /// Ports fetch may reach on allowed hosts (default: [80, 443])
+    #[serde(default = "default_allowed_ports")]
+    allowed_ports: Vec<u16>,
+
+    /// Optional input object to pass to the main function
+    #[serde(default)]
+    input: Option<serde_json::Value>,
+
+    /// Optional base64 binary input, exposed to the script as the `inputBytes` Uint8Array
+    #[serde(default)]
+    input_bytes: Option<String>,
+
+    /// Treat a main function that returns nothing as an error (default: false)
+    #[serde(default)]
+    require_return: bool,
+
+    /// String configuration values exposed to the code as a frozen `env` object
+    #[serde(default)]
+    env: HashMap<String, String>,
+
+    /// Credentials exposed as a frozen `secrets` object; their values are redacted from
+    /// console output, the result and errors
+    #[serde(default)]
+    secrets: HashMap<String, String>,
+
+    /// Expose a gc() function to the code (default: true)
+    #[serde(default = "default_allow_gc")]
+    allow_gc: bool,
+
+    /// Expose crypto.randomUUID() to the code (default: false)
+    #[serde(default)]
+    allow_crypto: bool,
+
+    /// Optional QuickJS stack size in bytes (default: 1MB, max: 8MB)
+    #[serde(default = "default_stack_size")]
+    stack_size_bytes: usize,
+
+    /// Optional maximum serialized result size in bytes (default: 5MB, max: 5MB)
+    #[serde(default = "default_max_result_bytes")]
+    max_result_bytes: usize,
+
+    /// Remove the Proxy and Reflect globals (default: false)
+    #[serde(default)]
+    restrict_reflection: bool,
+
+    /// Normally-removed globals to keep, e.g. ["Function"] (default: empty)
+    #[serde(default)]
+    allowed_globals: Vec<String>,
+
+    /// Optional trusted helper code evaluated before the user code
+    #[serde(default)]
+    prelude_code: Option<String>,
+
+    /// Optional JSON Schema the input is validated against before execution
+    #[serde(default)]
+    input_schema: Option<serde_json::Value>,
+
+    /// Headers added to every fetch request unless the code sets them (default: empty)
+    #[serde(default)]
+    default_headers: HashMap<String, String>,
+
+    /// Return the value assigned to `result` when the code has no return (default: false)
+    #[serde(default)]
+    result_variable: bool,
+
+    /// Optional maximum nesting depth of function calls in the code
+    #[serde(default)]
+    max_call_depth: Option<usize>,
+
+    /// Give catch blocks a RangeError("Maximum call stack size exceeded") for a stack
+    /// overflow instead of QuickJS's InternalError (default: false)
+    #[serde(default)]
+    stack_overflow_range_error: bool,
+
+    /// Return String(value) for results that cannot be JSON-serialized (default: false)
+    #[serde(default)]
+    fallback_stringify: bool,
+
+    /// Milliseconds to shift the script's Date clock by, e.g. 86400000 for a day ahead (default: 0)
+    #[serde(default)]
+    clock_offset_ms: i64,
+
+    /// Optional maximum number of fetch requests to any single host
+    #[serde(default)]
+    per_domain_fetch_limit: Option<usize>,
+
+    /// Optional maximum size of a fetch response body in bytes
+    #[serde(default)]
+    max_response_bytes: Option<usize>,
+
+    /// Maximum fetch URL length in characters (default: 2048)
+    #[serde(default = "default_max_url_length")]
+    max_url_length: usize,
+
+    /// Optional per-domain path prefix rules, e.g. {"example.com": {"deny": ["/admin"]}}
+    #[serde(default)]
+    path_rules: HashMap<String, sandbox::PathRules>,
+
+    /// Make fetch reject with an HTTPError on non-2xx responses (default: false)
+    #[serde(default)]
+    throw_on_http_error: bool,
+
+    /// Optional base URL that relative fetch URLs are resolved against
+    #[serde(default)]
+    base_url: Option<String>,
+
+    /// Keep blocking fetches within the remaining execution time (default: false)
+    #[serde(default)]
+    fetch_time_budget: bool,
+
+    /// Optional retries for idempotent fetches on 5xx or connection errors,
+    /// e.g. {"maxRetries": 2, "baseBackoffMs": 100}
+    #[serde(default)]
+    fetch_retry: Option<sandbox::RetryPolicy>,
+
+    /// Also return console output as { level, message, elapsedMs } entries (default: false)
+    #[serde(default)]
+    structured_console: bool,
+
+    /// Capture console output; false makes console calls no-ops (default: true)
+    #[serde(default = "default_console_enabled")]
+    console_enabled: bool,
+
+    /// Collapse consecutive identical console lines into "line (xN)" (default: false)
+    #[serde(default)]
+    coalesce_console: bool,
+
+    /// End the run with an error at the first console.error call (default: false)
+    #[serde(default)]
+    fail_on_console_error: bool,
+
+    /// Optional CPU budget in QuickJS interrupt polls, independent of wall time
+    #[serde(default)]
+    max_interrupt_ticks: Option<u64>,
+
+    /// Sort object keys in the result instead of keeping insertion order (default: false)
+    #[serde(default)]
+    sort_keys: bool,
+
+    /// Optional cap on keys per result object; extra keys are replaced by a "[Truncated]" count
+    #[serde(default)]
+    max_object_keys: Option<usize>,
+
+    /// Optional cap on elements per result array; extra elements are replaced by a marker
+    #[serde(default)]
+    max_array_length: Option<usize>,
+
+    /// Optional cap on result nesting; deeper objects and arrays become a placeholder
+    #[serde(default)]
+    max_result_depth: Option<usize>,
+
+    /// Return the value of single-expression code without an explicit return (default: false)
+    #[serde(default)]
+    expression_mode: bool,
+
+    /// Handling of numbers beyond ±2^53 - 1: "lossy", "string" or "error" (default: "lossy")
+    #[serde(default)]
+    number_policy: sandbox::NumberPolicy,
+
+    /// Optional list of scripts to run sequentially, each in a fresh runtime.
+    /// Other request settings apply to every item.
+    #[serde(default)]
+    batch: Option<Vec<BatchItem>>,
+
+    /// Optional list of inputs; main runs once per input and the result is the array
+    /// of return values. Takes precedence over `input`.
+    #[serde(default)]
+    inputs: Option<Vec<serde_json::Value>>,
+
+    /// Run each of `inputs` in its own fresh runtime instead of sharing one (default: false)
+    #[serde(default)]
+    isolate_inputs: bool,
+
+    /// Scheduler warmup ping: respond immediately without running any code
+    #[serde(default)]
+    warmup: bool,
+
+    /// Return diagnostics (wrapped code, phase timings, fetch details) for this call
+    #[serde(default)]
+    debug: bool,
+
+    /// Report compile time and bytecode size of the wrapped code (default: false)
+    #[serde(default)]
+    compile_metrics: bool,
+
+    /// Report which capability globals (fetch, crypto, env, ...) the script read (default: false)
+    #[serde(default)]
+    track_global_access: bool,
+
+    /// Leave input values out of the executedSource returned under `debug` (default: false)
+    #[serde(default)]
+    redact_input: bool,
+
+    /// Result key scanned for the skip reason (default: "skip_reason")
+    #[serde(default = "default_skip_reason_key")]
+    skip_reason_key: String,
+
+    /// Result key scanned for the error reason (default: "error_reason")
+    #[serde(default = "default_error_reason_key")]
+    error_reason_key: String,
+
+    /// Report success=false when the result carries an error_reason (default: false)
+    #[serde(default)]
+    treat_error_reason_as_failure: bool,
+
+    /// Reject code matching DISALLOWED_PATTERNS before running it (default: false).
+    /// This is a heuristic pre-filter, not a security boundary.
+    #[serde(default)]
+    reject_disallowed_patterns: bool,
+
+    /// Optional caller-supplied id for log correlation (default: a generated UUID)
+    #[serde(default)]
+    execution_id: Option<String>,
+
+    /// Encoding of the result; "msgpack" returns it, and each batch item's result, in
+    /// `resultMsgpack` (default: "json")
+    #[serde(default)]
+    response_format: ResponseFormat,
+}
+
+/// How the submitted code is encoded
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum CodeEncoding {
+    Base64,
+}
+
+/// How the result is returned
+#[derive(Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ResponseFormat {
+    /// As JSON in `result`
+    #[default]
+    Json,
+    /// As base64-encoded MessagePack in `resultMsgpack`, with `result` omitted
+    Msgpack,
+}
+
+/// A single script within a batch request
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchItem {
+    /// The JavaScript code to execute
+    code: String,
+
     /// Optional input object to pass to the main function
     #[serde(default)]
     input: Option<serde_json::Value>,
+
+    /// Optional timeout override in milliseconds
+    #[serde(default)]
+    timeout_ms: Option<u64>,
 }
 
 fn default_timeout() -> u64 {
@@ -35,9 +297,55 @@ fn default_memory_limit() -> usize {
     10 * 1024 * 1024 // 10 MB
 }
 
-#[derive(Serialize)]
+fn default_allow_gc() -> bool {
+    true
+}
+
+fn default_console_enabled() -> bool {
+    true
+}
+
+fn default_stack_size() -> usize {
+    sandbox::DEFAULT_STACK_SIZE
+}
+
+fn default_max_result_bytes() -> usize {
+    sandbox::DEFAULT_MAX_RESULT_BYTES
+}
+
+fn default_max_url_length() -> usize {
+    sandbox::DEFAULT_MAX_URL_LENGTH
+}
+
+fn default_allowed_ports() -> Vec<u16> {
+    sandbox::DEFAULT_ALLOWED_PORTS.to_vec()
+}
+
+fn default_skip_reason_key() -> String {
+    "skip_reason".to_string()
+}
+
+fn default_error_reason_key() -> String {
+    "error_reason".to_string()
+}
+
+/// Maximum execution time; also bounds the total time of a batch (within Lambda timeout)
+const MAX_TIMEOUT_MS: u64 = 25000; // 25 seconds
+
+/// Time left before the Lambda deadline for building and returning the response
+const DEADLINE_SAFETY_MARGIN_MS: u64 = 500;
+
+/// Substrings commonly used to probe for sandbox escapes. Matching is purely textual
+/// and trivially bypassed by obfuscation; the sandbox itself must remain the defence.
+const DISALLOWED_PATTERNS: &[&str] = &["constructor.constructor", "import(", "__syncFetch"];
+
+#[derive(Default, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ExecuteResponse {
+    /// Id for correlating this invocation with its log lines
+    #[serde(skip_serializing_if = "Option::is_none")]
+    execution_id: Option<String>,
+
     /// Whether execution was successful
     success: bool,
 
@@ -45,6 +353,14 @@ struct ExecuteResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     result: Option<serde_json::Value>,
 
+    /// JSON type of the result: "object", "array", "number", "string", "boolean" or "null"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result_type: Option<&'static str>,
+
+    /// The result as base64-encoded MessagePack, when the request asked for msgpack
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result_msgpack: Option<String>,
+
     /// Error message (if failed)
     #[serde(skip_serializing_if = "Option::is_none")]
     error: Option<String>,
@@ -53,6 +369,10 @@ struct ExecuteResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     skip_reason: Option<String>,
 
+    /// Status code from a `{ __status, __body }` result, whose `__body` becomes the result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_code: Option<u16>,
+
     /// Error reason (from user code or unexpected errors)
     #[serde(skip_serializing_if = "Option::is_none")]
     error_reason: Option<String>,
@@ -63,16 +383,373 @@ struct ExecuteResponse {
     /// Console output captured during execution
     #[serde(skip_serializing_if = "Vec::is_empty", default)]
     console_output: Vec<String>,
+
+    /// Whether execution was stopped because it ran past its timeout; `result` then holds
+    /// the last value passed to checkpoint(), if any
+    timed_out: bool,
+
+    /// Values yielded when the code returned a generator
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    yielded: Vec<serde_json::Value>,
+
+    /// Wall-clock time execution started (epoch millis, UTC)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    started_at: Option<u64>,
+
+    /// Wall-clock time execution finished (epoch millis, UTC)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    finished_at: Option<u64>,
+
+    /// Request body bytes sent by fetch
+    bytes_sent: u64,
+
+    /// Response body bytes received by fetch
+    bytes_received: u64,
+
+    /// Whether the result is the String() form of a value JSON could not represent
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    stringified: bool,
+
+    /// Whether main produced no value, as opposed to explicitly returning null
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    resolved_undefined: bool,
+
+    /// Whether the timeout was lowered to finish before the Lambda invocation deadline
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    deadline_clamped: bool,
+
+    /// The exact source that was evaluated, returned when the request set `debug`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    executed_source: Option<String>,
+
+    /// Result properties stored under registered symbols, keyed by symbol name
+    #[serde(skip_serializing_if = "serde_json::Map::is_empty")]
+    metadata: serde_json::Map<String, serde_json::Value>,
+
+    /// Capability globals the script read, when trackGlobalAccess was set
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    accessed_globals: Vec<String>,
+
+    /// Milliseconds spent compiling the wrapped code, when compileMetrics was set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compile_time_ms: Option<f64>,
+
+    /// Bytecode size of the wrapped code, when compileMetrics was set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytecode_bytes: Option<usize>,
+
+    /// Time spent blocked in native calls (fetch, host functions)
+    native_time_ms: f64,
+
+    /// Time spent running the script itself, excluding native calls
+    js_time_ms: f64,
+
+    /// Console calls with level and timing, returned when the request set `structuredConsole`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    structured_console: Vec<sandbox::ConsoleEntry>,
+
+    /// Records streamed via emit(), in call order
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    emitted: Vec<serde_json::Value>,
+
+    /// Diagnostics collected when the request set `debug`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    diagnostics: Option<sandbox::Diagnostics>,
+
+    /// Per-item responses for batch requests, in submission order
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    batch_results: Vec<ExecuteResponse>,
+}
+
+/// Bounds how many executions run at once in this process, to protect memory when
+/// several invocations share it
+#[derive(Clone)]
+struct ConcurrencyLimit {
+    semaphore: Arc<Semaphore>,
+    /// Reject invocations beyond the limit instead of queuing them
+    reject_when_full: bool,
+}
+
+impl ConcurrencyLimit {
+    /// Read MAX_CONCURRENT_EXECUTIONS and CONCURRENCY_OVERFLOW ("queue" or "reject",
+    /// default "queue"); no limit applies when MAX_CONCURRENT_EXECUTIONS is unset or 0
+    fn from_env() -> Option<Self> {
+        Self::parse(
+            std::env::var("MAX_CONCURRENT_EXECUTIONS").ok().as_deref(),
+            std::env::var("CONCURRENCY_OVERFLOW").ok().as_deref(),
+        )
+    }
+
+    fn parse(max: Option<&str>, overflow: Option<&str>) -> Option<Self> {
+        // A zero-permit semaphore would leave every invocation waiting forever
+        let max = max?.parse::<usize>().ok().filter(|&max| max > 0)?;
+        let reject_when_full = overflow == Some("reject");
+        Some(ConcurrencyLimit { semaphore: Arc::new(Semaphore::new(max)), reject_when_full })
+    }
 }
 
-async fn function_handler(event: LambdaEvent<ExecuteRequest>) -> Result<ExecuteResponse, Error> {
-    let (request, _context) = event.into_parts();
+async fn function_handler(
+    event: LambdaEvent<ExecuteRequest>,
+    limit: Option<ConcurrencyLimit>,
+) -> Result<ExecuteResponse, Error> {
+    let (mut request, context) = event.into_parts();
+
+    // Every log line for this invocation carries the execution id
+    let execution_id = request
+        .execution_id
+        .take()
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let span = info_span!("execution", execution_id = %execution_id);
+
+    // Warmup pings run no code, so they never wait for capacity. The span is only
+    // entered afterwards because its guard must not be held across an await.
+    let _permit = match &limit {
+        Some(limit) if !request.warmup => {
+            let semaphore = limit.semaphore.clone();
+            if limit.reject_when_full {
+                match semaphore.try_acquire_owned() {
+                    Ok(permit) => Some(permit),
+                    Err(_) => {
+                        span.in_scope(|| info!("Rejecting execution: capacity exceeded"));
+                        return Ok(ExecuteResponse {
+                            success: false,
+                            error: Some("Execution capacity exceeded".to_string()),
+                            execution_id: Some(execution_id),
+                            ..Default::default()
+                        });
+                    }
+                }
+            } else {
+                Some(semaphore.acquire_owned().await?)
+            }
+        }
+        _ => None,
+    };
+    let _guard = span.enter();
+
+    // Waiting for capacity may have used up part of the invocation, so clamp afterwards
+    let (timeout_ms, deadline_clamped) = clamp_to_deadline(
+        request.timeout_ms.min(MAX_TIMEOUT_MS),
+        context.deadline,
+        epoch_millis(),
+    );
+    if deadline_clamped {
+        info!("Timeout clamped to {}ms by the invocation deadline", timeout_ms);
+    }
+    request.timeout_ms = timeout_ms;
+
+    let response_format = request.response_format;
+    let mut response = if request.warmup {
+        warmup()
+    } else if let Some(items) = request.batch.take() {
+        execute_batch(request, items, context.deadline)
+    } else if request.isolate_inputs && request.inputs.is_some() {
+        execute_isolated_inputs(request, context.deadline)
+    } else {
+        execute_request(request)
+    };
+    response.execution_id = Some(execution_id);
+    response.deadline_clamped = deadline_clamped;
+
+    if response_format == ResponseFormat::Msgpack {
+        encode_result_msgpack(&mut response);
+    }
+
+    Ok(response)
+}
+
+/// Replace a `{ __status, __body }` result with its body, returning the status. Results
+/// without an integer `__status` in the u16 range are left as they are.
+fn split_status_code(value: &mut serde_json::Value) -> Option<u16> {
+    let status = value.get("__status")?.as_u64().and_then(|s| u16::try_from(s).ok())?;
+    let body = value.as_object_mut()?.remove("__body");
+    *value = body.unwrap_or(serde_json::Value::Null);
+    Some(status)
+}
+
+/// The JSON type name of a value, as reported in `resultType`
+fn json_type(value: &serde_json::Value) -> &'static str {
+    match value {
+        serde_json::Value::Object(_) => "object",
+        serde_json::Value::Array(_) => "array",
+        serde_json::Value::Number(_) => "number",
+        serde_json::Value::String(_) => "string",
+        serde_json::Value::Bool(_) => "boolean",
+        serde_json::Value::Null => "null",
+    }
+}
+
+/// Move the result into `result_msgpack`, failing the response if it can't be encoded.
+/// Batch items are encoded the same way, and one that fails fails the batch.
+fn encode_result_msgpack(response: &mut ExecuteResponse) {
+    for item in response.batch_results.iter_mut() {
+        encode_result_msgpack(item);
+        response.success &= item.success;
+    }
+    let Some(result) = response.result.take() else {
+        return;
+    };
+    match rmp_serde::to_vec(&result) {
+        Ok(bytes) => response.result_msgpack = Some(BASE64_STANDARD.encode(bytes)),
+        Err(e) => {
+            response.success = false;
+            response.error = Some(format!("Failed to encode result as msgpack: {}", e));
+        }
+    }
+}
+
+/// Answer a warmup ping, initializing a QuickJS runtime so the next real call is warm
+fn warmup() -> ExecuteResponse {
+    let start = std::time::Instant::now();
+    if let Err(e) = sandbox::warm_up() {
+        info!("Warmup failed to initialize a runtime: {}", e);
+    }
+    info!("Warmup request handled");
+
+    ExecuteResponse {
+        success: true,
+        execution_time_ms: start.elapsed().as_millis(),
+        ..Default::default()
+    }
+}
+
+/// Run each batch item sequentially in its own isolated runtime.
+/// Item timeouts are clamped so the whole batch stays within MAX_TIMEOUT_MS and finishes
+/// before the invocation deadline (epoch milliseconds; 0 when unknown).
+fn execute_batch(
+    request: ExecuteRequest,
+    items: Vec<BatchItem>,
+    deadline_ms: u64,
+) -> ExecuteResponse {
+    info!("Executing batch of {} scripts", items.len());
+
+    let start = std::time::Instant::now();
+    let mut batch_results = Vec::with_capacity(items.len());
+
+    for item in items {
+        let timeout_ms = item.timeout_ms.unwrap_or(request.timeout_ms);
+        let remaining_ms = remaining_budget_ms(timeout_ms, start, deadline_ms);
+        if remaining_ms == 0 {
+            batch_results.push(ExecuteResponse {
+                success: false,
+                error: Some("Batch time budget exhausted".to_string()),
+                ..Default::default()
+            });
+            continue;
+        }
+
+        let mut item_request = request.clone();
+        item_request.code = item.code;
+        item_request.input = item.input;
+        item_request.timeout_ms = remaining_ms;
+
+        batch_results.push(execute_request(item_request));
+    }
+
+    ExecuteResponse {
+        success: batch_results.iter().all(|r| r.success),
+        execution_time_ms: start.elapsed().as_millis(),
+        batch_results,
+        ..Default::default()
+    }
+}
+
+/// Run main once per input, each in its own runtime, collecting the results in order.
+/// The code is compiled once and shared by every run. The first failing input fails the
+/// whole request. Timeouts are clamped as for batches.
+fn execute_isolated_inputs(mut request: ExecuteRequest, deadline_ms: u64) -> ExecuteResponse {
+    let inputs = request.inputs.take().unwrap_or_default();
+    info!("Executing {} inputs in isolated runtimes", inputs.len());
+
+    let start = std::time::Instant::now();
+    let mut results = Vec::with_capacity(inputs.len());
+    let mut console_output = Vec::new();
+    let mut compiled = None;
+
+    for (index, input) in inputs.into_iter().enumerate() {
+        let remaining_ms = remaining_budget_ms(request.timeout_ms, start, deadline_ms);
+        let mut item_request = request.clone();
+        item_request.input = Some(input);
+        item_request.timeout_ms = remaining_ms;
+
+        let mut item_response = if remaining_ms == 0 {
+            ExecuteResponse {
+                success: false,
+                error: Some("Time budget exhausted".to_string()),
+                ..Default::default()
+            }
+        } else {
+            execute_request_with(item_request, Some(&mut compiled))
+        };
+        console_output.append(&mut item_response.console_output);
+        if !item_response.success {
+            item_response.error = item_response.error.map(|e| format!("Input {}: {}", index, e));
+            item_response.console_output = console_output;
+            return item_response;
+        }
+        results.push(item_response.result.unwrap_or(serde_json::Value::Null));
+    }
+
+    ExecuteResponse {
+        success: true,
+        result: Some(serde_json::Value::Array(results)),
+        result_type: Some("array"),
+        execution_time_ms: start.elapsed().as_millis(),
+        console_output,
+        ..Default::default()
+    }
+}
+
+fn execute_request(request: ExecuteRequest) -> ExecuteResponse {
+    execute_request_with(request, None)
+}
+
+/// Run a request, compiling its code into `compiled` on the first call when a slot is
+/// given, so later calls with the same code and options reuse it
+fn execute_request_with(
+    mut request: ExecuteRequest,
+    compiled: Option<&mut Option<sandbox::CompiledScript>>,
+) -> ExecuteResponse {
+    if let Some(CodeEncoding::Base64) = request.code_encoding {
+        let decoded = BASE64_STANDARD
+            .decode(request.code.trim())
+            .map_err(|e| format!("Invalid base64 code: {}", e))
+            .and_then(|bytes| {
+                String::from_utf8(bytes).map_err(|_| "Decoded code is not valid UTF-8".to_string())
+            });
+        match decoded {
+            Ok(code) => request.code = code,
+            Err(error) => {
+                return ExecuteResponse {
+                    success: false,
+                    error: Some(error),
+                    ..Default::default()
+                };
+            }
+        }
+    }
+
+    let decoded_bytes = request
+        .input_bytes
+        .as_deref()
+        .map(|encoded| BASE64_STANDARD.decode(encoded.trim()));
+    let input_bytes = match decoded_bytes {
+        None => None,
+        Some(Ok(bytes)) => Some(bytes),
+        Some(Err(e)) => {
+            return ExecuteResponse {
+                success: false,
+                error: Some(format!("Invalid base64 inputBytes: {}", e)),
+                ..Default::default()
+            };
+        }
+    };
 
     info!("Executing JavaScript code (length: {} bytes)", request.code.len());
 
     // Validate input
     if request.code.is_empty() {
-        return Ok(ExecuteResponse {
+        return ExecuteResponse {
             success: false,
             result: None,
             error: Some("Code cannot be empty".to_string()),
@@ -80,13 +757,14 @@ async fn function_handler(event: LambdaEvent<ExecuteRequest>) -> Result<ExecuteR
             error_reason: None,
             execution_time_ms: 0,
             console_output: Vec::new(),
-        });
+            ..Default::default()
+        };
     }
 
     // Limit code size to prevent abuse
     const MAX_CODE_SIZE: usize = 100 * 1024; // 100 KB
     if request.code.len() > MAX_CODE_SIZE {
-        return Ok(ExecuteResponse {
+        return ExecuteResponse {
             success: false,
             result: None,
             error: Some(format!("Code size exceeds maximum of {} bytes", MAX_CODE_SIZE)),
@@ -94,45 +772,140 @@ async fn function_handler(event: LambdaEvent<ExecuteRequest>) -> Result<ExecuteR
             error_reason: None,
             execution_time_ms: 0,
             console_output: Vec::new(),
-        });
+            ..Default::default()
+        };
+    }
+
+    if request.reject_disallowed_patterns {
+        if let Some(pattern) = DISALLOWED_PATTERNS.iter().find(|p| request.code.contains(*p)) {
+            return ExecuteResponse {
+                success: false,
+                error: Some(format!("Code contains disallowed pattern '{}'", pattern)),
+                ..Default::default()
+            };
+        }
     }
 
     // Validate timeout
-    const MAX_TIMEOUT_MS: u64 = 25000; // 25 seconds (within Lambda timeout)
     let timeout_ms = request.timeout_ms.min(MAX_TIMEOUT_MS);
 
     // Validate memory limit
     const MAX_MEMORY_LIMIT: usize = 50 * 1024 * 1024; // 50 MB
     let memory_limit = request.memory_limit_bytes.min(MAX_MEMORY_LIMIT);
 
+    // Validate stack size
+    const MAX_STACK_SIZE: usize = 8 * 1024 * 1024; // 8 MB
+    let stack_size = request.stack_size_bytes.min(MAX_STACK_SIZE);
+
+    // Validate fetch retries; backoff is bounded by the execution deadline, the count here
+    const MAX_FETCH_RETRIES: u32 = 5;
+    let fetch_retry = request.fetch_retry.map(|policy| sandbox::RetryPolicy {
+        max_retries: policy.max_retries.min(MAX_FETCH_RETRIES),
+        ..policy
+    });
+
+    // Validate result size limit (the response must fit in Lambda's 6MB payload)
+    let max_result_bytes = request.max_result_bytes.min(sandbox::DEFAULT_MAX_RESULT_BYTES);
+
+    let started_at = epoch_millis();
     let start = std::time::Instant::now();
 
     // Convert allowed_domains to &[&str]
     let allowed_domains_refs: Vec<&str> = request.allowed_domains.iter().map(|s| s.as_str()).collect();
 
+    let diagnostics_sink = request.debug.then(sandbox::DiagnosticsSink::default);
+
+    let options = sandbox::ExecutionOptions {
+        require_return: request.require_return,
+        env: request.env,
+        secrets: request.secrets,
+        allow_gc: request.allow_gc,
+        allow_crypto: request.allow_crypto,
+        stack_size_bytes: stack_size,
+        max_result_bytes,
+        restrict_reflection: request.restrict_reflection,
+        allowed_globals: request.allowed_globals,
+        prelude_code: request.prelude_code,
+        input_schema: request.input_schema,
+        default_headers: request.default_headers,
+        result_variable: request.result_variable,
+        max_call_depth: request.max_call_depth,
+        stack_overflow_range_error: request.stack_overflow_range_error,
+        fallback_stringify: request.fallback_stringify,
+        clock_offset_ms: request.clock_offset_ms,
+        per_domain_fetch_limit: request.per_domain_fetch_limit,
+        max_response_bytes: request.max_response_bytes,
+        max_url_length: request.max_url_length,
+        allowed_ports: request.allowed_ports,
+        path_rules: request.path_rules,
+        throw_on_http_error: request.throw_on_http_error,
+        base_url: request.base_url,
+        fetch_time_budget: request.fetch_time_budget,
+        fetch_retry,
+        structured_console: request.structured_console,
+        console_enabled: request.console_enabled,
+        coalesce_console: request.coalesce_console,
+        fail_on_console_error: request.fail_on_console_error,
+        max_interrupt_ticks: request.max_interrupt_ticks,
+        sort_keys: request.sort_keys,
+        max_object_keys: request.max_object_keys,
+        max_array_length: request.max_array_length,
+        max_result_depth: request.max_result_depth,
+        expression_mode: request.expression_mode,
+        number_policy: request.number_policy,
+        track_global_access: request.track_global_access,
+        compile_metrics: request.compile_metrics,
+        diagnostics: diagnostics_sink.clone(),
+        capture_source: request.debug,
+        redact_input: request.redact_input,
+        map_inputs: request.inputs.is_some(),
+        input_bytes,
+        ..Default::default()
+    };
+
     // Execute the code in sandbox
-    match sandbox::execute_js(
-        &request.code,
-        timeout_ms,
-        memory_limit,
-        &allowed_domains_refs,
-        request.input,
-    ) {
-        Ok(result) => {
-            let execution_time = start.elapsed().as_millis();
-
-            // Extract skip_reason and error_reason from the result if present
+    let input = request.inputs.map(serde_json::Value::Array).or(request.input);
+    let outcome = match compiled {
+        None => sandbox::execute_js(
+            &request.code,
+            timeout_ms,
+            memory_limit,
+            &allowed_domains_refs,
+            input,
+            &options,
+        ),
+        Some(compiled) => {
+            let script = match compiled {
+                Some(script) => Ok(&*script),
+                None => sandbox::compile(&request.code, timeout_ms, memory_limit, &options)
+                    .map(|script| &*compiled.insert(script)),
+            };
+            script.and_then(|script| {
+                script.run(timeout_ms, memory_limit, &allowed_domains_refs, input, &options)
+            })
+        }
+    };
+    let execution_time = start.elapsed().as_millis();
+    let finished_at = epoch_millis();
+    let diagnostics = diagnostics_sink.map(|sink| sink.lock().unwrap().clone());
+
+    match outcome {
+        Ok(mut result) => {
+            // A handler-style { __status, __body } result splits into a status code and body
+            let status_code = split_status_code(&mut result.value);
+
+            // Extract the skip and error reasons from the configured result keys if present
             let mut skip_reason = None;
             let mut error_reason = None;
 
             if let Some(obj) = result.value.as_object() {
-                if let Some(reason) = obj.get("skip_reason") {
+                if let Some(reason) = obj.get(&request.skip_reason_key) {
                     if let Some(reason_str) = reason.as_str() {
                         skip_reason = Some(reason_str.to_string());
                         info!("Execution completed with skip_reason: {} (took {}ms)", reason_str, execution_time);
                     }
                 }
-                if let Some(reason) = obj.get("error_reason") {
+                if let Some(reason) = obj.get(&request.error_reason_key) {
                     if let Some(reason_str) = reason.as_str() {
                         error_reason = Some(reason_str.to_string());
                         info!("Execution completed with error_reason: {} (took {}ms)", reason_str, execution_time);
@@ -140,26 +913,53 @@ async fn function_handler(event: LambdaEvent<ExecuteRequest>) -> Result<ExecuteR
                 }
             }
 
-            if skip_reason.is_none() && error_reason.is_none() {
+            if result.timed_out {
+                info!("Execution timed out with a checkpoint (took {}ms)", execution_time);
+            } else if skip_reason.is_none() && error_reason.is_none() {
                 info!("Execution successful (took {}ms)", execution_time);
             }
 
-            Ok(ExecuteResponse {
-                success: true,
+            // A checkpointed result is partial, so the run still counts as failed
+            let success = !result.timed_out
+                && !(request.treat_error_reason_as_failure && error_reason.is_some());
+
+            ExecuteResponse {
+                success,
+                result_type: Some(json_type(&result.value)),
                 result: Some(result.value),
-                error: None,
+                error: result.timed_out.then(|| sandbox::ExecutionError::Timeout.to_string()),
                 skip_reason,
                 error_reason,
+                status_code,
                 execution_time_ms: execution_time,
                 console_output: result.console_output,
-            })
+                yielded: result.yielded,
+                bytes_sent: result.bytes_sent,
+                bytes_received: result.bytes_received,
+                stringified: result.stringified,
+                resolved_undefined: result.resolved_undefined,
+                executed_source: result.executed_source,
+                emitted: result.emitted,
+                structured_console: result.structured_console,
+                metadata: result.metadata,
+                accessed_globals: result.accessed_globals,
+                compile_time_ms: result.compile_time_ms,
+                bytecode_bytes: result.bytecode_bytes,
+                native_time_ms: result.native_time_ms,
+                js_time_ms: result.js_time_ms,
+                timed_out: result.timed_out,
+                started_at: Some(started_at),
+                finished_at: Some(finished_at),
+                diagnostics,
+                ..Default::default()
+            }
         }
         Err(e) => {
-            let execution_time = start.elapsed().as_millis();
+            let timed_out = matches!(e.downcast_ref(), Some(sandbox::ExecutionError::Timeout));
             let error_msg = e.to_string();
             info!("Execution failed: {} (took {}ms)", error_msg, execution_time);
 
-            Ok(ExecuteResponse {
+            ExecuteResponse {
                 success: false,
                 result: None,
                 error: Some(error_msg.clone()),
@@ -167,11 +967,44 @@ async fn function_handler(event: LambdaEvent<ExecuteRequest>) -> Result<ExecuteR
                 error_reason: Some(error_msg), // Forward unexpected errors to error_reason
                 execution_time_ms: execution_time,
                 console_output: Vec::new(),
-            })
+                timed_out,
+                started_at: Some(started_at),
+                finished_at: Some(finished_at),
+                diagnostics,
+                ..Default::default()
+            }
         }
     }
 }
 
+/// Lower an item's `timeout_ms` to what is left of the MAX_TIMEOUT_MS budget shared by
+/// every item since `start`, and to what the invocation deadline still allows
+fn remaining_budget_ms(timeout_ms: u64, start: std::time::Instant, deadline_ms: u64) -> u64 {
+    let budget_ms = MAX_TIMEOUT_MS.saturating_sub(start.elapsed().as_millis() as u64);
+    clamp_to_deadline(timeout_ms.min(budget_ms), deadline_ms, epoch_millis()).0
+}
+
+/// Lower `timeout_ms` to end a safety margin before `deadline_ms`, reporting whether it changed
+fn clamp_to_deadline(timeout_ms: u64, deadline_ms: u64, now_ms: u64) -> (u64, bool) {
+    if deadline_ms == 0 {
+        return (timeout_ms, false);
+    }
+    let available_ms = deadline_ms.saturating_sub(now_ms).saturating_sub(DEADLINE_SAFETY_MARGIN_MS);
+    if timeout_ms > available_ms {
+        (available_ms, true)
+    } else {
+        (timeout_ms, false)
+    }
+}
+
+/// Current wall-clock time as milliseconds since the Unix epoch (UTC)
+fn epoch_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     // Initialize tracing
@@ -183,5 +1016,416 @@ async fn main() -> Result<(), Error> {
 
     info!("Starting JavaScript executor Lambda function");
 
-    run(service_fn(function_handler)).await
+    let limit = ConcurrencyLimit::from_env();
+    run(service_fn(move |event| function_handler(event, limit.clone()))).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambda_runtime::Context;
+
+    async fn invoke(request: serde_json::Value) -> ExecuteResponse {
+        let request: ExecuteRequest = serde_json::from_value(request).unwrap();
+        function_handler(LambdaEvent::new(request, Context::default()), None).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_batch_execution_is_isolated() {
+        let response = invoke(serde_json::json!({
+            "batch": [
+                { "code": "globalThis.leak = input.value; return input.value * 2;", "input": { "value": 21 } },
+                { "code": "return typeof globalThis.leak;", "timeoutMs": 1000 }
+            ]
+        }))
+        .await;
+
+        assert!(response.success);
+        assert_eq!(response.batch_results.len(), 2);
+        assert_eq!(response.batch_results[0].result, Some(serde_json::json!(42)));
+        assert_eq!(response.batch_results[1].result, Some(serde_json::json!("undefined")));
+    }
+
+    #[tokio::test]
+    async fn test_batch_reports_item_failures() {
+        let response = invoke(serde_json::json!({
+            "batch": [
+                { "code": "return 1;" },
+                { "code": "throw new Error('boom');" }
+            ]
+        }))
+        .await;
+
+        assert!(!response.success);
+        assert!(response.batch_results[0].success);
+        assert!(!response.batch_results[1].success);
+        assert!(response.batch_results[1].error.as_ref().unwrap().contains("boom"));
+    }
+
+    #[tokio::test]
+    async fn test_response_timestamps() {
+        let response = invoke(serde_json::json!({
+            "code": "let x = 0; for (let i = 0; i < 100000; i++) { x += i; } return x;"
+        }))
+        .await;
+
+        assert!(response.success);
+        let started_at = response.started_at.unwrap();
+        let finished_at = response.finished_at.unwrap();
+        assert!(finished_at >= started_at);
+        let wall_ms = (finished_at - started_at) as i128;
+        assert!((wall_ms - response.execution_time_ms as i128).abs() <= 5);
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_flag() {
+        let response = invoke(serde_json::json!({ "code": "while (true) {}", "timeoutMs": 100 })).await;
+        assert!(!response.success);
+        assert!(response.timed_out);
+
+        let response = invoke(serde_json::json!({ "code": "throw new Error('boom');" })).await;
+        assert!(!response.success);
+        assert!(!response.timed_out);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_request_skips_execution() {
+        let start = std::time::Instant::now();
+        let response = invoke(serde_json::json!({
+            "warmup": true,
+            "code": "console.log('ran'); while (true) {}"
+        }))
+        .await;
+
+        assert!(response.success);
+        assert_eq!(response.result, None);
+        assert!(response.console_output.is_empty());
+        assert!(!response.timed_out);
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_debug_diagnostics() {
+        let response = invoke(serde_json::json!({ "code": "return 1;", "debug": true })).await;
+        let diagnostics = response.diagnostics.expect("diagnostics should be present");
+        assert!(diagnostics.wrapped_code.contains("return 1;"));
+        let phases: Vec<&str> = diagnostics.phases.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(phases, ["setup", "execute", "drain", "serialize"]);
+
+        let response = invoke(serde_json::json!({ "code": "return 1;" })).await;
+        assert!(response.diagnostics.is_none());
+        assert!(serde_json::to_value(&response).unwrap().get("diagnostics").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_treat_error_reason_as_failure() {
+        let code = "return { error_reason: 'x', partial: 1 };";
+
+        let response = invoke(serde_json::json!({ "code": code })).await;
+        assert!(response.success);
+
+        let response = invoke(serde_json::json!({ "code": code, "treatErrorReasonAsFailure": true })).await;
+        assert!(!response.success);
+        assert_eq!(response.error_reason.as_deref(), Some("x"));
+        assert_eq!(response.result, Some(serde_json::json!({ "error_reason": "x", "partial": 1 })));
+    }
+
+    #[tokio::test]
+    async fn test_execution_id() {
+        let response = invoke(serde_json::json!({ "code": "return 1;", "executionId": "req-123" })).await;
+        assert_eq!(response.execution_id.as_deref(), Some("req-123"));
+
+        let response = invoke(serde_json::json!({ "code": "return 1;" })).await;
+        let generated = response.execution_id.expect("an id should be generated");
+        assert!(uuid::Uuid::parse_str(&generated).is_ok(), "not a UUID: {}", generated);
+    }
+
+    #[tokio::test]
+    async fn test_executed_source_under_debug() {
+        let request = serde_json::json!({
+            "code": "return input.secret.length;",
+            "input": { "secret": "hunter2" },
+            "debug": true
+        });
+        let response = invoke(request.clone()).await;
+        let source = response.executed_source.expect("executedSource should be present");
+        assert!(source.contains("(async function main(input) {"));
+        assert!(source.contains("return input.secret.length;"));
+        assert!(source.contains("hunter2"));
+
+        let mut redacted = request.clone();
+        redacted["redactInput"] = serde_json::json!(true);
+        let source = invoke(redacted).await.executed_source.unwrap();
+        assert!(!source.contains("hunter2"));
+        assert!(source.contains("return input.secret.length;"));
+
+        let response = invoke(serde_json::json!({ "code": "return 1;" })).await;
+        assert!(response.executed_source.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_base64_code() {
+        let response = invoke(serde_json::json!({
+            "code": BASE64_STANDARD.encode("return 1+1"),
+            "codeEncoding": "base64"
+        }))
+        .await;
+        assert!(response.success);
+        assert_eq!(response.result, Some(serde_json::json!(2)));
+
+        let response = invoke(serde_json::json!({ "code": "not base64!", "codeEncoding": "base64" })).await;
+        assert!(!response.success);
+        assert!(response.error.unwrap().starts_with("Invalid base64 code"));
+    }
+
+    #[tokio::test]
+    async fn test_reject_disallowed_patterns() {
+        let code = "return typeof globalThis.__syncFetch;";
+
+        let response = invoke(serde_json::json!({ "code": code, "rejectDisallowedPatterns": true })).await;
+        assert!(!response.success);
+        assert_eq!(response.error.as_deref(), Some("Code contains disallowed pattern '__syncFetch'"));
+
+        let response = invoke(serde_json::json!({ "code": code })).await;
+        assert!(response.success);
+        assert_eq!(response.result, Some(serde_json::json!("function")));
+    }
+
+    #[tokio::test]
+    async fn test_custom_reason_keys() {
+        let code = "return { skipReason: 'nothing to do', skip_reason: 'ignored' };";
+        let response = invoke(serde_json::json!({ "code": code, "skipReasonKey": "skipReason" })).await;
+        assert!(response.success);
+        assert_eq!(response.skip_reason.as_deref(), Some("nothing to do"));
+
+        let response = invoke(serde_json::json!({ "code": code })).await;
+        assert_eq!(response.skip_reason.as_deref(), Some("ignored"));
+    }
+
+    #[tokio::test]
+    async fn test_inputs_map_mode() {
+        let code = "globalThis.calls = (globalThis.calls || 0) + 1; return [input * 2, calls];";
+
+        let shared = invoke(serde_json::json!({ "code": code, "inputs": [1, 2, 3] })).await;
+        assert!(shared.success, "{:?}", shared.error);
+        assert_eq!(shared.result, Some(serde_json::json!([[2, 1], [4, 2], [6, 3]])));
+
+        let isolated = invoke(serde_json::json!({ "code": code, "inputs": [1, 2, 3], "isolateInputs": true })).await;
+        assert!(isolated.success, "{:?}", isolated.error);
+        assert_eq!(isolated.result, Some(serde_json::json!([[2, 1], [4, 1], [6, 1]])));
+
+        // Isolated inputs share one compiled script, wrapped with the request's options
+        let request = serde_json::json!({
+            "code": "input * 3", "inputs": [1, 2], "isolateInputs": true, "expressionMode": true
+        });
+        let isolated = invoke(request).await;
+        assert_eq!(isolated.result, Some(serde_json::json!([3, 6])));
+
+        let request = serde_json::json!({ "code": "return (", "inputs": [1, 2], "isolateInputs": true });
+        let error = invoke(request).await.error.unwrap();
+        assert!(error.starts_with("Input 0: JavaScript execution error"), "{}", error);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_concurrency_limit() {
+        async fn run_pair(reject_when_full: bool) -> Vec<ExecuteResponse> {
+            let limit = ConcurrencyLimit { semaphore: Arc::new(Semaphore::new(1)), reject_when_full };
+            let code = "const end = Date.now() + 300; while (Date.now() < end) {} return 1;";
+            let calls = (0..2).map(|_| {
+                let request: ExecuteRequest = serde_json::from_value(serde_json::json!({ "code": code })).unwrap();
+                tokio::spawn(function_handler(LambdaEvent::new(request, Context::default()), Some(limit.clone())))
+            });
+            let mut responses = Vec::new();
+            for call in calls.collect::<Vec<_>>() {
+                responses.push(call.await.unwrap().unwrap());
+            }
+            responses
+        }
+
+        let rejected = run_pair(true).await;
+        assert_eq!(rejected.iter().filter(|r| r.success).count(), 1);
+        let failure = rejected.iter().find(|r| !r.success).unwrap();
+        assert_eq!(failure.error.as_deref(), Some("Execution capacity exceeded"));
+
+        let queued = run_pair(false).await;
+        assert!(queued.iter().all(|r| r.success));
+    }
+
+    #[test]
+    fn test_concurrency_limit_parse() {
+        let limit = ConcurrencyLimit::parse(Some("2"), Some("reject")).unwrap();
+        assert_eq!(limit.semaphore.available_permits(), 2);
+        assert!(limit.reject_when_full);
+        assert!(!ConcurrencyLimit::parse(Some("2"), None).unwrap().reject_when_full);
+
+        // Zero, unset and malformed values leave executions unlimited
+        assert!(ConcurrencyLimit::parse(Some("0"), None).is_none());
+        assert!(ConcurrencyLimit::parse(None, None).is_none());
+        assert!(ConcurrencyLimit::parse(Some("many"), None).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_resolved_undefined() {
+        let empty = invoke(serde_json::json!({ "code": "const x = 1;" })).await;
+        assert!(empty.success);
+        assert_eq!(empty.result, Some(serde_json::Value::Null));
+        assert!(empty.resolved_undefined);
+
+        let null = invoke(serde_json::json!({ "code": "return null;" })).await;
+        assert_eq!(null.result, Some(serde_json::Value::Null));
+        assert!(!null.resolved_undefined);
+    }
+
+    #[tokio::test]
+    async fn test_input_bytes() {
+        let response = invoke(serde_json::json!({
+            "code": r#"
+                "use strict";
+                let reassigned = false;
+                try { inputBytes = null; reassigned = true; } catch (e) {}
+                return {
+                    isUint8Array: inputBytes instanceof Uint8Array,
+                    length: inputBytes.length,
+                    first: inputBytes[0],
+                    reassigned,
+                };
+            "#,
+            "input": { "name": "upload.bin" },
+            "inputBytes": BASE64_STANDARD.encode([0xCA, 0xFE, 0x00, 0x01, 0xFF]),
+        }))
+        .await;
+        assert!(response.success, "{:?}", response.error);
+        assert_eq!(
+            response.result,
+            Some(serde_json::json!({ "isUint8Array": true, "length": 5, "first": 202, "reassigned": false }))
+        );
+
+        let response = invoke(serde_json::json!({ "code": "return 1", "inputBytes": "%%%" })).await;
+        assert!(!response.success);
+        assert!(response.error.unwrap().starts_with("Invalid base64 inputBytes"));
+    }
+
+    #[test]
+    fn test_clamp_to_deadline() {
+        // 2s left leaves 1.5s after the safety margin
+        assert_eq!(clamp_to_deadline(5000, 12_000, 10_000), (1500, true));
+        assert_eq!(clamp_to_deadline(1000, 12_000, 10_000), (1000, false));
+        // Already past the margin, and no deadline known
+        assert_eq!(clamp_to_deadline(5000, 10_200, 10_000), (0, true));
+        assert_eq!(clamp_to_deadline(5000, 0, 10_000), (5000, false));
+    }
+
+    #[tokio::test]
+    async fn test_deadline_clamped_response() {
+        let request = serde_json::json!({ "code": "while (true) {}", "timeoutMs": 20000 });
+        let request: ExecuteRequest = serde_json::from_value(request).unwrap();
+        let mut context = Context::default();
+        context.deadline = epoch_millis() + 800;
+        let started = std::time::Instant::now();
+        let response = function_handler(LambdaEvent::new(request, context), None).await.unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert!(response.deadline_clamped);
+        assert!(response.timed_out);
+    }
+
+    #[tokio::test]
+    async fn test_msgpack_response_format() {
+        let code = "return { name: 'widget', tags: ['a', 'b'], price: 9.5, count: 3, gone: null };";
+        let json = invoke(serde_json::json!({ "code": code })).await;
+        let msgpack = invoke(serde_json::json!({ "code": code, "responseFormat": "msgpack" })).await;
+
+        assert!(msgpack.success);
+        assert!(msgpack.result.is_none());
+        let bytes = BASE64_STANDARD.decode(msgpack.result_msgpack.unwrap()).unwrap();
+        let decoded: serde_json::Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(Some(decoded), json.result);
+        assert!(json.result_msgpack.is_none());
+
+        // Each batch item carries its own encoded result
+        let batch = invoke(serde_json::json!({
+            "batch": [{ "code": code }, { "code": "return [1, 'two'];" }],
+            "responseFormat": "msgpack"
+        }))
+        .await;
+        assert!(batch.success);
+        let decoded: Vec<serde_json::Value> = batch
+            .batch_results
+            .iter()
+            .map(|item| {
+                assert!(item.result.is_none());
+                let bytes = BASE64_STANDARD.decode(item.result_msgpack.as_ref().unwrap()).unwrap();
+                rmp_serde::from_slice(&bytes).unwrap()
+            })
+            .collect();
+        assert_eq!(decoded, vec![json.result.unwrap(), serde_json::json!([1, "two"])]);
+    }
+
+    #[tokio::test]
+    async fn test_result_type() {
+        let cases = [
+            ("return { a: 1 };", "object"),
+            ("return [1, 2];", "array"),
+            ("return 4.5;", "number"),
+            ("return 'text';", "string"),
+            ("return false;", "boolean"),
+            ("return null;", "null"),
+        ];
+        for (code, expected) in cases {
+            let response = invoke(serde_json::json!({ "code": code })).await;
+            assert_eq!(response.result_type, Some(expected), "{}", code);
+        }
+
+        let response = invoke(serde_json::json!({ "code": "throw new Error('no');" })).await;
+        assert_eq!(response.result_type, None);
+
+        // Batch items and isolated inputs report their own result types
+        let batch = invoke(serde_json::json!({
+            "batch": [{ "code": "return 'a';" }, { "code": "return { b: 1 };" }, { "code": "throw 1;" }]
+        }))
+        .await;
+        let types: Vec<_> = batch.batch_results.iter().map(|item| item.result_type).collect();
+        assert_eq!(types, vec![Some("string"), Some("object"), None]);
+        assert_eq!(batch.result_type, None);
+
+        let isolated = invoke(serde_json::json!({
+            "code": "return input;", "inputs": [1, 2], "isolateInputs": true
+        }))
+        .await;
+        assert_eq!(isolated.result_type, Some("array"));
+    }
+
+    #[tokio::test]
+    async fn test_status_code_result() {
+        let code = "return { __status: 201, __body: { id: 1 } };";
+        let response = invoke(serde_json::json!({ "code": code })).await;
+        assert!(response.success);
+        assert_eq!(response.status_code, Some(201));
+        assert_eq!(response.result, Some(serde_json::json!({ "id": 1 })));
+
+        // Without __status the result is returned unchanged
+        let response = invoke(serde_json::json!({ "code": "return { __body: 1 };" })).await;
+        assert_eq!(response.status_code, None);
+        assert_eq!(response.result, Some(serde_json::json!({ "__body": 1 })));
+    }
+
+    #[tokio::test]
+    async fn test_batch_clamped_to_deadline() {
+        let request = serde_json::json!({
+            "batch": [
+                { "code": "while (true) {}", "timeoutMs": 20000 },
+                { "code": "return 1;", "timeoutMs": 20000 }
+            ]
+        });
+        let request: ExecuteRequest = serde_json::from_value(request).unwrap();
+        let mut context = Context::default();
+        context.deadline = epoch_millis() + 800;
+        let started = std::time::Instant::now();
+        let response = function_handler(LambdaEvent::new(request, context), None).await.unwrap();
+        assert!(started.elapsed() < std::time::Duration::from_secs(2));
+        assert!(response.batch_results[0].timed_out);
+        assert_eq!(
+            response.batch_results[1].error.as_deref(),
+            Some("Batch time budget exhausted")
+        );
+    }
 }
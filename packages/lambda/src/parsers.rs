@@ -0,0 +1,127 @@
+//! Host-side parsers for fetch response bodies.
+//!
+//! Scripts call `response.parse(format)` to get a body in a non-JSON format as a plain
+//! value without bundling a parser in JS. Parsers are keyed by format name, so an
+//! embedder can register more alongside the built-in ones.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Turns a response body into a JSON-compatible value
+pub type ResponseParser = Arc<dyn Fn(&str) -> Result<serde_json::Value> + Send + Sync>;
+
+/// The parsers available to every execution
+pub fn builtin_parsers() -> HashMap<String, ResponseParser> {
+    let mut parsers: HashMap<String, ResponseParser> = HashMap::new();
+    parsers.insert("csv".to_string(), Arc::new(parse_csv));
+    parsers
+}
+
+/// Parse RFC 4180 CSV whose first record is the header into an array of objects with
+/// string values. Quoted fields may contain commas, newlines and doubled quotes.
+pub fn parse_csv(text: &str) -> Result<serde_json::Value> {
+    let mut records = csv_records(text)?.into_iter();
+    let header = match records.next() {
+        Some(header) => header,
+        None => return Ok(serde_json::Value::Array(Vec::new())),
+    };
+
+    let mut rows = Vec::new();
+    for (index, record) in records.enumerate() {
+        if record.len() != header.len() {
+            return Err(anyhow!(
+                "row {} has {} fields, expected {}",
+                index + 1,
+                record.len(),
+                header.len()
+            ));
+        }
+        let row: serde_json::Map<String, serde_json::Value> = header
+            .iter()
+            .cloned()
+            .zip(record.into_iter().map(serde_json::Value::String))
+            .collect();
+        rows.push(serde_json::Value::Object(row));
+    }
+    Ok(serde_json::Value::Array(rows))
+}
+
+/// Split CSV text into records of fields; blank lines are skipped
+fn csv_records(text: &str) -> Result<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut record = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    // Whether the current record has any content, so blank lines produce no record
+    let mut started = false;
+    let mut chars = text.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => {
+                in_quotes = true;
+                started = true;
+            }
+            ',' => {
+                record.push(std::mem::take(&mut field));
+                started = true;
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' | '\r' => {
+                if started {
+                    record.push(std::mem::take(&mut field));
+                    records.push(std::mem::take(&mut record));
+                    started = false;
+                }
+            }
+            _ => {
+                field.push(c);
+                started = true;
+            }
+        }
+    }
+
+    if in_quotes {
+        return Err(anyhow!("unterminated quoted field"));
+    }
+    if started {
+        record.push(field);
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_csv() {
+        let text = "name,note\r\nwidget,\"small, blue\"\r\n\r\ngadget,\"says \"\"hi\"\"\nloudly\"\r\n";
+        assert_eq!(
+            parse_csv(text).unwrap(),
+            serde_json::json!([
+                { "name": "widget", "note": "small, blue" },
+                { "name": "gadget", "note": "says \"hi\"\nloudly" }
+            ])
+        );
+        assert_eq!(parse_csv("").unwrap(), serde_json::json!([]));
+        assert_eq!(parse_csv("a,b\n1,").unwrap(), serde_json::json!([{ "a": "1", "b": "" }]));
+
+        let error = parse_csv("a,b\n1,2,3").unwrap_err();
+        assert_eq!(error.to_string(), "row 1 has 3 fields, expected 2");
+        assert!(parse_csv("a\n\"open").is_err());
+    }
+}
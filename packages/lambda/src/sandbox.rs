@@ -1,1053 +1,6165 @@
+use crate::bytecode;
+use crate::http::{HttpClient, HttpError, HttpRequest, ReqwestClient};
+use crate::instrument;
+use crate::parsers::{self, ResponseParser};
 use anyhow::{anyhow, Result};
-use reqwest;
+use base64::prelude::*;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, USER_AGENT};
 use rquickjs::{
-    CatchResultExt, Context, Ctx, Function, Object, Runtime, Value,
+    convert::Coerced, function::{Opt, This}, ArrayBuffer, CatchResultExt, Context, Ctx, Exception,
+    Function, Object, Runtime, Value,
 };
-use serde_json;
-use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 use tracing::debug;
 use url::Url;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ExecutionResult {
     pub value: serde_json::Value,
     pub console_output: Vec<String>,
+    /// Values yielded when main returned a generator, in order
+    pub yielded: Vec<serde_json::Value>,
+    /// Total request body bytes sent by fetch
+    pub bytes_sent: u64,
+    /// Total response body bytes received by fetch
+    pub bytes_received: u64,
+    /// Whether `value` is the String() form of a result JSON could not represent
+    pub stringified: bool,
+    /// Whether main resolved to undefined (no return value) rather than an explicit value
+    pub resolved_undefined: bool,
+    /// The exact source evaluated, when `capture_source` was set
+    pub executed_source: Option<String>,
+    /// Records passed to emit(), in call order
+    pub emitted: Vec<serde_json::Value>,
+    /// Console calls with level and timing, when `structured_console` was set
+    pub structured_console: Vec<ConsoleEntry>,
+    /// Properties the result carried under registered symbols (`Symbol.for(name)`),
+    /// keyed by name; JSON serialization leaves them out of `value`
+    pub metadata: serde_json::Map<String, serde_json::Value>,
+    /// Wall time spent blocked in native calls (fetch, host functions)
+    pub native_time_ms: f64,
+    /// Wall time spent running the script, excluding native calls
+    pub js_time_ms: f64,
+    /// Whether the run timed out and `value` is the last value passed to checkpoint()
+    pub timed_out: bool,
+    /// Capability globals the script read, in first-access order, when
+    /// `track_global_access` was set
+    pub accessed_globals: Vec<String>,
+    /// Time spent compiling the wrapped code, when `compile_metrics` was set
+    pub compile_time_ms: Option<f64>,
+    /// Size of the wrapped code's bytecode, when `compile_metrics` was set
+    pub bytecode_bytes: Option<usize>,
 }
 
-#[derive(Clone)]
-struct Console {
-    output: Arc<Mutex<Vec<String>>>,
+/// Failures that callers may want to tell apart from ordinary script errors
+#[derive(Debug, thiserror::Error)]
+pub enum ExecutionError {
+    /// Execution was stopped by the interrupt handler or the watchdog deadline
+    #[error("Execution timeout exceeded")]
+    Timeout,
+
+    /// Execution was stopped because the embedder set the cancellation flag
+    #[error("Execution cancelled")]
+    Cancelled,
+
+    /// Execution was stopped after `max_interrupt_ticks` interrupt checks
+    #[error("Execution work budget exceeded")]
+    WorkBudgetExceeded,
+
+    /// Execution was stopped by a console.error call under `fail_on_console_error`
+    #[error("console.error called: {0}")]
+    ConsoleError(String),
 }
 
-impl Console {
-    fn new() -> Self {
-        Console {
-            output: Arc::new(Mutex::new(Vec::new())),
-        }
-    }
+/// Default QuickJS stack size (1MB)
+pub const DEFAULT_STACK_SIZE: usize = 1024 * 1024;
 
-    fn log(&self, message: String) {
-        self.output.lock().unwrap().push(format!("[log] {}", message));
-    }
+/// Default maximum serialized result size (5MB, below Lambda's 6MB response limit)
+pub const DEFAULT_MAX_RESULT_BYTES: usize = 5 * 1024 * 1024;
 
-    fn get_output(&self) -> Vec<String> {
-        self.output.lock().unwrap().clone()
-    }
+/// Default maximum length of a fetch URL in characters
+pub const DEFAULT_MAX_URL_LENGTH: usize = 2048;
+
+/// Ports fetch may reach by default: standard HTTP and HTTPS
+pub const DEFAULT_ALLOWED_PORTS: &[u16] = &[80, 443];
+
+/// Callback invoked synchronously with each console line as it is logged
+pub type ConsoleCallback = Arc<Mutex<dyn FnMut(&str) + Send>>;
+
+/// Host-provided function callable from scripts; arguments and results cross as JSON
+pub type HostFunction = Arc<dyn Fn(serde_json::Value) -> serde_json::Value + Send + Sync>;
+
+/// Handling of result numbers that JSON consumers may not represent exactly
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NumberPolicy {
+    /// Emit them as JSON numbers, which may lose precision downstream
+    #[default]
+    Lossy,
+    /// Emit them as strings holding every digit
+    String,
+    /// Fail the execution
+    Error,
 }
 
-/// Execute JavaScript code in a sandboxed QuickJS environment
-pub fn execute_js(
-    code: &str,
-    timeout_ms: u64,
-    memory_limit: usize,
-    allowed_domains: &[&str],
-    input: Option<serde_json::Value>,
-) -> Result<ExecutionResult> {
-    // Create QuickJS runtime with memory limit
-    let runtime = Runtime::new()?;
+/// Path prefix rules for one allowed domain; deny rules take precedence over allow rules
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default)]
+pub struct PathRules {
+    /// Reachable path prefixes; empty allows every path not denied
+    pub allow: Vec<String>,
+    /// Blocked path prefixes
+    pub deny: Vec<String>,
+}
 
-    // Set memory limit
-    runtime.set_memory_limit(memory_limit);
+/// Retries for idempotent fetches (GET, HEAD, PUT, DELETE) that fail with a 5xx status or
+/// a connection error; POST and PATCH are never retried
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RetryPolicy {
+    /// Attempts after the first
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled before each one after it
+    #[serde(default = "default_retry_backoff_ms")]
+    pub base_backoff_ms: u64,
+}
 
-    // Set max stack size (1MB)
-    runtime.set_max_stack_size(1024 * 1024);
+fn default_retry_backoff_ms() -> u64 {
+    100
+}
 
-    // Track execution start time for timeout
-    let start = Instant::now();
-    let timeout_duration = Duration::from_millis(timeout_ms);
-    let start_clone = start.clone();
-    let timeout_clone = timeout_duration.clone();
+/// Opt-in detail about one execution, filled in as it progresses
+#[derive(Debug, Default, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostics {
+    /// The code as evaluated, after wrapping and instrumentation
+    pub wrapped_code: String,
+    /// Duration of each execution phase, in order
+    pub phases: Vec<PhaseTiming>,
+    /// Every outbound fetch that was attempted
+    pub fetches: Vec<FetchDiagnostic>,
+}
 
-    // Set interrupt handler for timeout
-    runtime.set_interrupt_handler(Some(Box::new(move || {
-        start_clone.elapsed() > timeout_clone
-    })));
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PhaseTiming {
+    pub name: String,
+    pub duration_ms: f64,
+}
 
-    let context = Context::full(&runtime)?;
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FetchDiagnostic {
+    pub method: String,
+    pub url: String,
+    pub status: Option<u16>,
+    pub error: Option<String>,
+    pub duration_ms: f64,
+}
 
-    // Create console for capturing output
-    let console = Console::new();
+/// One console call, as returned when `structured_console` is set
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConsoleEntry {
+    /// Position among this execution's console calls, starting at 0. Calls are recorded
+    /// as they run, so across async tasks this is the order the job queue ran them in.
+    pub sequence: u64,
+    pub level: String,
+    pub message: String,
+    /// Milliseconds since execution start
+    pub elapsed_ms: f64,
+}
 
-    let result = context.with(|ctx| {
-        setup_sandbox(&ctx, console.clone(), allowed_domains)?;
+/// Shared so diagnostics survive a failed execution
+pub type DiagnosticsSink = Arc<Mutex<Diagnostics>>;
 
-        // Inject the input object into the global scope
-        if let Some(inp) = input {
-            let input_json = serde_json::to_string(&inp)?;
-            let input_code = format!("globalThis.__userInput = {};", input_json);
-            ctx.eval::<(), _>(input_code.as_str())?;
-        } else {
-            ctx.eval::<(), _>("globalThis.__userInput = undefined;")?;
-        }
+/// User-Agent sent with fetch requests unless `default_headers` overrides it
+pub const DEFAULT_USER_AGENT: &str = concat!("js-executor/", env!("CARGO_PKG_VERSION"));
 
-        // Wrap user code in async main function with input parameter
-        let wrapped_code = format!(
-            r#"(async function main(input) {{
-    {}
-}})(globalThis.__userInput)"#,
-            code
-        );
+/// Globals removed from the sandbox unless listed in `allowed_globals`
+pub const REMOVED_GLOBALS: &[&str] = &["eval", "Function", "setTimeout", "setInterval"];
 
-        debug!("Executing JavaScript code wrapped in async main(input)");
+/// Optional behaviour toggles for a single execution
+#[derive(Clone)]
+pub struct ExecutionOptions {
+    /// Fail when the main function resolves to undefined instead of returning null
+    pub require_return: bool,
 
-        // Evaluate the code - this returns a Promise
-        let promise: rquickjs::Promise = ctx.eval(wrapped_code.as_str()).catch(&ctx).map_err(|e| {
-            let error_msg = format_js_error(&ctx, e);
-            anyhow!("JavaScript execution error: {}", error_msg)
-        })?;
+    /// String configuration values exposed to scripts as a frozen `env` global
+    pub env: HashMap<String, String>,
 
-        // Wait for the promise to resolve
-        let result_value: Value = promise.finish().catch(&ctx).map_err(|e| {
-            let error_msg = format_js_error(&ctx, e);
-            anyhow!("Promise resolution error: {}", error_msg)
-        })?;
+    /// Credentials exposed as a frozen `secrets` global; their values are replaced with
+    /// `[REDACTED]` in console output, the result and error messages
+    pub secrets: HashMap<String, String>,
 
-        // Check if timeout exceeded
-        if start.elapsed() > timeout_duration {
-            return Err(anyhow!("Execution timeout exceeded"));
-        }
+    /// Expose a gc() global that lets scripts trigger garbage collection
+    pub allow_gc: bool,
 
-        // Convert result to JSON
-        let json_value = value_to_json(&ctx, result_value)?;
+    /// Expose a frozen crypto global with randomUUID()
+    pub allow_crypto: bool,
 
-        Ok(ExecutionResult {
-            value: json_value,
-            console_output: console.get_output(),
-        })
-    })?;
+    /// Maximum stack size for the QuickJS runtime in bytes
+    pub stack_size_bytes: usize,
 
-    Ok(result)
-}
+    /// Maximum size of the JSON-serialized result in bytes
+    pub max_result_bytes: usize,
 
-/// Setup the sandbox environment with security restrictions
-fn setup_sandbox(ctx: &Ctx, console: Console, allowed_domains: &[&str]) -> Result<()> {
-    let globals = ctx.globals();
+    /// Remove the Proxy and Reflect globals for high-security tenants
+    pub restrict_reflection: bool,
 
-    // Setup console
-    setup_console(ctx, &globals, console)?;
+    /// Stream console lines to an embedder-provided sink during execution
+    pub on_console_line: Option<ConsoleCallback>,
 
-    // Setup fetch with domain allowlist
-    setup_fetch(ctx, &globals, allowed_domains)?;
+    /// Normally-removed globals (see REMOVED_GLOBALS) that should be kept
+    pub allowed_globals: Vec<String>,
 
-    // Freeze Object.prototype to prevent prototype pollution
-    ctx.eval::<(), _>("Object.freeze(Object.prototype);")?;
-    ctx.eval::<(), _>("Object.freeze(Array.prototype);")?;
+    /// Trusted helper code evaluated before the user code; its globals are frozen
+    pub prelude_code: Option<String>,
 
-    // Remove dangerous globals
-    globals.remove("eval").ok();
-    globals.remove("Function").ok();
-    globals.remove("setTimeout").ok();
-    globals.remove("setInterval").ok();
+    /// Named host functions exposed to scripts as globals
+    pub host_functions: HashMap<String, HostFunction>,
 
-    Ok(())
-}
+    /// Parsers behind `response.parse(format)`, keyed by format name (default: csv)
+    pub response_parsers: HashMap<String, ResponseParser>,
 
-/// Setup console API for capturing output
-fn setup_console<'js>(ctx: &Ctx<'js>, globals: &Object<'js>, console: Console) -> Result<()> {
-    let console_obj = Object::new(ctx.clone())?;
+    /// JSON Schema the input must satisfy before the code is run
+    pub input_schema: Option<serde_json::Value>,
 
-    // Create console.log function
-    let console_clone = console.clone();
-    let log_fn = Function::new(
-        ctx.clone(),
-        move |args: rquickjs::function::Rest<Value>| {
-            let messages: Vec<String> = args
-                .iter()
-                .map(|v| value_to_string(v))
-                .collect();
-            let message = messages.join(" ");
-            console_clone.log(message);
-        },
-    )?;
+    /// Headers added to every fetch request unless the script sets them itself
+    pub default_headers: HashMap<String, String>,
 
-    console_obj.set("log", log_fn)?;
+    /// Setting this flag to true stops the execution as soon as possible
+    pub cancel_flag: Option<Arc<AtomicBool>>,
 
-    // Add console._times for Node.js compatibility (SES requirement)
-    let times_obj = Object::new(ctx.clone())?;
-    console_obj.set("_times", times_obj)?;
+    /// Trusted CommonJS-style module sources scripts can load with require(name)
+    pub modules: HashMap<String, String>,
 
-    globals.set("console", console_obj)?;
+    /// Use the value assigned to `result` when the code finishes without returning
+    pub result_variable: bool,
 
-    Ok(())
-}
+    /// Maximum nesting of user function calls, independent of stack size in bytes
+    pub max_call_depth: Option<usize>,
 
-/// Setup fetch API with domain allowlist
-/// Returns a standards-compliant Promise-based fetch API
-fn setup_fetch<'js>(ctx: &Ctx<'js>, globals: &Object<'js>, allowed_domains: &[&str]) -> Result<()> {
-    let allowed_domains_vec: Vec<String> = allowed_domains.iter().map(|s| s.to_string()).collect();
+    /// Hand caught stack overflows to catch blocks as RangeError("Maximum call stack size
+    /// exceeded"), as other engines do; instruments every catch block in the user code
+    pub stack_overflow_range_error: bool,
 
-    // Create a synchronous native fetch that returns either a response object or an error object
-    let sync_fetch = Function::new(
-        ctx.clone(),
-        move |ctx: Ctx<'js>, url: String, options: Object<'js>| -> rquickjs::Result<Object<'js>> {
-            // Validate URL and domain
-            let parsed_url = match Url::parse(&url) {
-                Ok(u) => u,
-                Err(e) => {
-                    let error_obj = Object::new(ctx.clone())?;
-                    error_obj.set("__isError", true)?;
-                    error_obj.set("message", format!("Invalid URL: {}", e))?;
-                    return Ok(error_obj);
-                }
-            };
+    /// Return String(value) for results JSON cannot represent (functions, symbols, cycles)
+    pub fallback_stringify: bool,
 
-            let host = match parsed_url.host_str() {
-                Some(h) => h,
-                None => {
-                    let error_obj = Object::new(ctx.clone())?;
-                    error_obj.set("__isError", true)?;
-                    error_obj.set("message", "Invalid URL: no host")?;
-                    return Ok(error_obj);
-                }
-            };
+    /// Shift Date.now() and `new Date()` by this many milliseconds; the clock keeps running
+    pub clock_offset_ms: i64,
 
-            let is_allowed = allowed_domains_vec
-                .iter()
-                .any(|domain| host == domain || host.ends_with(&format!(".{}", domain)));
+    /// Maximum number of fetch requests to any single host per execution
+    pub per_domain_fetch_limit: Option<usize>,
 
-            if !is_allowed {
-                let error_obj = Object::new(ctx.clone())?;
-                error_obj.set("__isError", true)?;
-                error_obj.set("message", format!("Domain '{}' is not in the allowlist", host))?;
-                return Ok(error_obj);
-            }
+    /// Reject fetch responses whose body is larger than this many bytes
+    pub max_response_bytes: Option<usize>,
 
-            // Block private IP ranges
-            if host == "localhost"
-                || host.starts_with("127.")
-                || host.starts_with("10.")
-                || host.starts_with("192.168.")
-                || host.starts_with("172.16.")
-                || host == "0.0.0.0"
-            {
-                let error_obj = Object::new(ctx.clone())?;
-                error_obj.set("__isError", true)?;
-                error_obj.set("message", "Requests to private IP ranges are not allowed")?;
-                return Ok(error_obj);
-            }
+    /// Reject fetch URLs longer than this many characters, before parsing
+    pub max_url_length: usize,
 
-            // Parse options
-            let method = options.get::<_, Option<String>>("method")
-                .unwrap_or(None)
-                .unwrap_or_else(|| "GET".to_string())
-                .to_uppercase();
+    /// Ports fetch may reach on allowed hosts; URL allowlist entries, which name their
+    /// port, are exempt
+    pub allowed_ports: Vec<u16>,
 
-            let body = options.get::<_, Option<String>>("body").unwrap_or(None);
+    /// Reject fetch with an HTTPError (carrying status and statusText) on non-2xx responses
+    pub throw_on_http_error: bool,
 
-            // Make HTTP request
-            let client = match reqwest::blocking::Client::builder()
-                .timeout(Duration::from_secs(5))
-                .build()
-            {
-                Ok(c) => c,
-                Err(e) => {
-                    let error_obj = Object::new(ctx.clone())?;
-                    error_obj.set("__isError", true)?;
-                    error_obj.set("message", format!("Failed to create HTTP client: {}", e))?;
-                    return Ok(error_obj);
-                }
-            };
+    /// Base that relative fetch URLs are resolved against before any policy checks
+    pub base_url: Option<String>,
 
-            let mut request_builder = match method.as_str() {
-                "GET" => client.get(&url),
-                "POST" => client.post(&url),
-                "PUT" => client.put(&url),
-                "DELETE" => client.delete(&url),
-                "PATCH" => client.patch(&url),
-                "HEAD" => client.head(&url),
-                _ => {
-                    let error_obj = Object::new(ctx.clone())?;
-                    error_obj.set("__isError", true)?;
-                    error_obj.set("message", format!("Unsupported HTTP method: {}", method))?;
-                    return Ok(error_obj);
-                }
-            };
+    /// Cap each blocking fetch at the execution time remaining and reject fetches
+    /// started after it runs out
+    pub fetch_time_budget: bool,
 
-            // Add body if present
-            if let Some(body_data) = body {
-                request_builder = request_builder.body(body_data);
-            }
+    /// Retry idempotent fetches on transient failures; under `fetch_time_budget` a retry
+    /// whose backoff would outlast the budget is not attempted
+    pub fetch_retry: Option<RetryPolicy>,
 
-            // Add headers if present
-            if let Ok(Some(headers_obj)) = options.get::<_, Option<Object>>("headers") {
-                for prop in headers_obj.props::<String, String>() {
-                    if let Ok((key, value)) = prop {
-                        request_builder = request_builder.header(&key, &value);
-                    }
-                }
-            }
+    /// Also return console calls as entries with level and elapsed time
+    pub structured_console: bool,
 
-            let response = match request_builder.send() {
-                Ok(r) => r,
-                Err(e) => {
-                    let error_obj = Object::new(ctx.clone())?;
-                    error_obj.set("__isError", true)?;
-                    error_obj.set("message", format!("HTTP request failed: {}", e))?;
-                    return Ok(error_obj);
-                }
-            };
+    /// Capture console calls; when false the console methods are no-ops and nothing is
+    /// recorded, streamed or returned
+    pub console_enabled: bool,
 
-            let status = response.status().as_u16();
-            let response_text = match response.text() {
-                Ok(t) => t,
-                Err(e) => {
-                    let error_obj = Object::new(ctx.clone())?;
-                    error_obj.set("__isError", true)?;
-                    error_obj.set("message", format!("Failed to read response: {}", e))?;
-                    return Ok(error_obj);
-                }
-            };
+    /// Collapse consecutive identical console lines into one with an "(xN)" suffix;
+    /// the line callback and structured entries still see every call
+    pub coalesce_console: bool,
 
-            // Create response object
-            let response_obj = Object::new(ctx.clone())?;
-            response_obj.set("status", status)?;
-            response_obj.set("ok", status >= 200 && status < 300)?;
-            response_obj.set("_bodyText", response_text.clone())?;
+    /// Stop the run at the first console.error call (including failed console.assert)
+    /// and fail citing its message
+    pub fail_on_console_error: bool,
 
-            Ok(response_obj)
-        },
-    )?;
+    /// Transport for fetch; the built-in reqwest client when unset
+    pub http_client: Option<Arc<dyn HttpClient>>,
 
-    // Set the synchronous implementation as a hidden global
-    ctx.globals().set("__syncFetch", sync_fetch)?;
+    /// Stop the script after this many interrupt-handler polls, a proxy for CPU work
+    pub max_interrupt_ticks: Option<u64>,
 
-    // Wrap it in JavaScript to provide Promise-based API
-    let fetch_wrapper_code = r#"
-(function() {
-    return function fetch(url, options) {
-        return new Promise((resolve, reject) => {
-            try {
-                // Convert options to empty object if undefined
-                const opts = options || {};
-                const result = globalThis.__syncFetch(url, opts);
+    /// Path prefix rules keyed by allowed domain (a trailing `*` is ignored)
+    pub path_rules: HashMap<String, PathRules>,
 
-                // Check if result is an error
-                if (result.__isError) {
-                    reject(new Error(result.message));
-                    return;
-                }
+    /// Sort object keys in the result instead of keeping JS insertion order
+    pub sort_keys: bool,
 
-                // Add text() and json() methods that return Promises
-                result.text = function() {
-                    return Promise.resolve(this._bodyText);
-                };
+    /// Keep at most this many keys of each result object, then add a truncation marker
+    pub max_object_keys: Option<usize>,
 
-                result.json = function() {
-                    return new Promise((resolve, reject) => {
-                        try {
-                            resolve(JSON.parse(this._bodyText));
-                        } catch (e) {
-                            reject(e);
-                        }
-                    });
-                };
+    /// Keep at most this many elements of each result array, then append a truncation marker
+    pub max_array_length: Option<usize>,
 
-                resolve(result);
-            } catch (error) {
-                reject(error);
-            }
-        });
-    };
-})()
-"#;
+    /// Keep at most this many levels of nested result objects and arrays; deeper ones are
+    /// replaced by a "[depth limit reached]" placeholder
+    pub max_result_depth: Option<usize>,
 
-    let fetch_fn: Function = ctx.eval(fetch_wrapper_code)?;
-    globals.set("fetch", fetch_fn)?;
+    /// Record which capability globals (fetch, crypto, env, host functions, ...) the
+    /// script reads; they become accessor properties, which scripts can observe
+    pub track_global_access: bool,
 
-    Ok(())
-}
+    /// Report compile time and bytecode size; sizing costs one extra compile unless the
+    /// script came from `compile`
+    pub compile_metrics: bool,
 
-/// Convert a QuickJS Value to a string representation
-fn value_to_string(value: &Value) -> String {
-    if let Some(s) = value.as_string() {
-        s.to_string().unwrap_or_else(|_| "[String]".to_string())
-    } else if value.is_null() {
-        "null".to_string()
-    } else if value.is_undefined() {
-        "undefined".to_string()
-    } else if let Some(b) = value.as_bool() {
-        b.to_string()
-    } else if let Some(n) = value.as_int() {
-        n.to_string()
-    } else if let Some(n) = value.as_float() {
-        n.to_string()
-    } else if value.is_object() {
-        "[Object]".to_string()
-    } else if value.is_array() {
-        "[Array]".to_string()
-    } else if value.is_function() {
-        "[Function]".to_string()
-    } else {
-        "[Unknown]".to_string()
-    }
+    /// Collect wrapped code, phase timings and fetch details into this sink
+    pub diagnostics: Option<DiagnosticsSink>,
+
+    /// Return the value of code that is a single expression without needing `return`
+    pub expression_mode: bool,
+
+    /// Treat the input as an array and call main once per element in this runtime,
+    /// returning the array of results
+    pub map_inputs: bool,
+
+    /// Binary input exposed to the script as the `inputBytes` Uint8Array
+    pub input_bytes: Option<Vec<u8>>,
+
+    /// How to serialize numbers outside the safe integer range (±2^53 - 1)
+    pub number_policy: NumberPolicy,
+
+    /// Return the fully assembled source (input injection plus wrapped code)
+    pub capture_source: bool,
+
+    /// Replace the input value with a placeholder in the captured source
+    pub redact_input: bool,
 }
 
-/// Convert a QuickJS Value to serde_json::Value
-fn value_to_json<'js>(ctx: &Ctx<'js>, value: Value<'js>) -> Result<serde_json::Value> {
-    if value.is_null() {
-        Ok(serde_json::Value::Null)
-    } else if value.is_undefined() {
-        Ok(serde_json::Value::Null)
-    } else if let Some(b) = value.as_bool() {
-        Ok(serde_json::Value::Bool(b))
-    } else if let Some(i) = value.as_int() {
-        Ok(serde_json::Value::Number(i.into()))
-    } else if let Some(f) = value.as_float() {
-        serde_json::Number::from_f64(f)
-            .map(serde_json::Value::Number)
-            .ok_or_else(|| anyhow!("Invalid float value"))
-    } else if let Some(s) = value.as_string() {
-        Ok(serde_json::Value::String(
-            s.to_string().unwrap_or_else(|_| String::new()),
-        ))
-    } else if value.is_array() {
-        let arr = value.as_array().unwrap();
-        let mut result = Vec::new();
-        for i in 0..arr.len() {
-            if let Ok(item) = arr.get::<Value>(i) {
-                result.push(value_to_json(ctx, item)?);
-            }
+impl Default for ExecutionOptions {
+    fn default() -> Self {
+        ExecutionOptions {
+            require_return: false,
+            env: HashMap::new(),
+            secrets: HashMap::new(),
+            allow_gc: true,
+            allow_crypto: false,
+            stack_size_bytes: DEFAULT_STACK_SIZE,
+            max_result_bytes: DEFAULT_MAX_RESULT_BYTES,
+            restrict_reflection: false,
+            on_console_line: None,
+            allowed_globals: Vec::new(),
+            prelude_code: None,
+            host_functions: HashMap::new(),
+            response_parsers: parsers::builtin_parsers(),
+            input_schema: None,
+            default_headers: HashMap::new(),
+            cancel_flag: None,
+            modules: HashMap::new(),
+            result_variable: false,
+            max_call_depth: None,
+            stack_overflow_range_error: false,
+            fallback_stringify: false,
+            clock_offset_ms: 0,
+            per_domain_fetch_limit: None,
+            max_response_bytes: None,
+            max_url_length: DEFAULT_MAX_URL_LENGTH,
+            allowed_ports: DEFAULT_ALLOWED_PORTS.to_vec(),
+            throw_on_http_error: false,
+            base_url: None,
+            fetch_time_budget: false,
+            fetch_retry: None,
+            structured_console: false,
+            console_enabled: true,
+            coalesce_console: false,
+            fail_on_console_error: false,
+            http_client: None,
+            max_interrupt_ticks: None,
+            path_rules: HashMap::new(),
+            sort_keys: false,
+            max_object_keys: None,
+            max_array_length: None,
+            max_result_depth: None,
+            track_global_access: false,
+            compile_metrics: false,
+            diagnostics: None,
+            expression_mode: false,
+            map_inputs: false,
+            input_bytes: None,
+            number_policy: NumberPolicy::default(),
+            capture_source: false,
+            redact_input: false,
         }
-        Ok(serde_json::Value::Array(result))
-    } else if value.is_object() {
-        let obj = value.as_object().unwrap();
+    }
+}
 
-        // Try to use JSON.stringify for better conversion
-        let json_obj: Object = ctx.globals().get("JSON")?;
+#[derive(Clone)]
+struct Console {
+    output: Arc<Mutex<Vec<String>>>,
+    timers: Arc<Mutex<HashMap<String, Instant>>>,
+    callback: Option<ConsoleCallback>,
+    /// Structured entries, kept only when requested
+    entries: Option<Arc<Mutex<Vec<ConsoleEntry>>>>,
+    /// The last line and how many times in a row it was logged, when coalescing
+    repeated: Option<Arc<Mutex<(String, usize)>>>,
+    /// The first console.error message, recorded when errors are fatal
+    first_error: Option<Arc<OnceLock<String>>>,
+    /// Open console.group levels; messages are indented two spaces per level
+    group_depth: Arc<Mutex<usize>>,
+    redactor: Redactor,
+    start: Instant,
+}
+
+impl Console {
+    fn new(
+        callback: Option<ConsoleCallback>,
+        structured: bool,
+        coalesce: bool,
+        redactor: Redactor,
+        start: Instant,
+    ) -> Self {
+        Console {
+            output: Arc::new(Mutex::new(Vec::new())),
+            timers: Arc::new(Mutex::new(HashMap::new())),
+            callback,
+            entries: structured.then(|| Arc::new(Mutex::new(Vec::new()))),
+            repeated: coalesce.then(|| Arc::new(Mutex::new((String::new(), 0)))),
+            first_error: None,
+            group_depth: Arc::new(Mutex::new(0)),
+            redactor,
+            start,
+        }
+    }
+
+    fn push(&self, level: &str, message: String) {
+        // Redact before anything sees the message, the line callback included
+        let message = self.redactor.redact(&message);
+        let depth = *self.group_depth.lock().unwrap();
+        let message = match depth {
+            0 => message,
+            depth => format!("{}{}", "  ".repeat(depth), message),
+        };
+        let line = format!("[{}] {}", level, message);
+        if let Some(callback) = &self.callback {
+            (callback.lock().unwrap())(&line);
+        }
+        if let Some(entries) = &self.entries {
+            let mut entries = entries.lock().unwrap();
+            let sequence = entries.len() as u64;
+            entries.push(ConsoleEntry {
+                sequence,
+                level: level.to_string(),
+                message,
+                elapsed_ms: self.start.elapsed().as_secs_f64() * 1000.0,
+            });
+        }
+
+        // A run of identical lines collapses into the first, suffixed with the count
+        let mut output = self.output.lock().unwrap();
+        if let Some(repeated) = &self.repeated {
+            let mut repeated = repeated.lock().unwrap();
+            if repeated.1 > 0 && repeated.0 == line {
+                repeated.1 += 1;
+                if let Some(last) = output.last_mut() {
+                    *last = format!("{} (x{})", line, repeated.1);
+                }
+                return;
+            }
+            *repeated = (line.clone(), 1);
+        }
+        output.push(line);
+    }
+
+    fn log(&self, message: String) {
+        self.push("log", message);
+    }
+
+    fn warn(&self, message: String) {
+        self.push("warn", message);
+    }
+
+    fn error(&self, message: String) {
+        if let Some(first_error) = &self.first_error {
+            first_error.get_or_init(|| self.redactor.redact(&message));
+        }
+        self.push("error", message);
+    }
+
+    fn dir(&self, message: String) {
+        self.push("dir", message);
+    }
+
+    fn group(&self, label: Option<String>) {
+        if let Some(label) = label {
+            self.log(label);
+        }
+        *self.group_depth.lock().unwrap() += 1;
+    }
+
+    fn group_end(&self) {
+        // Unbalanced groupEnd calls are ignored, as in browsers
+        let mut depth = self.group_depth.lock().unwrap();
+        *depth = depth.saturating_sub(1);
+    }
+
+    fn time(&self, label: String) {
+        let exists = match self.timers.lock().unwrap().entry(label.clone()) {
+            Entry::Occupied(_) => true,
+            Entry::Vacant(entry) => {
+                entry.insert(Instant::now());
+                false
+            }
+        };
+        if exists {
+            self.warn(format!("Timer '{}' already exists", label));
+        }
+    }
+
+    fn time_end(&self, label: String) {
+        let started = self.timers.lock().unwrap().remove(&label);
+        match started {
+            Some(started) => {
+                let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+                self.log(format!("{}: {:.3}ms", label, elapsed_ms));
+            }
+            None => self.warn(format!("Timer '{}' does not exist", label)),
+        }
+    }
+
+    fn get_output(&self) -> Vec<String> {
+        self.output.lock().unwrap().clone()
+    }
+
+    fn get_entries(&self) -> Vec<ConsoleEntry> {
+        self.entries.as_ref().map_or_else(Vec::new, |entries| entries.lock().unwrap().clone())
+    }
+}
+
+/// Replaces secret values with `[REDACTED]` wherever script output leaves the sandbox
+#[derive(Clone, Default)]
+struct Redactor(Arc<Vec<String>>);
+
+impl Redactor {
+    fn new(secrets: &HashMap<String, String>) -> Self {
+        // Longest first, so a secret containing another is replaced whole
+        let mut values: Vec<String> =
+            secrets.values().filter(|value| !value.is_empty()).cloned().collect();
+        values.sort_by_key(|value| std::cmp::Reverse(value.len()));
+        Redactor(Arc::new(values))
+    }
+
+    fn redact(&self, text: &str) -> String {
+        self.0.iter().fold(text.to_string(), |text, secret| text.replace(secret.as_str(), REDACTED))
+    }
+
+    /// Redact every string and object key in `value`
+    fn redact_json(&self, value: &mut serde_json::Value) {
+        if self.0.is_empty() {
+            return;
+        }
+        match value {
+            serde_json::Value::String(text) => *text = self.redact(text),
+            serde_json::Value::Array(items) => {
+                items.iter_mut().for_each(|item| self.redact_json(item))
+            }
+            serde_json::Value::Object(map) => self.redact_map(map),
+            _ => {}
+        }
+    }
+
+    /// Redact every key and value in `map`
+    fn redact_map(&self, map: &mut serde_json::Map<String, serde_json::Value>) {
+        if self.0.is_empty() {
+            return;
+        }
+        let entries = std::mem::take(map);
+        for (key, mut item) in entries {
+            self.redact_json(&mut item);
+            map.insert(self.redact(&key), item);
+        }
+    }
+
+    /// Redact an error's message, keeping the error itself when nothing was replaced so
+    /// callers can still downcast it
+    fn redact_error(&self, error: anyhow::Error) -> anyhow::Error {
+        let message = error.to_string();
+        let redacted = self.redact(&message);
+        if redacted == message {
+            error
+        } else {
+            anyhow!(redacted)
+        }
+    }
+}
+
+/// Placeholder for secret values in output
+const REDACTED: &str = "[REDACTED]";
+
+/// Outbound traffic accounting and limits shared by every fetch in one execution
+#[derive(Clone, Default)]
+struct FetchMetrics {
+    bytes_sent: Arc<AtomicU64>,
+    bytes_received: Arc<AtomicU64>,
+    /// Execution deadline that blocking fetches must finish by, when `fetch_time_budget` is set
+    deadline: Option<Instant>,
+    /// Execution deadline past which response bodies stop being read, whatever the budget
+    execution_deadline: Option<Instant>,
+}
+
+/// Wall time spent inside native calls that block the script, in nanoseconds
+#[derive(Clone, Default)]
+struct NativeTime(Arc<AtomicU64>);
+
+impl NativeTime {
+    fn add(&self, elapsed: Duration) {
+        self.0.fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn as_duration(&self) -> Duration {
+        Duration::from_nanos(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// State the execution thread shares with the caller, so a run that times out after
+/// calling checkpoint() still reports what it produced up to then
+#[derive(Clone, Default)]
+struct RunState {
+    /// The last value passed to checkpoint()
+    checkpoint: Arc<Mutex<Option<serde_json::Value>>>,
+    console_output: Arc<Mutex<Vec<String>>>,
+    console_entries: Arc<Mutex<Vec<ConsoleEntry>>>,
+    fetch_metrics: FetchMetrics,
+    native_time: NativeTime,
+    emitted: Arc<Mutex<Vec<serde_json::Value>>>,
+    accessed_globals: Arc<Mutex<Vec<String>>>,
+}
+
+impl RunState {
+    /// The partial result of a timed-out run, if it checkpointed
+    fn take_checkpoint(&self) -> Option<ExecutionResult> {
+        let value = self.checkpoint.lock().unwrap().take()?;
+        Some(ExecutionResult {
+            value,
+            console_output: self.console_output.lock().unwrap().clone(),
+            structured_console: self.console_entries.lock().unwrap().clone(),
+            bytes_sent: self.fetch_metrics.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.fetch_metrics.bytes_received.load(Ordering::Relaxed),
+            emitted: std::mem::take(&mut *self.emitted.lock().unwrap()),
+            native_time_ms: self.native_time.as_duration().as_secs_f64() * 1000.0,
+            accessed_globals: self.accessed_globals.lock().unwrap().clone(),
+            timed_out: true,
+            ..Default::default()
+        })
+    }
+}
+
+/// Recursively freezes the injected input object and everything reachable from it
+const DEEP_FREEZE_INPUT_CODE: &str = r#"
+(function deepFreeze(value) {
+    if (value !== null && typeof value === 'object' && !Object.isFrozen(value)) {
+        Object.getOwnPropertyNames(value).forEach((key) => deepFreeze(value[key]));
+        Object.freeze(value);
+    }
+    return value;
+})(globalThis.__userInput);
+"#;
+
+// Typed arrays with elements can't be frozen, so the binding is locked and the array
+// made non-extensible; element writes only change this run's copy
+const INPUT_BYTES_CODE: &str = r#"
+(function(buffer) {
+    Object.defineProperty(globalThis, 'inputBytes', {
+        value: Object.preventExtensions(new Uint8Array(buffer)),
+        writable: false,
+        enumerable: false,
+        configurable: false,
+    });
+})
+"#;
+
+/// Resolves to `{ yielded, value }`, draining (async) generators and passing
+/// any other value through unchanged
+const DRAIN_GENERATOR_CODE: &str = r#"
+(async function drainGenerator(value) {
+    const isIterator = value !== null
+        && typeof value === 'object'
+        && typeof value.next === 'function'
+        && (typeof value[Symbol.asyncIterator] === 'function' || typeof value[Symbol.iterator] === 'function');
+    if (!isIterator) {
+        return { yielded: [], value };
+    }
+    const yielded = [];
+    while (true) {
+        const step = await value.next();
+        if (step.done) {
+            return { yielded, value: step.value };
+        }
+        yielded.push(step.value);
+    }
+})
+"#;
+
+/// Collects the result's own properties keyed by registered symbols into a plain
+/// object keyed by symbol name
+const SYMBOL_METADATA_CODE: &str = r#"
+(function symbolMetadata(value) {
+    const metadata = {};
+    if (value !== null && typeof value === 'object') {
+        for (const key of Object.getOwnPropertySymbols(value)) {
+            const name = Symbol.keyFor(key);
+            if (name !== undefined) {
+                metadata[name] = value[key];
+            }
+        }
+    }
+    return metadata;
+})
+"#;
+
+/// QuickJS reports stack overflow as an InternalError("stack overflow"). The engine
+/// offers no hook where errors are created, so with `stack_overflow_range_error` every
+/// catch block in user code passes its error through this first, turning an overflow
+/// into the RangeError other engines throw. Other InternalErrors are returned unchanged.
+const STACK_OVERFLOW_RANGE_ERROR_CODE: &str = r#"
+(function() {
+    const InternalErrorClass = InternalError;
+    const rangeErrorPrototype = RangeError.prototype;
+    const setPrototypeOf = Object.setPrototypeOf;
+    return function __stackOverflowToRangeError(error) {
+        if (error instanceof InternalErrorClass && error.message === 'stack overflow') {
+            setPrototypeOf(error, rangeErrorPrototype);
+            error.message = 'Maximum call stack size exceeded';
+        }
+        return error;
+    };
+})()
+"#;
+
+/// Message reported for uncaught stack overflows, matching V8 and SpiderMonkey
+const STACK_OVERFLOW_MESSAGE: &str = "RangeError: Maximum call stack size exceeded";
+
+/// Calls the compiled main once per input, in order, collecting the results
+const MAP_INPUTS_CODE: &str = r#"
+(async function mapInputs(main, inputs) {
+    if (!Array.isArray(inputs)) {
+        throw new TypeError("map_inputs requires an array input");
+    }
+    const results = [];
+    for (const input of inputs) {
+        results.push(await main(input));
+    }
+    return results;
+})
+"#;
+
+/// Grace period past timeout_ms before the watchdog gives up on the execution thread
+const WATCHDOG_GRACE_MS: u64 = 250;
+
+/// How often the watchdog checks the cancellation flag while waiting
+const WATCHDOG_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Native stack headroom for the execution thread on top of the QuickJS stack limit
+const EXECUTION_THREAD_STACK_MARGIN: usize = 4 * 1024 * 1024;
+
+/// Execute JavaScript code in a sandboxed QuickJS environment
+///
+/// The execution runs on a dedicated thread under a wall-clock watchdog, so a
+/// blocking native call (e.g. a stuck fetch) cannot overrun the timeout by more
+/// than WATCHDOG_GRACE_MS even though the interrupt handler never gets a chance to run.
+pub fn execute_js(
+    code: &str,
+    timeout_ms: u64,
+    memory_limit: usize,
+    allowed_domains: &[&str],
+    input: Option<serde_json::Value>,
+    options: &ExecutionOptions,
+) -> Result<ExecutionResult> {
+    let script = Script::Source(code.to_string());
+    execute(script, timeout_ms, memory_limit, allowed_domains, input, options)
+}
+
+/// A script compiled once by `compile` and run any number of times
+///
+/// Each run gets a fresh runtime and context, exactly like `execute_js`; only the
+/// parsing and compilation of the wrapped code is shared.
+#[derive(Clone)]
+pub struct CompiledScript {
+    script: Script,
+}
+
+/// Compile `code` with the same wrapping `execute_js` applies, for later runs with the
+/// same options. Syntax errors are reported here rather than per run.
+///
+/// Compilation gets the same thread, memory, stack and interrupt limits as a run.
+pub fn compile(
+    code: &str,
+    timeout_ms: u64,
+    memory_limit: usize,
+    options: &ExecutionOptions,
+) -> Result<CompiledScript> {
+    let code = code.to_string();
+    let options = options.clone();
+    execution_thread(options.stack_size_bytes)
+        .spawn(move || compile_on_thread(&code, timeout_ms, memory_limit, &options))?
+        .join()
+        .map_err(|_| anyhow!("Compilation thread terminated unexpectedly"))?
+}
+
+fn compile_on_thread(
+    code: &str,
+    timeout_ms: u64,
+    memory_limit: usize,
+    options: &ExecutionOptions,
+) -> Result<CompiledScript> {
+    let runtime = Runtime::new()?;
+    runtime.set_memory_limit(memory_limit);
+    runtime.set_max_stack_size(options.stack_size_bytes);
+
+    let start = Instant::now();
+    let timeout = Duration::from_millis(timeout_ms);
+    let cancel_flag = options.cancel_flag.clone();
+    runtime.set_interrupt_handler(Some(Box::new(move || {
+        interruption(start, timeout, cancel_flag.as_deref()).is_some()
+    })));
+    let context = Context::full(&runtime)?;
+
+    let (main_source, bytecode) = context.with(|ctx| -> Result<(String, Vec<u8>)> {
+        let main_source = wrap_main(&ctx, code, options);
+        let interrupted = interruption(start, timeout, options.cancel_flag.as_deref());
+        let bytecode = bytecode::compile_script(&ctx, &main_source)
+            .catch(&ctx)
+            .map_err(|e| js_failure(&ctx, e, "JavaScript execution error", interrupted))?;
+        Ok((main_source, bytecode))
+    })?;
+
+    Ok(CompiledScript {
+        script: Script::Compiled {
+            main_source: Arc::new(main_source),
+            bytecode: Arc::new(bytecode),
+        },
+    })
+}
+
+impl CompiledScript {
+    /// Run the compiled script against `input` in a fresh, isolated context. `options`
+    /// must match those given to `compile`, which fixed how the code was wrapped.
+    pub fn run(
+        &self,
+        timeout_ms: u64,
+        memory_limit: usize,
+        allowed_domains: &[&str],
+        input: Option<serde_json::Value>,
+        options: &ExecutionOptions,
+    ) -> Result<ExecutionResult> {
+        execute(self.script.clone(), timeout_ms, memory_limit, allowed_domains, input, options)
+    }
+}
+
+/// What a run evaluates: user code still to be wrapped, or main already compiled
+#[derive(Clone)]
+enum Script {
+    Source(String),
+    Compiled { main_source: Arc<String>, bytecode: Arc<Vec<u8>> },
+}
+
+/// Run `script` on a dedicated thread under the watchdog; see `execute_js`
+fn execute(
+    script: Script,
+    timeout_ms: u64,
+    memory_limit: usize,
+    allowed_domains: &[&str],
+    input: Option<serde_json::Value>,
+    options: &ExecutionOptions,
+) -> Result<ExecutionResult> {
+    // Reject malformed input before spending any time in the runtime
+    if let Some(schema) = &options.input_schema {
+        match input.as_ref() {
+            Some(serde_json::Value::Array(inputs)) if options.map_inputs => {
+                inputs.iter().try_for_each(|item| validate_input(schema, item))?
+            }
+            input => validate_input(schema, input.unwrap_or(&serde_json::Value::Null))?,
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel();
+
+    let redactor = Redactor::new(&options.secrets);
+    let allowed_domains: Vec<String> = allowed_domains.iter().map(|s| s.to_string()).collect();
+    let options = options.clone();
+    let cancel_flag = options.cancel_flag.clone();
+    let run_state = RunState::default();
+    let run_state_clone = run_state.clone();
+
+    // Logs from the execution thread go to the caller's subscriber, inside its span
+    let dispatch = tracing::dispatcher::get_default(|dispatch| dispatch.clone());
+    let span = tracing::Span::current();
+
+    execution_thread(options.stack_size_bytes)
+        .spawn(move || {
+            let _dispatch = tracing::dispatcher::set_default(&dispatch);
+            let _span = span.enter();
+            let allowed_domains_refs: Vec<&str> = allowed_domains.iter().map(|s| s.as_str()).collect();
+            let result = run_sandboxed(
+                &script,
+                timeout_ms,
+                memory_limit,
+                &allowed_domains_refs,
+                input,
+                &options,
+                run_state_clone,
+            );
+            // The receiver is gone if the watchdog already gave up
+            sender.send(result).ok();
+        })?;
+
+    let deadline = Instant::now() + Duration::from_millis(timeout_ms + WATCHDOG_GRACE_MS);
+    let mut cancel_deadline = None;
+    let outcome = loop {
+        let now = Instant::now();
+
+        // Once cancelled, give the interrupt handler the same grace period to unwind
+        if cancel_deadline.is_none() && is_cancelled(cancel_flag.as_deref()) {
+            cancel_deadline = Some(now + Duration::from_millis(WATCHDOG_GRACE_MS));
+        }
+        let limit = cancel_deadline.map_or(deadline, |c| c.min(deadline));
+        if now >= limit {
+            break Err(match cancel_deadline {
+                Some(_) => ExecutionError::Cancelled.into(),
+                None => ExecutionError::Timeout.into(),
+            });
+        }
+
+        match receiver.recv_timeout((limit - now).min(WATCHDOG_POLL_INTERVAL)) {
+            Ok(result) => break result,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(anyhow!("Execution thread terminated unexpectedly"))
+            }
+        }
+    };
+
+    // A timed-out run that checkpointed returns its last checkpoint as a partial result
+    let outcome = match outcome {
+        Err(e) if matches!(e.downcast_ref(), Some(ExecutionError::Timeout)) => {
+            run_state.take_checkpoint().ok_or(e)
+        }
+        outcome => outcome,
+    };
+
+    // Console lines are redacted as they are logged; values and errors are redacted here
+    match outcome {
+        Ok(mut result) => {
+            redactor.redact_json(&mut result.value);
+            result.yielded.iter_mut().for_each(|value| redactor.redact_json(value));
+            result.emitted.iter_mut().for_each(|value| redactor.redact_json(value));
+            redactor.redact_map(&mut result.metadata);
+            Ok(result)
+        }
+        Err(e) => Err(redactor.redact_error(e)),
+    }
+}
+
+/// A thread for QuickJS to run on, with native stack to spare over its stack limit
+fn execution_thread(stack_size_bytes: usize) -> std::thread::Builder {
+    std::thread::Builder::new()
+        .name("js-executor".to_string())
+        .stack_size(stack_size_bytes + EXECUTION_THREAD_STACK_MARGIN)
+}
+
+fn is_cancelled(cancel_flag: Option<&AtomicBool>) -> bool {
+    cancel_flag.is_some_and(|flag| flag.load(Ordering::Relaxed))
+}
+
+/// The reason execution should stop right now, if any; cancellation wins over timeout
+fn interruption(start: Instant, timeout: Duration, cancel_flag: Option<&AtomicBool>) -> Option<ExecutionError> {
+    if is_cancelled(cancel_flag) {
+        Some(ExecutionError::Cancelled)
+    } else if start.elapsed() > timeout {
+        Some(ExecutionError::Timeout)
+    } else {
+        None
+    }
+}
+
+/// Create and tear down a runtime and context to page in QuickJS before real work
+pub fn warm_up() -> Result<()> {
+    let runtime = Runtime::new()?;
+    let _context = Context::full(&runtime)?;
+    Ok(())
+}
+
+/// Check the input against a JSON Schema, listing every violation
+fn validate_input(schema: &serde_json::Value, input: &serde_json::Value) -> Result<()> {
+    let validator =
+        jsonschema::validator_for(schema).map_err(|e| anyhow!("Invalid input schema: {}", e))?;
+    let errors: Vec<String> = validator
+        .iter_errors(input)
+        .map(|e| {
+            let path = e.instance_path.to_string();
+            if path.is_empty() {
+                e.to_string()
+            } else {
+                format!("{} (at {})", e, path)
+            }
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("Input validation failed: {}", errors.join("; ")))
+    }
+}
+
+/// Run the script on the current thread; see `execute_js`
+fn run_sandboxed(
+    script: &Script,
+    timeout_ms: u64,
+    memory_limit: usize,
+    allowed_domains: &[&str],
+    input: Option<serde_json::Value>,
+    options: &ExecutionOptions,
+    run_state: RunState,
+) -> Result<ExecutionResult> {
+    // Create QuickJS runtime with memory limit
+    let runtime = Runtime::new()?;
+
+    // Set memory limit
+    runtime.set_memory_limit(memory_limit);
+
+    // Set max stack size
+    runtime.set_max_stack_size(options.stack_size_bytes);
+
+    // Track execution start time for timeout
+    let start = Instant::now();
+    let timeout_duration = Duration::from_millis(timeout_ms);
+    let start_clone = start;
+    let timeout_clone = timeout_duration;
+    let cancel_flag = options.cancel_flag.clone();
+
+    // QuickJS polls the handler after a fixed amount of bytecode work, so the number
+    // of polls is a CPU measure that doesn't depend on how loaded the host is
+    let ticks = Arc::new(AtomicU64::new(0));
+    let ticks_clone = ticks.clone();
+    let max_ticks = options.max_interrupt_ticks;
+
+    // Only ever set when console errors are fatal
+    let console_error: Arc<OnceLock<String>> = Arc::new(OnceLock::new());
+    let console_error_clone = console_error.clone();
+
+    // Set interrupt handler for timeout, cancellation, the work budget and console errors
+    runtime.set_interrupt_handler(Some(Box::new(move || {
+        let ticked = ticks_clone.fetch_add(1, Ordering::Relaxed) + 1;
+        max_ticks.is_some_and(|max| ticked > max)
+            || console_error_clone.get().is_some()
+            || interruption(start_clone, timeout_clone, cancel_flag.as_deref()).is_some()
+    })));
+    let interrupted = || {
+        if let Some(message) = console_error.get() {
+            Some(ExecutionError::ConsoleError(message.clone()))
+        } else if max_ticks.is_some_and(|max| ticks.load(Ordering::Relaxed) > max) {
+            Some(ExecutionError::WorkBudgetExceeded)
+        } else {
+            interruption(start, timeout_duration, options.cancel_flag.as_deref())
+        }
+    };
+
+    let context = Context::full(&runtime)?;
+
+    // Create console for capturing output
+    let console = Console {
+        output: run_state.console_output.clone(),
+        entries: options.structured_console.then(|| run_state.console_entries.clone()),
+        first_error: options.fail_on_console_error.then(|| console_error.clone()),
+        ..Console::new(
+            options.on_console_line.clone(),
+            options.structured_console,
+            options.coalesce_console,
+            Redactor::new(&options.secrets),
+            start,
+        )
+    };
+    let fetch_metrics = FetchMetrics {
+        deadline: options.fetch_time_budget.then(|| start + timeout_duration),
+        execution_deadline: Some(start + timeout_duration),
+        ..run_state.fetch_metrics.clone()
+    };
+    let native_time = run_state.native_time.clone();
+    let emitted = run_state.emitted.clone();
+    let accessed_globals = run_state.accessed_globals.clone();
+
+    let diagnostics = options.diagnostics.as_ref();
+    let mut phase_start = start;
+    let mut end_phase = |name: &str| {
+        if let Some(sink) = diagnostics {
+            sink.lock().unwrap().phases.push(PhaseTiming {
+                name: name.to_string(),
+                duration_ms: phase_start.elapsed().as_secs_f64() * 1000.0,
+            });
+        }
+        phase_start = Instant::now();
+    };
+
+    let result = context.with(|ctx| {
+        setup_sandbox(
+            &ctx,
+            console.clone(),
+            fetch_metrics.clone(),
+            native_time.clone(),
+            allowed_domains,
+            start,
+            options,
+        )?;
+        if options.track_global_access {
+            setup_global_access_tracking(&ctx, options, accessed_globals.clone())?;
+        }
+
+        // Lock the remaining global bindings; scripts can still add their own globals
+        ctx.eval::<(), _>(LOCK_GLOBALS_CODE)?;
+
+        setup_emit(&ctx, &ctx.globals(), emitted.clone())?;
+        setup_checkpoint(&ctx, &ctx.globals(), run_state.checkpoint.clone())?;
+
+        // Inject the input object into the global scope
+        let mut input_source = "globalThis.__userInput = undefined;".to_string();
+        if let Some(inp) = input {
+            let input_json = serde_json::to_string(&inp)?;
+            let input_code = format!("globalThis.__userInput = {};", input_json);
+            ctx.eval::<(), _>(input_code.as_str())?;
+            input_source = if options.redact_input {
+                "globalThis.__userInput = /* redacted */;".to_string()
+            } else {
+                input_code
+            };
+
+            // Deep-freeze the input so user code cannot mutate it
+            ctx.eval::<(), _>(DEEP_FREEZE_INPUT_CODE)?;
+        } else {
+            ctx.eval::<(), _>("globalThis.__userInput = undefined;")?;
+        }
+        if let Some(bytes) = &options.input_bytes {
+            let define_input_bytes: Function = ctx.eval(INPUT_BYTES_CODE)?;
+            define_input_bytes.call::<_, ()>((ArrayBuffer::new_copy(ctx.clone(), &bytes[..])?,))?;
+        }
+
+        // Falling off the end of main returns `result`, either a local the code declared
+        // or the writable global provided here
+        if options.result_variable {
+            ctx.eval::<(), _>("globalThis.result = undefined;")?;
+        }
+
+        let main_source = match script {
+            Script::Source(code) => wrap_main(&ctx, code, options),
+            Script::Compiled { main_source, .. } => main_source.to_string(),
+        };
+        // Reported form of what runs: main applied to the input, or main alone in map
+        // mode, where MAP_INPUTS_CODE calls it once per input
+        let wrapped_code = if options.map_inputs {
+            main_source.clone()
+        } else {
+            format!("{}(globalThis.__userInput)", main_source)
+        };
+
+        debug!("Executing JavaScript code wrapped in async main(input)");
+        let executed_source = options
+            .capture_source
+            .then(|| format!("{}\n{}", input_source, wrapped_code));
+        if let Some(sink) = diagnostics {
+            sink.lock().unwrap().wrapped_code = wrapped_code.clone();
+        }
+        end_phase("setup");
+
+        // Sizing the bytecode takes a separate compile unless the script is precompiled
+        let bytecode_bytes = match script {
+            _ if !options.compile_metrics => None,
+            Script::Compiled { bytecode, .. } => Some(bytecode.len()),
+            Script::Source(_) => bytecode::compile_script(&ctx, &main_source)
+                .catch(&ctx)
+                .ok()
+                .map(|bytecode| bytecode.len()),
+        };
+
+        // Evaluating the function expression only compiles main; calling it runs the code
+        // and returns a Promise. In map mode MAP_INPUTS_CODE calls it once per input.
+        let run_start = Instant::now();
+        let main_fn: Function = match script {
+            Script::Source(_) => ctx.eval(main_source.as_str()),
+            Script::Compiled { bytecode, .. } => {
+                bytecode::load_script(&ctx, bytecode).and_then(|main| main.get())
+            }
+        }
+        .catch(&ctx)
+        .map_err(|e| js_failure(&ctx, e, "JavaScript execution error", interrupted()))?;
+        let compile_elapsed = run_start.elapsed();
+        let user_input: Value = ctx.globals().get("__userInput")?;
+        let promise: rquickjs::Promise = if options.map_inputs {
+            let map_inputs: Function = ctx.eval(MAP_INPUTS_CODE)?;
+            map_inputs
+                .call((main_fn, user_input))
+                .catch(&ctx)
+                .map_err(|e| js_failure(&ctx, e, "JavaScript execution error", interrupted()))?
+        } else {
+            main_fn
+                .call((user_input,))
+                .catch(&ctx)
+                .map_err(|e| js_failure(&ctx, e, "JavaScript execution error", interrupted()))?
+        };
+
+        // Wait for the promise to resolve
+        let result_value: Value = promise
+            .finish()
+            .catch(&ctx)
+            .map_err(|e| js_failure(&ctx, e, "Promise resolution error", interrupted()))?;
+        end_phase("execute");
+
+        // Result values (including yields) are truncated to the collection limits
+        let limits = JsonLimits {
+            max_object_keys: options.max_object_keys,
+            max_array_length: options.max_array_length,
+            max_depth: options.max_result_depth,
+            ..Default::default()
+        };
+
+        // If main returned a generator, drain it: yields are collected and the
+        // generator's return value becomes the result
+        let drain: Function = ctx.eval(DRAIN_GENERATOR_CODE)?;
+        let drained: Object = drain
+            .call::<_, rquickjs::Promise>((result_value,))
+            .and_then(|p| p.finish())
+            .catch(&ctx)
+            .map_err(|e| js_failure(&ctx, e, "Promise resolution error", interrupted()))?;
+        let result_value: Value = drained.get("value")?;
+        let yielded_values: rquickjs::Array = drained.get("yielded")?;
+        let mut yielded = Vec::with_capacity(yielded_values.len());
+        for item in yielded_values.iter::<Value>() {
+            yielded.push(value_to_json_limited(&ctx, item?, limits)?);
+        }
+        end_phase("drain");
+        let native_elapsed = native_time.as_duration();
+        let js_elapsed = run_start.elapsed().saturating_sub(native_elapsed);
+
+        // Check if timeout exceeded, the run was cancelled or console.error was fatal
+        if let Some(reason) = interrupted() {
+            return Err(reason.into());
+        }
+
+        let resolved_undefined = result_value.is_undefined();
+        if options.require_return && resolved_undefined {
+            return Err(anyhow!("main function returned no value"));
+        }
+
+        // Symbol-keyed metadata is reported separately from the result
+        let collect_metadata: Function = ctx.eval(SYMBOL_METADATA_CODE)?;
+        let metadata = match value_to_json(&ctx, collect_metadata.call((result_value.clone(),))?)? {
+            serde_json::Value::Object(map) => map,
+            _ => serde_json::Map::new(),
+        };
+
+        // Convert result to JSON, optionally falling back to String(value)
+        let fallback = if options.fallback_stringify {
+            stringify_fallback(&ctx, &result_value)?
+        } else {
+            None
+        };
+        let stringified = fallback.is_some();
+        let mut json_value = match fallback {
+            Some(text) => serde_json::Value::String(text),
+            None => value_to_json_limited(&ctx, result_value, limits)?,
+        };
+        if options.number_policy != NumberPolicy::Lossy {
+            apply_number_policy(&mut json_value, options.number_policy)?;
+            for item in yielded.iter_mut() {
+                apply_number_policy(item, options.number_policy)?;
+            }
+        }
+        if options.sort_keys {
+            sort_json_keys(&mut json_value);
+            yielded.iter_mut().for_each(sort_json_keys);
+        }
+        end_phase("serialize");
+
+        // Reject oversized results before they blow the Lambda response limit
+        let emitted = std::mem::take(&mut *emitted.lock().unwrap());
+        let result_size = serde_json::to_vec(&json_value)?.len()
+            + serde_json::to_vec(&yielded)?.len()
+            + serde_json::to_vec(&emitted)?.len()
+            + serde_json::to_vec(&metadata)?.len();
+        if result_size > options.max_result_bytes {
+            return Err(anyhow!(
+                "Execution result exceeds maximum size of {} bytes ({} bytes)",
+                options.max_result_bytes,
+                result_size
+            ));
+        }
+
+        Ok(ExecutionResult {
+            value: json_value,
+            console_output: console.get_output(),
+            structured_console: console.get_entries(),
+            yielded,
+            bytes_sent: fetch_metrics.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: fetch_metrics.bytes_received.load(Ordering::Relaxed),
+            stringified,
+            resolved_undefined,
+            executed_source,
+            emitted,
+            metadata,
+            native_time_ms: native_elapsed.as_secs_f64() * 1000.0,
+            js_time_ms: js_elapsed.as_secs_f64() * 1000.0,
+            timed_out: false,
+            accessed_globals: std::mem::take(&mut *accessed_globals.lock().unwrap()),
+            compile_time_ms: options.compile_metrics.then_some(compile_elapsed.as_secs_f64() * 1000.0),
+            bytecode_bytes,
+        })
+    })?;
+
+    Ok(result)
+}
+
+/// Wrap user code in `async function main(input)`, applying call-depth and catch
+/// instrumentation, the `result` epilogue and expression mode. `ctx` is only used to
+/// compile, never to run.
+fn wrap_main(ctx: &Ctx, code: &str, options: &ExecutionOptions) -> String {
+    // Count call depth at the start of every user function when a limit is set
+    let code = match options.max_call_depth {
+        Some(_) => instrument::inject_function_prologue(code, "__callDepthCheck();"),
+        None => code.to_string(),
+    };
+
+    // Caught stack overflows become RangeErrors before the catch block sees them
+    let code = if options.stack_overflow_range_error {
+        instrument::inject_catch_prologue(&code, |name| {
+            format!("{0} = __stackOverflowToRangeError({0});", name)
+        })
+    } else {
+        code
+    };
+
+    // Falling off the end of main returns `result`; an explicit return still wins
+    let epilogue = if options.result_variable { "\n;return result;" } else { "" };
+
+    // Code that parses as a single expression is returned directly; anything else
+    // falls back to statement wrapping. The wrapper interpolates user code, which could
+    // close the function early and add top-level statements, so it is only compiled,
+    // never evaluated.
+    let expression_wrapper = format!("(async function main(input) {{ return ({}\n); }})", code);
+    let is_expression = options.expression_mode
+        && bytecode::check_syntax(ctx, &expression_wrapper).catch(ctx).is_ok();
+
+    // The code starts on the wrapper's first line so error line numbers match the
+    // user's source
+    if is_expression {
+        expression_wrapper
+    } else {
+        format!(
+            r#"(async function main(input) {{ {}{}
+}})"#,
+            code, epilogue
+        )
+    }
+}
+
+/// Setup the sandbox environment with security restrictions
+fn setup_sandbox(
+    ctx: &Ctx,
+    console: Console,
+    fetch_metrics: FetchMetrics,
+    native_time: NativeTime,
+    allowed_domains: &[&str],
+    start: Instant,
+    options: &ExecutionOptions,
+) -> Result<()> {
+    let globals = ctx.globals();
+
+    // Setup console, or no-op methods that capture nothing when it's disabled
+    if options.console_enabled {
+        setup_console(ctx, &globals, console)?;
+    } else {
+        ctx.eval::<(), _>(NOOP_CONSOLE_CODE)?;
+    }
+
+    // Setup performance.now() relative to execution start
+    setup_performance(ctx, &globals, start)?;
+
+    // Expose configuration values as a frozen env object
+    let env_obj = Object::new(ctx.clone())?;
+    for (key, value) in &options.env {
+        env_obj.set(key.as_str(), value.as_str())?;
+    }
+    globals.set("env", env_obj)?;
+    ctx.eval::<(), _>("Object.freeze(globalThis.env);")?;
+
+    // Secrets are read the same way; redaction happens on the way out
+    let secrets_obj = Object::new(ctx.clone())?;
+    for (key, value) in &options.secrets {
+        secrets_obj.set(key.as_str(), value.as_str())?;
+    }
+    globals.set("secrets", secrets_obj)?;
+    ctx.eval::<(), _>("Object.freeze(globalThis.secrets);")?;
+
+    // Let scripts nudge garbage collection to stay under the memory limit
+    if options.allow_gc {
+        setup_gc(ctx, &globals)?;
+    }
+
+    // Tolerant parsing for upstreams that emit slightly non-standard JSON
+    setup_json5(ctx, &globals)?;
+
+    // UTF-8 TextDecoder, with streaming for text split across body chunks
+    setup_text_decoder(ctx, &globals)?;
+
+    // crypto.randomUUID() for ids; no keys or subtle API
+    if options.allow_crypto {
+        setup_crypto(ctx, &globals)?;
+    }
+
+    // Setup fetch with domain allowlist
+    setup_fetch(ctx, &globals, allowed_domains, options, fetch_metrics, native_time.clone())?;
+
+    // Expose embedder-registered host functions
+    for (name, host_fn) in &options.host_functions {
+        setup_host_function(ctx, &globals, name, host_fn.clone(), native_time.clone())?;
+    }
+
+    // Run the script as if the wall clock were offset, e.g. a day ahead to test expiry
+    if options.clock_offset_ms != 0 {
+        let shift_clock: Function = ctx.eval(CLOCK_OFFSET_CODE)?;
+        shift_clock.call::<_, ()>((options.clock_offset_ms as f64,))?;
+    }
+
+    // Caught deep recursion surfaces as a RangeError when asked for
+    if options.stack_overflow_range_error {
+        let to_range_error: Function = ctx.eval(STACK_OVERFLOW_RANGE_ERROR_CODE)?;
+        globals.set("__stackOverflowToRangeError", to_range_error)?;
+    }
+
+    // Freeze Object.prototype to prevent prototype pollution
+    ctx.eval::<(), _>("Object.freeze(Object.prototype);")?;
+    ctx.eval::<(), _>("Object.freeze(Array.prototype);")?;
+    ctx.eval::<(), _>(FREEZE_BUILTINS_CODE)?;
+
+    // WebAssembly is not supported; install a stub so failures are explicit
+    ctx.eval::<(), _>(WEBASSEMBLY_STUB_CODE)?;
+
+    // Remove dangerous globals unless explicitly allowed
+    for name in REMOVED_GLOBALS {
+        if !options.allowed_globals.iter().any(|allowed| allowed == name) {
+            globals.remove(*name).ok();
+        }
+    }
+
+    if options.restrict_reflection {
+        globals.remove("Proxy").ok();
+        globals.remove("Reflect").ok();
+    }
+
+    if let Some(max_depth) = options.max_call_depth {
+        let factory: Function = ctx.eval(CALL_DEPTH_CHECK_CODE)?;
+        let check_fn: Function = factory.call((max_depth,))?;
+        globals.set("__callDepthCheck", check_fn)?;
+    }
+
+    // Bundled modules are evaluated once and served from a frozen registry
+    if !options.modules.is_empty() {
+        setup_modules(ctx, &globals, &options.modules)?;
+    }
+
+    // Evaluate the prelude under the same restrictions as user code
+    if let Some(prelude) = &options.prelude_code {
+        setup_prelude(ctx, &globals, prelude)?;
+    }
+
+    Ok(())
+}
+
+// Depth is the number of stack frames between the check and the main wrapper,
+// which stays correct across await and generator suspensions
+const CALL_DEPTH_CHECK_CODE: &str = r#"
+(function(maxDepth) {
+    return function __callDepthCheck() {
+        const frames = new Error().stack.split("\n").filter((line) => line.startsWith("    at "));
+        let mainIndex = frames.length;
+        for (let i = frames.length - 1; i > 0; i--) {
+            if (frames[i].startsWith("    at main (")) {
+                mainIndex = i;
+                break;
+            }
+        }
+        if (mainIndex - 1 > maxDepth) {
+            throw new RangeError("maximum call depth exceeded");
+        }
+    };
+})
+"#;
+
+const REQUIRE_FACTORY_CODE: &str = r#"
+(function(registry) {
+    return function require(name) {
+        if (!Object.prototype.hasOwnProperty.call(registry, name)) {
+            throw new Error(`Module '${name}' is not available`);
+        }
+        return registry[name];
+    };
+})
+"#;
+
+/// Evaluate each module once, freeze its exports and expose them through require()
+fn setup_modules<'js>(ctx: &Ctx<'js>, globals: &Object<'js>, modules: &HashMap<String, String>) -> Result<()> {
+    let object_ctor: Object = globals.get("Object")?;
+    let freeze: Function = object_ctor.get("freeze")?;
+    let create: Function = object_ctor.get("create")?;
+    let registry: Object = create.call((rquickjs::Null,))?;
+
+    for (name, source) in modules {
+        // Wrap the source CommonJS-style so it can assign module.exports
+        let wrapped = format!("(function(module, exports) {{\n{}\n}})", source);
+        let module_fn: Function = ctx
+            .eval(wrapped.as_str())
+            .catch(ctx)
+            .map_err(|e| anyhow!("Module '{}' error: {}", name, format_js_error(ctx, e)))?;
+
+        let module_obj = Object::new(ctx.clone())?;
+        module_obj.set("exports", Object::new(ctx.clone())?)?;
+        let exports: Value = module_obj.get("exports")?;
+        module_fn
+            .call::<_, ()>((module_obj.clone(), exports))
+            .catch(ctx)
+            .map_err(|e| anyhow!("Module '{}' error: {}", name, format_js_error(ctx, e)))?;
+
+        let exports: Value = module_obj.get("exports")?;
+        freeze.call::<_, ()>((exports.clone(),))?;
+        registry.set(name.as_str(), exports)?;
+    }
+    freeze.call::<_, ()>((registry.clone(),))?;
+
+    let factory: Function = ctx.eval(REQUIRE_FACTORY_CODE)?;
+    let require_fn: Function = factory.call((registry,))?;
+    globals.set("require", require_fn)?;
+
+    Ok(())
+}
+
+/// Evaluate helper code and freeze every global it defines
+fn setup_prelude<'js>(ctx: &Ctx<'js>, globals: &Object<'js>, prelude: &str) -> Result<()> {
+    let existing: Vec<String> = globals.keys::<String>().collect::<rquickjs::Result<_>>()?;
+
+    ctx.eval::<(), _>(prelude)
+        .catch(ctx)
+        .map_err(|e| anyhow!("Prelude error: {}", format_js_error(ctx, e)))?;
+
+    let object_ctor: Object = globals.get("Object")?;
+    let freeze: Function = object_ctor.get("freeze")?;
+    for key in globals.keys::<String>() {
+        let key = key?;
+        if !existing.contains(&key) {
+            let value: Value = globals.get(key.as_str())?;
+            freeze.call::<_, ()>((value,))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Only the current time moves: dates built from explicit arguments, Date.parse and
+// Date.UTC are unchanged, and instances are still real Dates
+const CLOCK_OFFSET_CODE: &str = r#"
+(function(offsetMs) {
+    const RealDate = Date;
+    const now = () => RealDate.now() + offsetMs;
+    const ShiftedDate = function Date(...args) {
+        if (new.target === undefined) {
+            return new RealDate(now()).toString();
+        }
+        return args.length === 0 ? new RealDate(now()) : new RealDate(...args);
+    };
+    ShiftedDate.prototype = RealDate.prototype;
+    ShiftedDate.now = now;
+    ShiftedDate.parse = RealDate.parse;
+    ShiftedDate.UTC = RealDate.UTC;
+    Object.defineProperty(RealDate.prototype, "constructor", { value: ShiftedDate });
+    globalThis.Date = ShiftedDate;
+})
+"#;
+
+/// Freezes primitive wrapper and Function prototypes along with the core constructors
+const FREEZE_BUILTINS_CODE: &str = r#"
+[Object, Array, String, Number, Boolean, Function].forEach((ctor) => {
+    Object.freeze(ctor.prototype);
+    Object.freeze(ctor);
+});
+"#;
+
+/// Replaces WebAssembly with a frozen stub whose entry points fail with a documented message
+const WEBASSEMBLY_STUB_CODE: &str = r#"
+(function() {
+    const message = "WebAssembly is disabled in this sandbox";
+    const fail = function() { throw new Error(message); };
+    const reject = function() { return Promise.reject(new Error(message)); };
+    globalThis.WebAssembly = Object.freeze({
+        validate: fail,
+        compile: reject,
+        instantiate: reject,
+        compileStreaming: reject,
+        instantiateStreaming: reject,
+        Module: fail,
+        Instance: fail,
+        Memory: fail,
+        Table: fail,
+    });
+})();
+"#;
+
+/// Makes every existing global binding non-writable and non-configurable
+const LOCK_GLOBALS_CODE: &str = r#"
+Object.getOwnPropertyNames(globalThis).forEach((name) => {
+    const descriptor = Object.getOwnPropertyDescriptor(globalThis, name);
+    if (descriptor.configurable) {
+        if ('value' in descriptor) {
+            descriptor.writable = false;
+        }
+        descriptor.configurable = false;
+        Object.defineProperty(globalThis, name, descriptor);
+    }
+});
+"#;
+
+/// Setup console API for capturing output
+fn setup_console<'js>(ctx: &Ctx<'js>, globals: &Object<'js>, console: Console) -> Result<()> {
+    let console_obj = Object::new(ctx.clone())?;
+
+    // Create console.log function
+    let console_clone = console.clone();
+    let log_fn = Function::new(
+        ctx.clone(),
+        move |args: rquickjs::function::Rest<Value>| {
+            let messages: Vec<String> = args
+                .iter()
+                .map(|v| value_to_string(v))
+                .collect();
+            let message = messages.join(" ");
+            console_clone.log(message);
+        },
+    )?;
+
+    console_obj.set("log", log_fn)?;
+
+    let console_clone = console.clone();
+    let error_fn = Function::new(ctx.clone(), move |args: rquickjs::function::Rest<Value>| {
+        let messages: Vec<String> = args.iter().map(value_to_string).collect();
+        console_clone.error(messages.join(" "));
+    })?;
+    console_obj.set("error", error_fn)?;
+
+    // Create console.assert, which records (but never throws on) failed assertions
+    let console_clone = console.clone();
+    let assert_fn = Function::new(
+        ctx.clone(),
+        move |condition: Opt<Value>, args: rquickjs::function::Rest<Value>| {
+            let passed = condition
+                .0
+                .and_then(|v| v.get::<Coerced<bool>>().ok())
+                .map(|b| b.0)
+                .unwrap_or(false);
+            if !passed {
+                let messages: Vec<String> = args.iter().map(value_to_string).collect();
+                if messages.is_empty() {
+                    console_clone.error("Assertion failed".to_string());
+                } else {
+                    console_clone.error(format!("Assertion failed: {}", messages.join(" ")));
+                }
+            }
+        },
+    )?;
+    console_obj.set("assert", assert_fn)?;
+
+    // Create console.dir, which pretty-prints nested structures up to a depth
+    let console_clone = console.clone();
+    let dir_fn = Function::new(
+        ctx.clone(),
+        move |value: Opt<Value>, options: Opt<Object>| {
+            // depth: null or Infinity means unlimited, as in Node.js
+            let depth = match options.0.and_then(|o| o.get::<_, Value>("depth").ok()) {
+                Some(d) if d.is_null() => usize::MAX,
+                Some(d) => match d.as_number() {
+                    Some(n) if n.is_infinite() => usize::MAX,
+                    Some(n) => n.max(0.0) as usize,
+                    None => DEFAULT_DIR_DEPTH,
+                },
+                None => DEFAULT_DIR_DEPTH,
+            };
+            let message = match value.0 {
+                Some(v) => inspect_value(&v, depth, 0, &mut Vec::new()),
+                None => "undefined".to_string(),
+            };
+            console_clone.dir(message);
+        },
+    )?;
+    console_obj.set("dir", dir_fn)?;
+
+    // Create console.time/console.timeEnd for lightweight profiling
+    let console_clone = console.clone();
+    let time_fn = Function::new(ctx.clone(), move |label: Opt<String>| {
+        console_clone.time(label.0.unwrap_or_else(|| "default".to_string()));
+    })?;
+    console_obj.set("time", time_fn)?;
+
+    let console_clone = console.clone();
+    let time_end_fn = Function::new(ctx.clone(), move |label: Opt<String>| {
+        console_clone.time_end(label.0.unwrap_or_else(|| "default".to_string()));
+    })?;
+    console_obj.set("timeEnd", time_end_fn)?;
+
+    // Create console.group/groupEnd, which indent the lines logged between them
+    let console_clone = console.clone();
+    let group_fn = Function::new(ctx.clone(), move |args: rquickjs::function::Rest<Value>| {
+        let label = (!args.is_empty())
+            .then(|| args.iter().map(value_to_string).collect::<Vec<_>>().join(" "));
+        console_clone.group(label);
+    })?;
+    console_obj.set("group", group_fn)?;
+
+    let console_clone = console.clone();
+    let group_end_fn = Function::new(ctx.clone(), move || console_clone.group_end())?;
+    console_obj.set("groupEnd", group_end_fn)?;
+
+    // Add console._times for Node.js compatibility (SES requirement)
+    let times_obj = Object::new(ctx.clone())?;
+    console_obj.set("_times", times_obj)?;
+
+    globals.set("console", console_obj)?;
+
+    Ok(())
+}
+
+/// Setup a monotonic performance.now() timer
+/// Times are relative to execution start so no wall-clock information is exposed
+fn setup_performance<'js>(ctx: &Ctx<'js>, globals: &Object<'js>, start: Instant) -> Result<()> {
+    let performance_obj = Object::new(ctx.clone())?;
+
+    let now_fn = Function::new(ctx.clone(), move || start.elapsed().as_secs_f64() * 1000.0)?;
+    performance_obj.set("now", now_fn)?;
+    performance_obj.set("timeOrigin", 0)?;
+
+    globals.set("performance", performance_obj)?;
+
+    Ok(())
+}
+
+/// Setup a gc() global that runs the QuickJS garbage collector
+fn setup_gc<'js>(ctx: &Ctx<'js>, globals: &Object<'js>) -> Result<()> {
+    let gc_fn = Function::new(ctx.clone(), |ctx: Ctx<'js>| ctx.run_gc())?;
+    globals.set("gc", gc_fn)?;
+
+    Ok(())
+}
+
+/// Setup a parseJSON5(text) global; JSON.parse stays strict
+fn setup_json5<'js>(ctx: &Ctx<'js>, globals: &Object<'js>) -> Result<()> {
+    let parse_fn = Function::new(
+        ctx.clone(),
+        |ctx: Ctx<'js>, text: Coerced<String>| -> rquickjs::Result<Value<'js>> {
+            let value: serde_json::Value = json5::from_str(&text.0)
+                .map_err(|e| Exception::throw_syntax(&ctx, &format!("Invalid JSON5: {}", e)))?;
+            ctx.json_parse(value.to_string())
+        },
+    )?;
+    globals.set("parseJSON5", parse_fn)?;
+
+    Ok(())
+}
+
+/// Setup a frozen crypto object with randomUUID()
+fn setup_crypto<'js>(ctx: &Ctx<'js>, globals: &Object<'js>) -> Result<()> {
+    let crypto = Object::new(ctx.clone())?;
+    crypto.set("randomUUID", Function::new(ctx.clone(), || uuid::Uuid::new_v4().to_string())?)?;
+    globals.set("crypto", crypto)?;
+    ctx.eval::<(), _>("Object.freeze(globalThis.crypto);")?;
+
+    Ok(())
+}
+
+/// Setup a UTF-8 TextDecoder; invalid sequences decode to U+FFFD
+fn setup_text_decoder<'js>(ctx: &Ctx<'js>, globals: &Object<'js>) -> Result<()> {
+    let decode_fn = Function::new(ctx.clone(), |buffer: ArrayBuffer<'js>| -> String {
+        String::from_utf8_lossy(buffer.as_bytes().unwrap_or_default()).into_owned()
+    })?;
+    let factory: Function = ctx.eval(TEXT_DECODER_CODE)?;
+    let text_decoder: Value = factory.call((decode_fn,))?;
+    globals.set("TextDecoder", text_decoder)?;
+
+    Ok(())
+}
+
+// With { stream: true }, bytes that may start an unfinished sequence are held back
+// and prefixed to the next chunk
+const TEXT_DECODER_CODE: &str = r#"
+(function(decodeUtf8) {
+    function toBytes(input) {
+        if (input === undefined) {
+            return new Uint8Array(0);
+        }
+        if (input instanceof ArrayBuffer) {
+            return new Uint8Array(input);
+        }
+        if (ArrayBuffer.isView(input)) {
+            return new Uint8Array(input.buffer, input.byteOffset, input.byteLength);
+        }
+        throw new TypeError("TextDecoder.decode expects an ArrayBuffer or ArrayBuffer view");
+    }
+
+    // Number of trailing bytes that belong to a sequence the chunk doesn't finish
+    function incompleteTail(bytes) {
+        for (let back = 1; back <= Math.min(3, bytes.length); back++) {
+            const byte = bytes[bytes.length - back];
+            if ((byte & 0xc0) !== 0x80) {
+                const length = byte >= 0xf0 ? 4 : byte >= 0xe0 ? 3 : byte >= 0xc0 ? 2 : 1;
+                return length > back ? back : 0;
+            }
+        }
+        return 0;
+    }
+
+    return class TextDecoder {
+        constructor(label = "utf-8") {
+            const encoding = String(label).trim().toLowerCase();
+            if (encoding !== "utf-8" && encoding !== "utf8") {
+                throw new RangeError(`Unsupported encoding: ${label}`);
+            }
+            this.encoding = "utf-8";
+            this._pending = new Uint8Array(0);
+        }
+
+        decode(input, options) {
+            const chunk = toBytes(input);
+            let bytes = chunk;
+            if (this._pending.length > 0) {
+                bytes = new Uint8Array(this._pending.length + chunk.length);
+                bytes.set(this._pending);
+                bytes.set(chunk, this._pending.length);
+            }
+            const keep = options && options.stream ? incompleteTail(bytes) : 0;
+            this._pending = bytes.slice(bytes.length - keep);
+            const complete = bytes.subarray(0, bytes.length - keep);
+            return decodeUtf8(
+                complete.buffer.slice(complete.byteOffset, complete.byteOffset + complete.byteLength)
+            );
+        }
+    };
+})
+"#;
+
+/// Globals that give a script a capability beyond computation
+const TRACKED_GLOBALS: &[&str] =
+    &["fetch", "fetchAll", "fetchJson", "crypto", "env", "secrets", "gc", "require"];
+
+/// Replace each capability global (plus host functions) with a getter that records
+/// its first read; must run before the globals are locked
+fn setup_global_access_tracking<'js>(
+    ctx: &Ctx<'js>,
+    options: &ExecutionOptions,
+    accessed: Arc<Mutex<Vec<String>>>,
+) -> Result<()> {
+    let mut names: Vec<String> = TRACKED_GLOBALS.iter().map(|name| name.to_string()).collect();
+    let mut host_names: Vec<&String> = options.host_functions.keys().collect();
+    host_names.sort();
+    names.extend(host_names.into_iter().cloned());
+
+    let record_fn = Function::new(ctx.clone(), move |name: String| {
+        let mut accessed = accessed.lock().unwrap();
+        if !accessed.contains(&name) {
+            accessed.push(name);
+        }
+    })?;
+    let track: Function = ctx.eval(TRACK_GLOBAL_ACCESS_CODE)?;
+    track.call::<_, ()>((names, record_fn))?;
+
+    Ok(())
+}
+
+const TRACK_GLOBAL_ACCESS_CODE: &str = r#"
+(function(names, record) {
+    names.forEach((name) => {
+        const descriptor = Object.getOwnPropertyDescriptor(globalThis, name);
+        if (!descriptor || !('value' in descriptor) || !descriptor.configurable) {
+            return;
+        }
+        const value = descriptor.value;
+        Object.defineProperty(globalThis, name, {
+            get() {
+                record(name);
+                return value;
+            },
+            enumerable: descriptor.enumerable,
+            configurable: true,
+        });
+    });
+})
+"#;
+
+const NOOP_CONSOLE_CODE: &str = r#"
+(function() {
+    const noop = function() {};
+    globalThis.console = {
+        log: noop, error: noop, assert: noop, dir: noop, time: noop, timeEnd: noop,
+        group: noop, groupEnd: noop,
+    };
+})();
+"#;
+
+/// Expose emit(record), which streams records into the result as they are produced
+fn setup_emit<'js>(
+    ctx: &Ctx<'js>,
+    globals: &Object<'js>,
+    emitted: Arc<Mutex<Vec<serde_json::Value>>>,
+) -> Result<()> {
+    let emit_fn = Function::new(
+        ctx.clone(),
+        move |ctx: Ctx<'js>, record: Opt<Value<'js>>| -> rquickjs::Result<()> {
+            let record = match record.0 {
+                Some(value) => value_to_json(&ctx, value)
+                    .map_err(|e| Exception::throw_type(&ctx, &e.to_string()))?,
+                None => serde_json::Value::Null,
+            };
+            emitted.lock().unwrap().push(record);
+            Ok(())
+        },
+    )?;
+    globals.set("emit", emit_fn)?;
+
+    Ok(())
+}
+
+/// Expose checkpoint(value), which keeps the latest value as the result of a run that times out
+fn setup_checkpoint<'js>(
+    ctx: &Ctx<'js>,
+    globals: &Object<'js>,
+    checkpoint: Arc<Mutex<Option<serde_json::Value>>>,
+) -> Result<()> {
+    let checkpoint_fn = Function::new(
+        ctx.clone(),
+        move |ctx: Ctx<'js>, value: Opt<Value<'js>>| -> rquickjs::Result<()> {
+            // Serialize now: the value may be mutated, or the runtime gone, by the time it's read
+            let value = match value.0 {
+                Some(value) => value_to_json(&ctx, value)
+                    .map_err(|e| Exception::throw_type(&ctx, &e.to_string()))?,
+                None => serde_json::Value::Null,
+            };
+            *checkpoint.lock().unwrap() = Some(value);
+            Ok(())
+        },
+    )?;
+    globals.set("checkpoint", checkpoint_fn)?;
+
+    Ok(())
+}
+
+/// Expose a host function that takes and returns JSON-compatible values
+fn setup_host_function<'js>(
+    ctx: &Ctx<'js>,
+    globals: &Object<'js>,
+    name: &str,
+    host_fn: HostFunction,
+    native_time: NativeTime,
+) -> Result<()> {
+    let native_fn = Function::new(
+        ctx.clone(),
+        move |ctx: Ctx<'js>, arg: Opt<Value<'js>>| -> rquickjs::Result<Value<'js>> {
+            let arg = match arg.0 {
+                Some(value) => value_to_json(&ctx, value)
+                    .map_err(|e| Exception::throw_type(&ctx, &e.to_string()))?,
+                None => serde_json::Value::Null,
+            };
+            let called_at = Instant::now();
+            let result = host_fn(arg);
+            native_time.add(called_at.elapsed());
+            ctx.json_parse(result.to_string())
+        },
+    )?;
+    globals.set(name, native_fn)?;
+
+    Ok(())
+}
+
+/// Setup fetch API with domain allowlist
+/// Returns a standards-compliant Promise-based fetch API
+fn setup_fetch<'js>(
+    ctx: &Ctx<'js>,
+    globals: &Object<'js>,
+    allowed_domains: &[&str],
+    execution_options: &ExecutionOptions,
+    metrics: FetchMetrics,
+    native_time: NativeTime,
+) -> Result<()> {
+    // Entries with a scheme allow one URL (and the paths below it), CIDR entries allow
+    // IP-literal hosts in a range, and the rest allow a host
+    let (url_entries, other_entries): (Vec<&str>, Vec<&str>) =
+        allowed_domains.iter().partition(|entry| entry.contains("://"));
+    let (cidr_entries, host_entries): (Vec<&str>, Vec<&str>) =
+        other_entries.into_iter().partition(|entry| entry.contains('/'));
+    let allowed_domains_vec: Vec<String> = host_entries.iter().map(|s| s.to_string()).collect();
+    let allowed_urls = url_entries
+        .iter()
+        .map(|entry| Url::parse(entry).map_err(|e| anyhow!("Invalid allowlist URL '{}': {}", entry, e)))
+        .collect::<Result<Vec<Url>>>()?;
+    let allowed_networks = cidr_entries
+        .iter()
+        .map(|entry| IpNetwork::parse(entry))
+        .collect::<Result<Vec<IpNetwork>>>()?;
+    let default_headers: Vec<(String, String)> = build_default_headers(&execution_options.default_headers)?
+        .iter()
+        .map(|(name, value)| {
+            (name.as_str().to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned())
+        })
+        .collect();
+    let client: Arc<dyn HttpClient> = match &execution_options.http_client {
+        Some(client) => client.clone(),
+        None => Arc::new(ReqwestClient::new()?),
+    };
+    let per_domain_limit = execution_options.per_domain_fetch_limit;
+    let max_response_bytes = execution_options.max_response_bytes;
+    let max_url_length = execution_options.max_url_length;
+    let allowed_ports = execution_options.allowed_ports.clone();
+    let retry_policy = execution_options.fetch_retry.clone();
+    let path_rules = execution_options.path_rules.clone();
+    let base_url = match &execution_options.base_url {
+        Some(base) => Some(Url::parse(base).map_err(|e| anyhow!("Invalid base_url '{}': {}", base, e))?),
+        None => None,
+    };
+    let domain_counts: Arc<Mutex<HashMap<String, usize>>> = Arc::new(Mutex::new(HashMap::new()));
+    let diagnostics = execution_options.diagnostics.clone();
+    // Diagnostics reach the caller directly, so URLs and errors are redacted on the way in
+    let redactor = Redactor::new(&execution_options.secrets);
+
+    // Create a synchronous native fetch that returns either a response object or an error object
+    let sync_fetch = Function::new(
+        ctx.clone(),
+        move |ctx: Ctx<'js>, url: String, options: Object<'js>| -> rquickjs::Result<Object<'js>> {
+            let url_length = url.chars().count();
+            if url_length > max_url_length {
+                let error_obj = Object::new(ctx.clone())?;
+                error_obj.set("__isError", true)?;
+                error_obj.set(
+                    "message",
+                    format!(
+                        "URL exceeds maximum length of {} characters ({} characters)",
+                        max_url_length, url_length
+                    ),
+                )?;
+                return Ok(error_obj);
+            }
+
+            // Validate URL and domain; relative URLs resolve against base_url when set
+            let resolved = match &base_url {
+                Some(base) => base.join(&url),
+                None => Url::parse(&url),
+            };
+            let parsed_url = match resolved {
+                Ok(u) => u,
+                Err(e) => {
+                    let error_obj = Object::new(ctx.clone())?;
+                    error_obj.set("__isError", true)?;
+                    error_obj.set("message", format!("Invalid URL: {}", e))?;
+                    return Ok(error_obj);
+                }
+            };
+
+            let host = match parsed_url.host_str() {
+                Some(h) => h,
+                None => {
+                    let error_obj = Object::new(ctx.clone())?;
+                    error_obj.set("__isError", true)?;
+                    error_obj.set("message", "Invalid URL: no host")?;
+                    return Ok(error_obj);
+                }
+            };
+            let url = parsed_url.to_string();
+
+            let is_allowed = allowed_domains_vec
+                .iter()
+                .any(|domain| host == domain || host.ends_with(&format!(".{}", domain)));
+
+            // Only literal IPs are matched against CIDR entries; hostnames are not resolved
+            let host_ip = match parsed_url.host() {
+                Some(url::Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+                Some(url::Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+                _ => None,
+            };
+            let in_network =
+                host_ip.is_some_and(|ip| allowed_networks.iter().any(|network| network.contains(ip)));
+            let is_allowed = is_allowed || in_network;
+
+            let url_allowed = is_url_allowed(&allowed_urls, &parsed_url);
+            if !is_allowed && !url_allowed {
+                let error_obj = Object::new(ctx.clone())?;
+                error_obj.set("__isError", true)?;
+                if allowed_urls.iter().any(|entry| entry.host_str() == Some(host)) {
+                    error_obj.set("message", format!("URL '{}' is not in the allowlist", parsed_url))?;
+                } else {
+                    error_obj.set("message", format!("Domain '{}' is not in the allowlist", host))?;
+                }
+                return Ok(error_obj);
+            }
+
+            if !is_path_allowed(&path_rules, host, parsed_url.path()) {
+                let error_obj = Object::new(ctx.clone())?;
+                error_obj.set("__isError", true)?;
+                error_obj.set(
+                    "message",
+                    format!("Path '{}' on '{}' is not allowed", parsed_url.path(), host),
+                )?;
+                return Ok(error_obj);
+            }
+
+            // Block private IP ranges, even when a CIDR allowlist entry covers them
+            if host == "localhost" || host_ip.is_some_and(is_private_ip) {
+                let error_obj = Object::new(ctx.clone())?;
+                error_obj.set("__isError", true)?;
+                error_obj.set("message", "Requests to private IP ranges are not allowed")?;
+                return Ok(error_obj);
+            }
+
+            // Keep scripts to standard ports so admin ports on allowed hosts stay out of reach
+            let port = parsed_url.port_or_known_default();
+            if !url_allowed && !port.is_some_and(|port| allowed_ports.contains(&port)) {
+                let error_obj = Object::new(ctx.clone())?;
+                error_obj.set("__isError", true)?;
+                let port = port.map_or_else(|| "(none)".to_string(), |port| port.to_string());
+                error_obj.set("message", format!("Port {} on '{}' is not allowed", port, host))?;
+                return Ok(error_obj);
+            }
+
+            // Blocking calls don't yield to the interrupt handler, so refuse to start one
+            // once the execution budget is used up
+            let budget_remaining = metrics.deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            if budget_remaining == Some(Duration::ZERO) {
+                let error_obj = Object::new(ctx.clone())?;
+                error_obj.set("__isError", true)?;
+                error_obj.set("message", "Fetch rejected: time budget exhausted")?;
+                return Ok(error_obj);
+            }
+
+            // Parse options
+            let method = options.get::<_, Option<String>>("method")
+                .unwrap_or(None)
+                .unwrap_or_else(|| "GET".to_string())
+                .to_uppercase();
+
+            let body = match options.get::<_, Option<Value>>("body").unwrap_or(None) {
+                Some(value) if value.is_string() => value.get::<String>().ok(),
+                // Object bodies are form-encoded when the script asks for that content type
+                Some(value) if value.is_object() && is_form_urlencoded(&options) => {
+                    value.as_object().map(encode_form_body)
+                }
+                _ => None,
+            };
+
+            if !SUPPORTED_METHODS.contains(&method.as_str()) {
+                let error_obj = Object::new(ctx.clone())?;
+                error_obj.set("__isError", true)?;
+                error_obj.set("message", format!("Unsupported HTTP method: {}", method))?;
+                return Ok(error_obj);
+            }
+
+            // Throttle requests to any single host; only requests that passed every check
+            // count against the limit
+            if let Some(limit) = per_domain_limit {
+                let mut counts = domain_counts.lock().unwrap();
+                let count = counts.entry(host.to_string()).or_insert(0);
+                if *count >= limit {
+                    let error_obj = Object::new(ctx.clone())?;
+                    error_obj.set("__isError", true)?;
+                    error_obj.set(
+                        "message",
+                        format!("Fetch limit of {} requests to '{}' exceeded", limit, host),
+                    )?;
+                    return Ok(error_obj);
+                }
+                *count += 1;
+            }
+
+            // A signal from AbortSignal.timeout() bounds this request below the client timeout
+            let signal_timeout = options
+                .get::<_, Option<f64>>("timeoutMs")
+                .unwrap_or(None)
+                .filter(|ms| ms.is_finite())
+                .map(|ms| Duration::from_millis(ms.max(0.0) as u64));
+            // Under a time budget the request also may not outlive the execution
+            let bound_by_budget = |remaining: Option<Duration>| {
+                remaining.filter(|b| signal_timeout.is_none_or(|s| *b < s))
+            };
+            let mut budget_bound = bound_by_budget(budget_remaining);
+
+            // Script headers are sent as given; defaults fill in names the script didn't set
+            let mut headers = header_entries(&options);
+            for (name, value) in &default_headers {
+                if !headers.iter().any(|(existing, _)| existing.eq_ignore_ascii_case(name)) {
+                    headers.push((name.clone(), value.clone()));
+                }
+            }
+            // There is no cookie jar, and cookies set by hand are dropped too, so nothing is
+            // sent as ambient credentials whatever the `credentials` option says
+            headers.retain(|(name, _)| !name.eq_ignore_ascii_case("cookie"));
+
+            let request = HttpRequest {
+                method: method.clone(),
+                url: url.clone(),
+                headers,
+                body,
+                timeout: budget_bound.or(signal_timeout),
+                deadline: metrics.execution_deadline,
+                max_body_bytes: max_response_bytes,
+            };
+            let retry = retry_policy
+                .as_ref()
+                .filter(|_| IDEMPOTENT_METHODS.contains(&method.as_str()));
+            let mut attempt: u32 = 0;
+            let mut elapsed_ms: f64;
+            let response = loop {
+                // Earlier attempts and backoffs used up part of the budget, so each attempt
+                // gets only what is left of it
+                if attempt > 0 {
+                    let remaining =
+                        metrics.deadline.map(|d| d.saturating_duration_since(Instant::now()));
+                    budget_bound = bound_by_budget(remaining);
+                }
+                let request = HttpRequest {
+                    timeout: budget_bound.or(signal_timeout),
+                    ..request.clone()
+                };
+                if let Some(body_data) = &request.body {
+                    metrics.bytes_sent.fetch_add(body_data.len() as u64, Ordering::Relaxed);
+                }
+                let sent_at = Instant::now();
+                let response = client.send(request);
+                let send_duration = sent_at.elapsed();
+                native_time.add(send_duration);
+                elapsed_ms = send_duration.as_secs_f64() * 1000.0;
+                if let Some(sink) = &diagnostics {
+                    sink.lock().unwrap().fetches.push(FetchDiagnostic {
+                        method: method.clone(),
+                        url: redactor.redact(&url),
+                        status: response.as_ref().ok().map(|r| r.status),
+                        error: response.as_ref().err().map(|e| redactor.redact(&e.to_string())),
+                        duration_ms: elapsed_ms,
+                    });
+                }
+
+                let transient = match &response {
+                    Ok(r) => r.status >= 500,
+                    Err(e) => matches!(e, HttpError::Request(_)),
+                };
+                let Some(policy) = retry.filter(|p| transient && attempt < p.max_retries) else {
+                    break response;
+                };
+                let backoff = Duration::from_millis(
+                    policy.base_backoff_ms.saturating_mul(1u64 << attempt.min(16)),
+                );
+                // Sleeping blocks the interrupt handler, so never back off past the execution
+                // deadline or the fetch time budget; no retry could start anyway
+                let retry_at = Instant::now() + backoff;
+                if [metrics.deadline, metrics.execution_deadline]
+                    .into_iter()
+                    .flatten()
+                    .any(|deadline| retry_at >= deadline)
+                {
+                    break response;
+                }
+                std::thread::sleep(backoff);
+                native_time.add(backoff);
+                attempt += 1;
+            };
+            let response = match response {
+                Ok(r) => r,
+                Err(e) => {
+                    let error_obj = Object::new(ctx.clone())?;
+                    error_obj.set("__isError", true)?;
+                    let timed_out = matches!(e, HttpError::Timeout(_));
+                    let past_deadline =
+                        metrics.execution_deadline.is_some_and(|d| Instant::now() >= d);
+                    if timed_out && budget_bound.is_some() {
+                        error_obj.set("message", "Fetch aborted: time budget exhausted")?;
+                    } else if timed_out && past_deadline {
+                        error_obj.set("message", "Fetch aborted: execution timeout exceeded")?;
+                    } else if timed_out && signal_timeout.is_some() {
+                        error_obj.set("name", "TimeoutError")?;
+                        error_obj.set("message", "The operation was aborted due to timeout")?;
+                    } else {
+                        error_obj.set("message", e.to_string())?;
+                    }
+                    return Ok(error_obj);
+                }
+            };
+
+            let status = response.status;
+            let status_text = reqwest::StatusCode::from_u16(status)
+                .ok()
+                .and_then(|code| code.canonical_reason())
+                .unwrap_or("");
+
+            // Expose response headers as a plain map with lowercase names; repeated
+            // headers are joined with ", " as the Fetch spec does
+            let headers_obj = Object::new(ctx.clone())?;
+            let mut header_values: Vec<(String, String)> = Vec::new();
+            for (name, value) in response.headers {
+                match header_values.iter_mut().find(|(existing, _)| *existing == name) {
+                    Some((_, joined)) => {
+                        joined.push_str(", ");
+                        joined.push_str(&value);
+                    }
+                    None => header_values.push((name, value)),
+                }
+            }
+            for (name, value) in header_values {
+                headers_obj.set(name, value)?;
+            }
+
+            let response_bytes = response.body;
+            metrics.bytes_received.fetch_add(response_bytes.len() as u64, Ordering::Relaxed);
+            // Transports stop reading past the limit; this catches any that don't
+            if let Some(max_bytes) = max_response_bytes.filter(|max| response_bytes.len() > *max) {
+                let error_obj = Object::new(ctx.clone())?;
+                error_obj.set("__isError", true)?;
+                error_obj.set(
+                    "message",
+                    format!(
+                        "Response body exceeds maximum size of {} bytes ({} bytes)",
+                        max_bytes,
+                        response_bytes.len()
+                    ),
+                )?;
+                return Ok(error_obj);
+            }
+            let response_text = String::from_utf8_lossy(&response_bytes).into_owned();
+
+            // Create response object
+            let response_obj = Object::new(ctx.clone())?;
+            response_obj.set("status", status)?;
+            response_obj.set("ok", (200..300).contains(&status))?;
+            response_obj.set("statusText", status_text)?;
+            response_obj.set("headers", headers_obj)?;
+            response_obj.set("_elapsedMs", elapsed_ms)?;
+            response_obj.set("_bodyText", response_text)?;
+            response_obj.set("_bodyBytes", ArrayBuffer::new_copy(ctx.clone(), &response_bytes[..])?)?;
+
+            Ok(response_obj)
+        },
+    )?;
+
+    // Set the synchronous implementation as a hidden global
+    ctx.globals().set("__syncFetch", sync_fetch)?;
+
+    // Wrap it in JavaScript to provide Promise-based API
+    let fetch_wrapper_code = r#"
+(function(throwOnHttpError, parseBody, encodeBase64) {
+    const BODY_CHUNK_SIZE = 16384;
+
+    // A ReadableStream-like view of the buffered body: getReader().read() and
+    // `for await` both yield it as Uint8Array chunks
+    function bodyStream(bytes) {
+        return {
+            getReader() {
+                let offset = 0;
+                return {
+                    read() {
+                        if (offset >= bytes.byteLength) {
+                            return Promise.resolve({ done: true, value: undefined });
+                        }
+                        const end = Math.min(offset + BODY_CHUNK_SIZE, bytes.byteLength);
+                        const value = new Uint8Array(bytes.slice(offset, end));
+                        offset = end;
+                        return Promise.resolve({ done: false, value });
+                    },
+                    cancel() {
+                        offset = bytes.byteLength;
+                        return Promise.resolve();
+                    },
+                    releaseLock() {},
+                };
+            },
+            [Symbol.asyncIterator]() {
+                const reader = this.getReader();
+                return { next: () => reader.read() };
+            },
+        };
+    }
+
+    // Add body, text(), json(), data(), arrayBuffer(), blob() and clone() over the buffered
+    // body
+    function withBodyMethods(response) {
+        response.body = bodyStream(response._bodyBytes);
+
+        response.text = function() {
+            return Promise.resolve(this._bodyText);
+        };
+
+        // Parse the body with a host-side parser, e.g. parse("csv")
+        response.parse = function(format) {
+            return new Promise((resolve) => resolve(parseBody(String(format), this._bodyText)));
+        };
+
+        response.arrayBuffer = function() {
+            return Promise.resolve(this._bodyBytes.slice(0));
+        };
+
+        // A Blob-like view: size, type and arrayBuffer()/text(), without slicing or streams
+        response.blob = function() {
+            const bytes = this._bodyBytes;
+            const text = this._bodyText;
+            const contentType = (this.headers && this.headers['content-type']) || '';
+            return Promise.resolve({
+                size: bytes.byteLength,
+                type: contentType.split(';')[0].trim().toLowerCase(),
+                arrayBuffer: () => Promise.resolve(bytes.slice(0)),
+                text: () => Promise.resolve(text),
+            });
+        };
+
+        response.json = function() {
+            return new Promise((resolve, reject) => {
+                try {
+                    resolve(JSON.parse(this._bodyText));
+                } catch (e) {
+                    reject(e);
+                }
+            });
+        };
+
+        // The body in the shape its Content-Type suggests: parsed JSON for JSON types, a
+        // string for text/*, and base64 for anything else
+        response.data = function() {
+            const contentType = (this.headers && this.headers['content-type']) || '';
+            const mimeType = contentType.split(';')[0].trim().toLowerCase();
+            if (mimeType === 'application/json' || mimeType.endsWith('+json')) {
+                return this.json();
+            }
+            if (mimeType.startsWith('text/')) {
+                return this.text();
+            }
+            return Promise.resolve(encodeBase64(this._bodyBytes));
+        };
+
+        response.clone = function() {
+            return withBodyMethods({
+                status: this.status,
+                ok: this.ok,
+                statusText: this.statusText,
+                headers: Object.assign({}, this.headers),
+                elapsedMs: this.elapsedMs,
+                _bodyText: this._bodyText,
+                _bodyBytes: this._bodyBytes,
+            });
+        };
+
+        return response;
+    }
+
+    // Standard fetch options with no meaning outside a browser
+    const IGNORED_FETCH_OPTIONS = [
+        'cache', 'credentials', 'integrity', 'keepalive', 'mode', 'priority', 'redirect',
+        'referrer', 'referrerPolicy',
+    ];
+
+    // Turn a Headers-like object (anything with forEach) or entry list into [name, value]
+    // string pairs, keeping order and repeated names; plain objects pass through
+    function normalizeHeaders(headers) {
+        if (headers === null || typeof headers !== 'object') {
+            return headers;
+        }
+        const entries = [];
+        if (Array.isArray(headers)) {
+            headers.forEach((pair) => entries.push([pair[0], pair[1]]));
+        } else if (typeof headers.forEach === 'function') {
+            headers.forEach((value, key) => entries.push([key, value]));
+        } else {
+            return headers;
+        }
+        return entries.map(([key, value]) => [String(key), String(value)]);
+    }
+
+    return function fetch(resource, options) {
+        return new Promise((resolve, reject) => {
+            try {
+                // Accept a Request-like object in place of the url string
+                let url = resource;
+                let opts = Object.assign({}, options || {});
+                if (resource !== null && typeof resource === 'object' && typeof resource.url === 'string') {
+                    url = resource.url;
+                    opts = Object.assign(
+                        { method: resource.method, headers: resource.headers, body: resource.body },
+                        opts
+                    );
+                }
+                opts.headers = normalizeHeaders(opts.headers);
+
+                // Browser-only options are accepted and ignored; with credentials: "include"
+                // cookies are still never sent
+                for (const name of IGNORED_FETCH_OPTIONS) {
+                    delete opts[name];
+                }
+
+                // Translate an AbortSignal into the native per-request timeout
+                if (opts.signal) {
+                    if (opts.signal.aborted) {
+                        const error = new Error("The operation was aborted");
+                        error.name = "AbortError";
+                        reject(error);
+                        return;
+                    }
+                    if (typeof opts.signal.timeoutMs === 'number') {
+                        opts.timeoutMs = opts.signal.timeoutMs;
+                    }
+                    delete opts.signal;
+                }
+
+                const result = globalThis.__syncFetch(url, opts);
+
+                // Check if result is an error
+                if (result.__isError) {
+                    const error = new Error(result.message);
+                    if (result.name) {
+                        error.name = result.name;
+                    }
+                    reject(error);
+                    return;
+                }
+
+                // Opt-in ky/axios-style rejection of non-2xx responses
+                if (throwOnHttpError && !result.ok) {
+                    const statusText = result.statusText || '';
+                    const error = new Error(
+                        `Request failed with status ${result.status}${statusText ? ' ' + statusText : ''}`
+                    );
+                    error.name = "HTTPError";
+                    error.status = result.status;
+                    error.statusText = statusText;
+                    reject(error);
+                    return;
+                }
+
+                // Time spent waiting on the upstream, measured around the native send
+                result.elapsedMs = result._elapsedMs;
+                resolve(withBodyMethods(result));
+            } catch (error) {
+                reject(error);
+            }
+        });
+    };
+})
+"#;
+
+    let fetch_factory: Function = ctx.eval(fetch_wrapper_code)?;
+    let response_parsers = execution_options.response_parsers.clone();
+    let parse_body_fn = Function::new(
+        ctx.clone(),
+        move |ctx: Ctx<'js>, format: String, text: String| -> rquickjs::Result<Value<'js>> {
+            let parser = response_parsers.get(&format).ok_or_else(|| {
+                Exception::throw_type(&ctx, &format!("Unknown response format '{}'", format))
+            })?;
+            let value = parser(&text).map_err(|e| {
+                Exception::throw_syntax(&ctx, &format!("Invalid {}: {}", format, e))
+            })?;
+            ctx.json_parse(value.to_string())
+        },
+    )?;
+    let encode_base64_fn = Function::new(ctx.clone(), |buffer: ArrayBuffer<'js>| {
+        buffer.as_bytes().map(|bytes| BASE64_STANDARD.encode(bytes)).unwrap_or_default()
+    })?;
+    let fetch_fn: Function = fetch_factory.call((
+        execution_options.throw_on_http_error,
+        parse_body_fn,
+        encode_base64_fn,
+    ))?;
+    globals.set("fetch", fetch_fn.clone())?;
+
+    globals.set("AbortSignal", ctx.eval::<Object, _>(ABORT_SIGNAL_CODE)?)?;
+
+    // fetchAll goes through fetch, so every followed page passes the same checks
+    let fetch_all_factory: Function = ctx.eval(FETCH_ALL_CODE)?;
+    let fetch_all_fn: Function = fetch_all_factory.call((fetch_fn.clone(),))?;
+    globals.set("fetchAll", fetch_all_fn)?;
+
+    // fetchJson likewise goes through fetch and only swaps failures for the default
+    let fetch_json_factory: Function = ctx.eval(FETCH_JSON_CODE)?;
+    let fetch_json_fn: Function = fetch_json_factory.call((fetch_fn,))?;
+    globals.set("fetchJson", fetch_json_fn)?;
+
+    Ok(())
+}
+
+// Only the timeout() form is supported: the native fetch is blocking, so a signal
+// can't be aborted mid-request, but its deadline becomes the request timeout
+const ABORT_SIGNAL_CODE: &str = r#"
+Object.freeze({
+    timeout(ms) {
+        return Object.freeze({ aborted: false, reason: undefined, timeoutMs: Number(ms) });
+    },
+})
+"#;
+
+// Resolves to the parsed body of a 2xx response, or to defaultValue when the fetch is
+// rejected (allowlist, limits, network), the status is not 2xx or the body is not JSON
+const FETCH_JSON_CODE: &str = r#"
+(function(fetch) {
+    return async function fetchJson(url, options, defaultValue) {
+        try {
+            const response = await fetch(url, options);
+            return response.ok ? await response.json() : defaultValue;
+        } catch (e) {
+            return defaultValue;
+        }
+    };
+})
+"#;
+
+// Follows RFC 5988 `Link: <url>; rel="next"` headers, concatenating the JSON pages
+const FETCH_ALL_CODE: &str = r#"
+(function(fetch) {
+    const DEFAULT_MAX_PAGES = 10;
+
+    function nextLink(header) {
+        if (typeof header !== 'string') {
+            return null;
+        }
+        for (const part of header.split(',')) {
+            const match = part.match(/<([^>]*)>(.*)/);
+            const rel = match && match[2].match(/;\s*rel\s*=\s*"?([^";]*)"?/i);
+            if (rel && rel[1].toLowerCase().split(/\s+/).includes('next')) {
+                return match[1].trim();
+            }
+        }
+        return null;
+    }
+
+    function resolveUrl(base, link) {
+        if (/^[a-z][a-z0-9+.-]*:/i.test(link)) {
+            return link;
+        }
+        if (link.startsWith('/')) {
+            return base.match(/^[a-z][a-z0-9+.-]*:\/\/[^/?#]*/i)[0] + link;
+        }
+        return base.replace(/[?#].*$/, '').replace(/[^/]*$/, '') + link;
+    }
+
+    return async function fetchAll(url, options) {
+        const opts = Object.assign({}, options || {});
+        const maxPages = opts.maxPages === undefined ? DEFAULT_MAX_PAGES : opts.maxPages;
+        delete opts.maxPages;
+
+        let items = [];
+        let next = url;
+        for (let page = 1; next && page <= maxPages; page++) {
+            const response = await fetch(next, opts);
+            if (!response.ok) {
+                throw new Error(`fetchAll: page ${page} returned status ${response.status}`);
+            }
+            items = items.concat(await response.json());
+            const link = nextLink(response.headers && response.headers.link);
+            next = link ? resolveUrl(next, link) : null;
+        }
+        return items;
+    };
+})
+"#;
+
+/// Methods fetch accepts; anything else is rejected before reaching the transport
+const SUPPORTED_METHODS: &[&str] = &["GET", "POST", "PUT", "DELETE", "PATCH", "HEAD"];
+
+/// Methods safe to resend when `fetch_retry` is set
+const IDEMPOTENT_METHODS: &[&str] = &["GET", "HEAD", "PUT", "DELETE"];
+
+/// An allowlist entry for a range of IP addresses, e.g. "203.0.113.0/24"
+struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpNetwork {
+    fn parse(entry: &str) -> Result<Self> {
+        let invalid = |reason: &str| anyhow!("Invalid allowlist CIDR '{}': {}", entry, reason);
+        let (addr, prefix_len) =
+            entry.split_once('/').ok_or_else(|| invalid("missing prefix length"))?;
+        let addr: IpAddr = addr.parse().map_err(|_| invalid("invalid IP address"))?;
+        let prefix_len: u32 = prefix_len.parse().map_err(|_| invalid("invalid prefix length"))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix_len > max_len {
+            return Err(invalid("prefix length out of range"));
+        }
+        Ok(IpNetwork { addr, prefix_len })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether `ip` is loopback, private, link-local (including cloud metadata endpoints) or
+/// unspecified; IPv4-mapped IPv6 addresses are judged by their IPv4 form
+fn is_private_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(mapped) => is_private_ip(IpAddr::V4(mapped)),
+            None => {
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || first & 0xfe00 == 0xfc00 // unique local, fc00::/7
+                    || first & 0xffc0 == 0xfe80 // link-local, fe80::/10
+            }
+        },
+    }
+}
+
+/// Whether `url` matches a full-URL allowlist entry: same origin, and the same path or
+/// one below it. Query strings and fragments are ignored.
+fn is_url_allowed(entries: &[Url], url: &Url) -> bool {
+    entries.iter().any(|entry| {
+        let base = entry.path().trim_end_matches('/');
+        entry.origin() == url.origin()
+            && (url.path() == entry.path()
+                || url.path().strip_prefix(base).is_some_and(|rest| rest.starts_with('/')))
+    })
+}
+
+/// Check a request path against the rules of every domain entry matching the host
+fn is_path_allowed(path_rules: &HashMap<String, PathRules>, host: &str, path: &str) -> bool {
+    let matches = |prefix: &String| path.starts_with(prefix.trim_end_matches('*'));
+
+    path_rules
+        .iter()
+        .filter(|(domain, _)| host == domain.as_str() || host.ends_with(&format!(".{}", domain)))
+        .all(|(_, rules)| {
+            !rules.deny.iter().any(matches) && (rules.allow.is_empty() || rules.allow.iter().any(matches))
+        })
+}
+
+/// Request headers from the fetch options, given as a plain object or a list of
+/// [name, value] pairs, in order and with repeated names preserved
+fn header_entries(options: &Object) -> Vec<(String, String)> {
+    let Ok(Some(headers)) = options.get::<_, Option<Value>>("headers") else {
+        return Vec::new();
+    };
+    if let Some(pairs) = headers.as_array() {
+        pairs
+            .iter::<Vec<String>>()
+            .flatten()
+            .filter_map(|pair| match pair.as_slice() {
+                [name, value] => Some((name.clone(), value.clone())),
+                _ => None,
+            })
+            .collect()
+    } else if let Some(object) = headers.as_object() {
+        object.props::<String, String>().flatten().collect()
+    } else {
+        Vec::new()
+    }
+}
+
+/// Whether the fetch options declare an application/x-www-form-urlencoded body
+fn is_form_urlencoded(options: &Object) -> bool {
+    header_entries(options).into_iter().any(|(key, value)| {
+        key.eq_ignore_ascii_case("content-type")
+            && value.to_ascii_lowercase().starts_with("application/x-www-form-urlencoded")
+    })
+}
+
+/// Percent-encode an object's own properties as key=value pairs
+fn encode_form_body(body: &Object) -> String {
+    let mut serializer = url::form_urlencoded::Serializer::new(String::new());
+    for (key, value) in body.props::<String, Coerced<String>>().flatten() {
+        serializer.append_pair(&key, &value.0);
+    }
+    serializer.finish()
+}
+
+/// Build the headers applied to every fetch; request headers set by the script take precedence
+fn build_default_headers(headers: &HashMap<String, String>) -> Result<HeaderMap> {
+    let mut header_map = HeaderMap::new();
+    header_map.insert(USER_AGENT, HeaderValue::from_static(DEFAULT_USER_AGENT));
+
+    for (name, value) in headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| anyhow!("Invalid default header name '{}': {}", name, e))?;
+        let header_value = HeaderValue::from_str(value)
+            .map_err(|e| anyhow!("Invalid default header value for '{}': {}", name, e))?;
+        header_map.insert(header_name, header_value);
+    }
+
+    Ok(header_map)
+}
+
+/// Default nesting depth for console.dir, matching Node.js
+const DEFAULT_DIR_DEPTH: usize = 2;
+
+/// Pretty-print a value for console.dir; objects nested deeper than `depth` become placeholders
+fn inspect_value<'js>(
+    value: &Value<'js>,
+    depth: usize,
+    level: usize,
+    ancestors: &mut Vec<Object<'js>>,
+) -> String {
+    if let Some(s) = value.as_string() {
+        let s = s.to_string().unwrap_or_default();
+        return serde_json::to_string(&s).unwrap_or(s);
+    }
+    if value.is_function() {
+        return "[Function]".to_string();
+    }
+    let Some(obj) = value.as_object() else {
+        return value_to_string(value);
+    };
+
+    let is_array = value.is_array();
+    if ancestors.contains(obj) {
+        return "[Circular]".to_string();
+    }
+    if level > depth {
+        return if is_array { "[Array]" } else { "[Object]" }.to_string();
+    }
+
+    ancestors.push(obj.clone());
+    let indent = "  ".repeat(level + 1);
+    let mut entries = Vec::new();
+    if let Some(array) = value.as_array() {
+        for item in array.iter::<Value>().flatten() {
+            entries.push(format!("{}{}", indent, inspect_value(&item, depth, level + 1, ancestors)));
+        }
+    } else {
+        for (key, item) in obj.props::<String, Value>().flatten() {
+            entries.push(format!("{}{}: {}", indent, key, inspect_value(&item, depth, level + 1, ancestors)));
+        }
+    }
+    ancestors.pop();
+
+    let (open, close) = if is_array { ("[", "]") } else { ("{", "}") };
+    if entries.is_empty() {
+        format!("{}{}", open, close)
+    } else {
+        format!("{}\n{}\n{}{}", open, entries.join(",\n"), "  ".repeat(level), close)
+    }
+}
+
+/// Convert a QuickJS Value to a string representation
+fn value_to_string(value: &Value) -> String {
+    if let Some(s) = value.as_string() {
+        s.to_string().unwrap_or_else(|_| "[String]".to_string())
+    } else if value.is_null() {
+        "null".to_string()
+    } else if value.is_undefined() {
+        "undefined".to_string()
+    } else if let Some(b) = value.as_bool() {
+        b.to_string()
+    } else if let Some(n) = value.as_int() {
+        n.to_string()
+    } else if let Some(n) = value.as_float() {
+        n.to_string()
+    } else if value.as_big_int().is_some() {
+        format!("{}n", big_int_to_string(value))
+    } else if value.is_object() {
+        "[Object]".to_string()
+    } else if value.is_array() {
+        "[Array]".to_string()
+    } else if value.is_function() {
+        "[Function]".to_string()
+    } else {
+        "[Unknown]".to_string()
+    }
+}
+
+/// Render a BigInt as its decimal digits (without the "n" suffix)
+fn big_int_to_string(value: &Value) -> String {
+    value
+        .get::<Coerced<String>>()
+        .map(|s| s.0)
+        .unwrap_or_else(|_| "[BigInt]".to_string())
+}
+
+/// Caps on collection sizes and nesting when converting a result to JSON; `None` is
+/// unbounded
+#[derive(Debug, Clone, Copy, Default)]
+struct JsonLimits {
+    max_object_keys: Option<usize>,
+    max_array_length: Option<usize>,
+    max_depth: Option<usize>,
+    /// Nesting level of the value being converted, 0 for the result itself
+    depth: usize,
+}
+
+impl JsonLimits {
+    /// The limits for the members of a collection at the current depth
+    fn nested(self) -> Self {
+        JsonLimits { depth: self.depth + 1, ..self }
+    }
+
+    fn depth_exceeded(&self) -> bool {
+        self.max_depth.is_some_and(|max| self.depth >= max)
+    }
+}
+
+/// Stands in for objects and arrays nested deeper than `max_result_depth`
+const DEPTH_LIMIT_PLACEHOLDER: &str = "[depth limit reached]";
+
+/// Key under which a truncated object reports how many keys were dropped
+const TRUNCATED_KEY: &str = "[Truncated]";
+
+fn truncated_items_marker(dropped: usize) -> serde_json::Value {
+    serde_json::Value::String(format!("[Truncated: {} more items]", dropped))
+}
+
+/// Convert a QuickJS Value to serde_json::Value
+fn value_to_json<'js>(ctx: &Ctx<'js>, value: Value<'js>) -> Result<serde_json::Value> {
+    value_to_json_limited(ctx, value, JsonLimits::default())
+}
+
+/// Convert a QuickJS Value to serde_json::Value, truncating collections over `limits`
+fn value_to_json_limited<'js>(
+    ctx: &Ctx<'js>,
+    value: Value<'js>,
+    limits: JsonLimits,
+) -> Result<serde_json::Value> {
+    if value.is_null() || value.is_undefined() {
+        Ok(serde_json::Value::Null)
+    } else if let Some(b) = value.as_bool() {
+        Ok(serde_json::Value::Bool(b))
+    } else if let Some(i) = value.as_int() {
+        Ok(serde_json::Value::Number(i.into()))
+    } else if let Some(f) = value.as_float() {
+        serde_json::Number::from_f64(f)
+            .map(serde_json::Value::Number)
+            .ok_or_else(|| anyhow!("Invalid float value"))
+    } else if let Some(s) = value.as_string() {
+        Ok(serde_json::Value::String(
+            s.to_string().unwrap_or_else(|_| String::new()),
+        ))
+    } else if value.as_big_int().is_some() {
+        // JSON has no bigint type, so preserve every digit as a string
+        Ok(serde_json::Value::String(big_int_to_string(&value)))
+    } else if (value.is_array() || value.is_object()) && limits.depth_exceeded() {
+        Ok(serde_json::Value::String(DEPTH_LIMIT_PLACEHOLDER.to_string()))
+    } else if value.is_array() {
+        let arr = value.as_array().unwrap();
+        let kept = limits.max_array_length.map_or(arr.len(), |max| arr.len().min(max));
+        let mut result = Vec::new();
+        for i in 0..kept {
+            if let Ok(item) = arr.get::<Value>(i) {
+                result.push(value_to_json_limited(ctx, item, limits.nested())?);
+            }
+        }
+        if kept < arr.len() {
+            result.push(truncated_items_marker(arr.len() - kept));
+        }
+        Ok(serde_json::Value::Array(result))
+    } else if value.is_object() {
+        let obj = value.as_object().unwrap();
+
+        // Map and Set serialize to "{}" via JSON.stringify, so convert them explicitly
+        if let Some(entries) = collection_entries(ctx, obj, "Map")? {
+            let kept = limits.max_object_keys.map_or(entries.len(), |max| entries.len().min(max));
+            let mut map = serde_json::Map::new();
+            for entry in entries.iter::<rquickjs::Array>().take(kept) {
+                let entry = entry?;
+                let key: Value = entry.get(0)?;
+                let item = value_to_json_limited(ctx, entry.get(1)?, limits.nested())?;
+                map.insert(value_to_string(&key), item);
+            }
+            if kept < entries.len() {
+                map.insert(TRUNCATED_KEY.to_string(), (entries.len() - kept).into());
+            }
+            return Ok(serde_json::Value::Object(map));
+        }
+        if let Some(members) = collection_entries(ctx, obj, "Set")? {
+            let kept = limits.max_array_length.map_or(members.len(), |max| members.len().min(max));
+            let mut result = Vec::new();
+            for member in members.iter::<Value>().take(kept) {
+                result.push(value_to_json_limited(ctx, member?, limits.nested())?);
+            }
+            if kept < members.len() {
+                result.push(truncated_items_marker(members.len() - kept));
+            }
+            return Ok(serde_json::Value::Array(result));
+        }
+
+        // Try to use JSON.stringify for better conversion; its replacer converts nested
+        // Map/Set values and truncates collections over the limits before serialization
+        let json_obj: Object = ctx.globals().get("JSON")?;
+        let stringify: Function = json_obj.get("stringify")?;
+        let factory: Function = ctx.eval(RESULT_REPLACER_CODE)?;
+        // The replacer counts depth from the value it starts at
+        let remaining_depth = limits.max_depth.map(|max| max.saturating_sub(limits.depth));
+        let replacer: Function = factory.call((
+            limits.max_object_keys.map_or(f64::INFINITY, |max| max as f64),
+            limits.max_array_length.map_or(f64::INFINITY, |max| max as f64),
+            remaining_depth.map_or(f64::INFINITY, |max| max as f64),
+            TRUNCATED_KEY,
+            DEPTH_LIMIT_PLACEHOLDER,
+        ))?;
+
+        match stringify.call::<_, String>((value.clone(), replacer)) {
+            Ok(json_str) => {
+                serde_json::from_str(&json_str).map_err(|e| anyhow!("Failed to parse JSON: {}", e))
+            }
+            Err(_) => {
+                // Honour toJSON as JSON.stringify does before enumerating properties
+                if let Ok(to_json) = obj.get::<_, Function>("toJSON") {
+                    let replacement: Value = to_json.call((This(obj.clone()), ""))?;
+                    if replacement != value {
+                        return value_to_json_limited(ctx, replacement, limits);
+                    }
+                }
+
+                // Fallback to manual conversion
+                let props: Vec<(String, Value)> = obj.props::<String, Value>().flatten().collect();
+                let kept = limits.max_object_keys.map_or(props.len(), |max| props.len().min(max));
+                let mut map = serde_json::Map::new();
+                for (key, val) in props.iter().take(kept).cloned() {
+                    map.insert(key, value_to_json_limited(ctx, val, limits.nested())?);
+                }
+                if kept < props.len() {
+                    map.insert(TRUNCATED_KEY.to_string(), (props.len() - kept).into());
+                }
+                Ok(serde_json::Value::Object(map))
+            }
+        }
+    } else if value.is_function() {
+        Ok(serde_json::Value::String("[Function]".to_string()))
+    } else {
+        Ok(serde_json::Value::String("[Unknown type]".to_string()))
+    }
+}
+
+// Mirrors value_to_json_limited's Map/Set conversion and truncation for the
+// JSON.stringify path. Replacers aren't told how deep they are, so each returned
+// collection's depth is recorded for its members, which are visited with it as `this`.
+// Map keys are stringified the way value_to_string renders them.
+const RESULT_REPLACER_CODE: &str = r#"
+(function(maxKeys, maxItems, maxDepth, truncatedKey, depthPlaceholder) {
+    const depths = new WeakMap();
+    function mapKey(key) {
+        switch (typeof key) {
+            case 'string': return key;
+            case 'bigint': return `${key}n`;
+            case 'object': return key === null ? 'null' : '[Object]';
+            case 'function': return '[Object]';
+            default: return String(key);
+        }
+    }
+    function convert(value) {
+        if (value instanceof Map) {
+            const entries = Object.create(null);
+            value.forEach((item, key) => { entries[mapKey(key)] = item; });
+            return entries;
+        }
+        if (value instanceof Set) {
+            return Array.from(value);
+        }
+        return value;
+    }
+    function truncate(value) {
+        if (Array.isArray(value)) {
+            if (value.length <= maxItems) {
+                return value;
+            }
+            const kept = value.slice(0, maxItems);
+            kept.push(`[Truncated: ${value.length - maxItems} more items]`);
+            return kept;
+        }
+        const keys = Object.keys(value);
+        if (keys.length <= maxKeys) {
+            return value;
+        }
+        const kept = {};
+        keys.slice(0, maxKeys).forEach((name) => { kept[name] = value[name]; });
+        kept[truncatedKey] = keys.length - maxKeys;
+        return kept;
+    }
+    return function(key, value) {
+        if (value === null || typeof value !== 'object') {
+            return value;
+        }
+        const depth = depths.has(this) ? depths.get(this) + 1 : 0;
+        if (depth >= maxDepth) {
+            return depthPlaceholder;
+        }
+        const kept = truncate(convert(value));
+        depths.set(kept, depth);
+        return kept;
+    };
+})
+"#;
+
+/// Number.MAX_SAFE_INTEGER: beyond it, adjacent integers share one f64 representation
+const MAX_SAFE_INTEGER: f64 = 9_007_199_254_740_991.0;
+
+/// Rewrite (or reject) numbers beyond MAX_SAFE_INTEGER according to `policy`
+fn apply_number_policy(value: &mut serde_json::Value, policy: NumberPolicy) -> Result<()> {
+    match value {
+        serde_json::Value::Number(n) => {
+            let unsafe_digits = if let Some(i) = n.as_i64() {
+                (i as f64).abs() > MAX_SAFE_INTEGER
+            } else if n.is_u64() {
+                true
+            } else {
+                n.as_f64().is_some_and(|f| f.abs() > MAX_SAFE_INTEGER)
+            };
+            if unsafe_digits {
+                let digits = match n.as_f64() {
+                    Some(f) if !n.is_i64() && !n.is_u64() => js_integer_string(f),
+                    _ => n.to_string(),
+                };
+                match policy {
+                    NumberPolicy::Lossy => {}
+                    NumberPolicy::String => *value = serde_json::Value::String(digits),
+                    NumberPolicy::Error => {
+                        return Err(anyhow!(
+                            "Result contains a number outside the safe integer range: {}",
+                            digits
+                        ))
+                    }
+                }
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items {
+                apply_number_policy(item, policy)?;
+            }
+        }
+        serde_json::Value::Object(map) => {
+            for item in map.values_mut() {
+                apply_number_policy(item, policy)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Format an integral f64 the way JS String() does below 1e21: the shortest
+/// round-tripping digits, padded with zeros (2 ** 60 gives "1152921504606847000")
+fn js_integer_string(value: f64) -> String {
+    let scientific = format!("{:e}", value);
+    let Some((mantissa, exponent)) = scientific.split_once('e') else {
+        return scientific;
+    };
+    let Ok(exponent) = exponent.parse::<usize>() else {
+        return scientific;
+    };
+    let (sign, mantissa) = mantissa.strip_prefix('-').map_or(("", mantissa), |m| ("-", m));
+    let digits: String = mantissa.chars().filter(|c| c.is_ascii_digit()).collect();
+    if exponent >= 21 || digits.len() > exponent + 1 {
+        return scientific;
+    }
+    format!("{}{:0<width$}", sign, digits, width = exponent + 1)
+}
+
+/// Recursively sort object keys; otherwise keys keep JS property order
+fn sort_json_keys(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.sort_keys();
+            map.values_mut().for_each(sort_json_keys);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(sort_json_keys),
+        _ => {}
+    }
+}
+
+/// String(value) for values JSON.stringify cannot represent, or None if it can
+fn stringify_fallback<'js>(ctx: &Ctx<'js>, value: &Value<'js>) -> Result<Option<String>> {
+    let representable = if value.is_function() || value.is_symbol() {
+        false
+    } else if value.is_object() {
+        let json_obj: Object = ctx.globals().get("JSON")?;
         let stringify: Function = json_obj.get("stringify")?;
+        stringify.call::<_, String>((value.clone(),)).is_ok()
+    } else {
+        true
+    };
+    if representable {
+        return Ok(None);
+    }
+
+    let string_fn: Function = ctx.globals().get("String")?;
+    Ok(Some(string_fn.call((value.clone(),))?))
+}
+
+/// If `obj` is an instance of the named global collection (Map or Set),
+/// return its contents as an array via `Array.from`
+fn collection_entries<'js>(
+    ctx: &Ctx<'js>,
+    obj: &Object<'js>,
+    constructor: &str,
+) -> Result<Option<rquickjs::Array<'js>>> {
+    let class: Value = ctx.globals().get(constructor)?;
+    if !class.is_function() || !obj.is_instance_of(&class) {
+        return Ok(None);
+    }
+
+    let array_ctor: Object = ctx.globals().get("Array")?;
+    let from: Function = array_ctor.get("from")?;
+    Ok(Some(from.call((obj.clone(),))?))
+}
+
+/// Convert a caught JavaScript error into an execution error.
+/// Once the deadline has passed the error is the interrupt handler's abort, so report a timeout.
+fn js_failure<'js>(
+    ctx: &Ctx<'js>,
+    error: rquickjs::CaughtError<'js>,
+    stage: &str,
+    interrupted: Option<ExecutionError>,
+) -> anyhow::Error {
+    if let Some(reason) = interrupted {
+        return reason.into();
+    }
+    anyhow!("{}: {}", stage, format_js_error(ctx, error))
+}
+
+/// Format JavaScript error for better error messages
+fn format_js_error<'js>(_ctx: &Ctx<'js>, error: rquickjs::CaughtError<'js>) -> String {
+    match error {
+        rquickjs::CaughtError::Exception(e) => {
+            let message = match e.message() {
+                Some(message) if message == "stack overflow" => STACK_OVERFLOW_MESSAGE.to_string(),
+                Some(message) => message,
+                None => "Unknown error".to_string(),
+            };
+            let stack = e.stack().unwrap_or_default();
+
+            if !stack.is_empty() {
+                format!("{}\n{}", message, stack)
+            } else {
+                message
+            }
+        }
+        rquickjs::CaughtError::Error(e) => format!("Error: {}", e),
+        _ => "Unknown error".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_simple_execution() {
+        let code = "return 2 + 2";
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!(4));
+    }
+
+    #[test]
+    fn test_console_output() {
+        let code = r#"
+            console.log("Hello", "World");
+            return "done";
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!("done"));
+        assert!(result.console_output.contains(&"[log] Hello World".to_string()));
+    }
+
+    #[test]
+    fn test_json_return() {
+        let code = r#"
+            return {
+                "foo": "bar",
+                "number": 42,
+                "nested": {
+                    "array": [1, 2, 3]
+                }
+            };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "foo": "bar",
+                "number": 42,
+                "nested": {
+                    "array": [1, 2, 3]
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_infinite_loop_timeout() {
+        let code = "while(true) {}";
+        let result = execute_js(code, 100, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err_msg = result.unwrap_err().to_string();
+        // The interrupt handler should trigger and produce an error containing "interrupt"
+        assert!(err_msg.contains("timeout") || err_msg.contains("interrupt"));
+    }
+
+    #[test]
+    fn test_syntax_error() {
+        let code = "invalid javascript syntax {{{";
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_fetch_not_allowed_domain() {
+        let code = r#"
+            try {
+                const response = await fetch("https://evil.com/data");
+                return "should have rejected: " + JSON.stringify(response);
+            } catch (error) {
+                return error.message;
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["example.com"], None, &ExecutionOptions::default()).unwrap();
+        let response_str = result.value.as_str().unwrap();
+        assert!(response_str.contains("not in the allowlist") || response_str.contains("allowlist"));
+    }
+
+    #[test]
+    fn test_fetch_blocked_localhost() {
+        let code = r#"
+            try {
+                const response = await fetch("http://localhost:8080/secret");
+                return "should have rejected";
+            } catch (error) {
+                return error.message;
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["localhost"], None, &ExecutionOptions::default()).unwrap();
+        let response_str = result.value.as_str().unwrap();
+        assert!(response_str.contains("private IP"));
+    }
+
+    #[test]
+    fn test_runtime_isolation() {
+        // First execution: set a global variable
+        let code1 = r#"
+            globalThis.sharedState = "leaked value";
+            return "first execution";
+        "#;
+        let result1 = execute_js(code1, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result1.value, serde_json::json!("first execution"));
+
+        // Second execution: try to access the global variable from first execution
+        // This should fail if runtimes are properly isolated
+        let code2 = r#"
+            return {
+                hasSharedState: typeof globalThis.sharedState !== 'undefined',
+                sharedStateValue: globalThis.sharedState || null
+            };
+        "#;
+        let result2 = execute_js(code2, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let obj = result2.value.as_object().expect("Result should be an object");
+
+        // The shared state should NOT exist in the second execution
+        // This proves each execution gets a fresh runtime
+        assert_eq!(obj.get("hasSharedState").unwrap(), &serde_json::json!(false));
+        assert_eq!(obj.get("sharedStateValue").unwrap(), &serde_json::json!(null));
+    }
+
+    #[test]
+    fn test_input_parameter() {
+        let code = r#"
+            return {
+                receivedInput: input,
+                type: typeof input,
+                hasName: input && 'name' in input
+            };
+        "#;
+        let input = serde_json::json!({
+            "name": "test",
+            "value": 42
+        });
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], Some(input), &ExecutionOptions::default()).unwrap();
+
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("type").unwrap(), &serde_json::json!("object"));
+        assert_eq!(obj.get("hasName").unwrap(), &serde_json::json!(true));
+
+        let received = obj.get("receivedInput").unwrap().as_object().unwrap();
+        assert_eq!(received.get("name").unwrap(), &serde_json::json!("test"));
+        assert_eq!(received.get("value").unwrap(), &serde_json::json!(42));
+    }
+
+    #[test]
+    fn test_fetch_allowed_domain() {
+        // This test actually makes a real HTTP request to httpbin.org
+        // We verify that fetch returns a Promise and response has proper methods
+        let code = r#"
+            const response = await fetch("https://httpbin.org/get");
+            const data = await response.json();
+            return {
+                hasStatus: typeof response.status === 'number',
+                hasOk: typeof response.ok === 'boolean',
+                status: response.status,
+                hasJsonData: typeof data === 'object' && data !== null
+            };
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        // Verify fetch works - either success or valid HTTP error (not 0 which is connection error)
+        if let Ok(res) = result {
+            let obj = res.value.as_object().unwrap();
+            assert_eq!(obj.get("hasStatus").unwrap(), &serde_json::json!(true));
+            assert_eq!(obj.get("hasOk").unwrap(), &serde_json::json!(true));
+            // Status should be a real HTTP status, not 0 (which indicates our error handling)
+            let status = obj.get("status").unwrap().as_i64().unwrap();
+            assert!((200..600).contains(&status), "Expected valid HTTP status code, got {}", status);
+            // Should have successfully parsed JSON
+            assert_eq!(obj.get("hasJsonData").unwrap(), &serde_json::json!(true));
+        }
+    }
+
+    #[test]
+    fn test_fetch_post_with_body() {
+        let code = r#"
+            const response = await fetch("https://httpbin.org/post", {
+                method: "POST",
+                headers: {
+                    "Content-Type": "application/json"
+                },
+                body: JSON.stringify({ test: "data", number: 42 })
+            });
+            const data = await response.json();
+            return {
+                status: response.status,
+                ok: response.ok,
+                hasJsonField: data.json && typeof data.json === 'object'
+            };
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        if let Ok(res) = result {
+            let obj = res.value.as_object().unwrap();
+            let status = obj.get("status").unwrap().as_i64().unwrap();
+            assert!((200..300).contains(&status), "Expected 2xx status, got {}", status);
+            assert_eq!(obj.get("ok").unwrap(), &serde_json::json!(true));
+            assert_eq!(obj.get("hasJsonField").unwrap(), &serde_json::json!(true));
+        }
+    }
+
+    #[test]
+    fn test_fetch_put_method() {
+        let code = r#"
+            const response = await fetch("https://httpbin.org/put", {
+                method: "PUT",
+                body: "test data"
+            });
+            return {
+                status: response.status,
+                ok: response.ok
+            };
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        if let Ok(res) = result {
+            let obj = res.value.as_object().unwrap();
+            let status = obj.get("status").unwrap().as_i64().unwrap();
+            // Accept 2xx or 5xx (service errors are ok, we're testing method support)
+            assert!((200..600).contains(&status), "Expected valid HTTP status for PUT, got {}", status);
+        }
+    }
+
+    #[test]
+    fn test_fetch_delete_method() {
+        let code = r#"
+            const response = await fetch("https://httpbin.org/delete", {
+                method: "DELETE"
+            });
+            return {
+                status: response.status,
+                ok: response.ok
+            };
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        if let Ok(res) = result {
+            let obj = res.value.as_object().unwrap();
+            let status = obj.get("status").unwrap().as_i64().unwrap();
+            // Accept 2xx or 5xx (service errors are ok, we're testing method support)
+            assert!((200..600).contains(&status), "Expected valid HTTP status for DELETE, got {}", status);
+        }
+    }
+
+    // Tests for unhandled exceptions and runtime errors
+    #[test]
+    fn test_accessing_property_on_undefined() {
+        let code = r#"
+            const obj = undefined;
+            return obj.name;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("TypeError") || err.contains("undefined"));
+    }
+
+    #[test]
+    fn test_accessing_property_on_null() {
+        let code = r#"
+            const data = null;
+            return data.value;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("TypeError") || err.contains("null"));
+    }
+
+    #[test]
+    fn test_accessing_nested_undefined_properties() {
+        let code = r#"
+            const data = { user: { name: 'John' } };
+            return data.user.profile.nested.value;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("TypeError") || err.contains("undefined"));
+    }
+
+    #[test]
+    fn test_reference_error_undefined_variable() {
+        let code = r#"
+            return undefinedVariable;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("ReferenceError") || err.contains("not defined"));
+    }
+
+    #[test]
+    fn test_calling_non_function() {
+        let code = r#"
+            const notAFunction = "string";
+            return notAFunction();
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("TypeError") || err.contains("not a function"));
+    }
+
+    #[test]
+    fn test_network_call_to_disallowed_domain() {
+        let code = r#"
+            try {
+                const response = await fetch("https://blocked-domain.com/api");
+                return { error: "should have failed" };
+            } catch (error) {
+                return { caught: true, message: error.message };
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["allowed-domain.com"], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
+        let message = obj.get("message").unwrap().as_str().unwrap();
+        assert!(message.contains("not in the allowlist") || message.contains("allowlist"));
+    }
+
+    #[test]
+    fn test_json_parse_error() {
+        let code = r#"
+            try {
+                return JSON.parse("{ invalid json }");
+            } catch (error) {
+                return { caught: true, type: error.constructor.name };
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
+        assert_eq!(obj.get("type").unwrap(), &serde_json::json!("SyntaxError"));
+    }
+
+    #[test]
+    fn test_array_out_of_bounds_access() {
+        let code = r#"
+            const arr = [1, 2, 3];
+            return arr[100].id;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("TypeError") || err.contains("undefined"));
+    }
+
+    #[test]
+    fn test_promise_rejection() {
+        let code = r#"
+            await Promise.reject(new Error("Promise rejected"));
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("Promise rejected"));
+    }
+
+    #[test]
+    fn test_async_error_in_promise_chain() {
+        let code = r#"
+            const data = await Promise.resolve({ items: [] })
+                .then(d => d.items.find(i => i.id === 1))
+                .then(item => item.name);
+            return data;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("TypeError") || err.contains("undefined"));
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        // Division by zero in JavaScript returns Infinity, which cannot be represented in JSON
+        let code = r#"
+            return 1 / 0;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        // Infinity cannot be converted to JSON, so it should error
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("Invalid float") || err.contains("float"));
+    }
+
+    #[test]
+    fn test_throw_custom_error() {
+        let code = r#"
+            throw new Error("Custom error message");
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("Custom error message"));
+    }
+
+    #[test]
+    fn test_throw_string() {
+        let code = r#"
+            throw "String error";
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        // String throws may have different formatting, just verify we got an error
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_network_invalid_url() {
+        let code = r#"
+            try {
+                const response = await fetch("not-a-valid-url");
+                return { error: "should have failed" };
+            } catch (error) {
+                return { caught: true, message: error.message };
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["example.com"], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
+        let message = obj.get("message").unwrap().as_str().unwrap();
+        assert!(message.contains("Invalid URL") || message.contains("URL"));
+    }
+
+    #[test]
+    fn test_network_blocked_localhost() {
+        let code = r#"
+            try {
+                const response = await fetch("http://localhost:8080/secret");
+                return { error: "should have failed" };
+            } catch (error) {
+                return { caught: true, message: error.message };
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["localhost"], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
+        let message = obj.get("message").unwrap().as_str().unwrap();
+        assert!(message.contains("private IP"));
+    }
+
+    #[test]
+    fn test_network_blocked_private_ip() {
+        let code = r#"
+            try {
+                const response = await fetch("http://192.168.1.1/admin");
+                return { error: "should have failed" };
+            } catch (error) {
+                return { caught: true, message: error.message };
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["192.168.1.1"], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
+        let message = obj.get("message").unwrap().as_str().unwrap();
+        assert!(message.contains("private IP"));
+    }
+
+    #[test]
+    fn test_input_property_access_when_undefined() {
+        let code = r#"
+            // input is undefined, accessing property should fail
+            return input.someProperty;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        assert!(err.contains("TypeError") || err.contains("undefined"));
+    }
+
+    #[test]
+    fn test_malformed_json_response_simulation() {
+        // Simulate a case where JSON parsing fails on response
+        let code = r#"
+            try {
+                const malformedJson = "<html>Not JSON</html>";
+                return JSON.parse(malformedJson);
+            } catch (error) {
+                return { caught: true, type: error.constructor.name, message: error.message };
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
+        assert_eq!(obj.get("type").unwrap(), &serde_json::json!("SyntaxError"));
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let code = r#"
+            function recursive() {
+                return recursive();
+            }
+            return recursive();
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+        let err = result.unwrap_err().to_string();
+        // Should get stack overflow or max stack size exceeded
+        assert!(err.contains("stack") || err.contains("InternalError") || err.contains("recursion"));
+    }
+
+    #[test]
+    fn test_try_catch_handles_error_gracefully() {
+        let code = r#"
+            try {
+                const obj = undefined;
+                return obj.name;
+            } catch (error) {
+                return { caught: true, errorType: error.constructor.name, message: error.message };
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
+        assert_eq!(obj.get("errorType").unwrap(), &serde_json::json!("TypeError"));
+    }
+
+    #[test]
+    fn test_multiple_errors_first_one_caught() {
+        let code = r#"
+            try {
+                throw new Error("First error");
+                throw new Error("Second error");
+            } catch (error) {
+                return { message: error.message };
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("message").unwrap(), &serde_json::json!("First error"));
+    }
+
+    #[test]
+    fn test_user_return_skip_reason() {
+        let code = r#"
+            return {
+                skip_reason: "user_cancelled",
+                additional_data: "some info"
+            };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("skip_reason").unwrap(), &serde_json::json!("user_cancelled"));
+        assert_eq!(obj.get("additional_data").unwrap(), &serde_json::json!("some info"));
+    }
+
+    #[test]
+    fn test_user_return_error_reason() {
+        let code = r#"
+            return {
+                error_reason: "validation_failed",
+                details: "Missing required field"
+            };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("error_reason").unwrap(), &serde_json::json!("validation_failed"));
+        assert_eq!(obj.get("details").unwrap(), &serde_json::json!("Missing required field"));
+    }
+
+    #[test]
+    fn test_user_return_both_reasons() {
+        let code = r#"
+            return {
+                skip_reason: "user_skip",
+                error_reason: "also_error",
+                data: 42
+            };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert_eq!(obj.get("skip_reason").unwrap(), &serde_json::json!("user_skip"));
+        assert_eq!(obj.get("error_reason").unwrap(), &serde_json::json!("also_error"));
+        assert_eq!(obj.get("data").unwrap(), &serde_json::json!(42));
+    }
+
+    #[test]
+    fn test_user_return_nothing() {
+        let code = r#"
+            // Don't return anything
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        // Should return null/undefined
+        assert!(result.value.is_null());
+    }
+
+    #[test]
+    fn test_user_return_empty_object() {
+        let code = r#"
+            return {};
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let obj = result.value.as_object().unwrap();
+        assert!(obj.is_empty());
+    }
+
+    #[test]
+    fn test_return_map() {
+        let code = r#"
+            const m = new Map();
+            m.set("a", 1);
+            m.set("b", { nested: true });
+            return m;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "a": 1, "b": { "nested": true } }));
+    }
+
+    #[test]
+    fn test_return_set() {
+        let code = r#"
+            return new Set([1, "two", 3]);
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!([1, "two", 3]));
+    }
+
+    #[test]
+    fn test_return_nested_map_and_set() {
+        let code = r#"
+            return {
+                m: new Map([[1, 2], ["k", new Set(["x"])]]),
+                a: { b: { s: new Set([1, 2]) } },
+            };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({ "m": { "1": 2, "k": ["x"] }, "a": { "b": { "s": [1, 2] } } })
+        );
+
+        // Nested collections still count toward the truncation limits
+        let options = ExecutionOptions {
+            max_object_keys: Some(1),
+            max_array_length: Some(1),
+            ..Default::default()
+        };
+        let code = r#"
+            return { m: new Map([["a", 1], ["b", 2]]) };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "m": { "a": 1, TRUNCATED_KEY: 1 } }));
+        let code = r#"
+            return { s: new Set([1, 2, 3]) };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "s": [1, truncated_items_marker(2)] }));
+    }
+
+    #[test]
+    fn test_return_bigint() {
+        let code = r#"
+            return 2n ** 64n;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!("18446744073709551616"));
+    }
+
+    #[test]
+    fn test_console_log_bigint() {
+        let code = r#"
+            console.log("big", 9007199254740993n);
+            return null;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert!(result.console_output.contains(&"[log] big 9007199254740993n".to_string()));
+    }
+
+    #[test]
+    fn test_require_return_rejects_missing_return() {
+        let code = r#"
+            // Don't return anything
+        "#;
+        let options = ExecutionOptions {
+            require_return: true,
+            ..Default::default()
+        };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("main function returned no value"));
+
+        // An explicit null is still a value
+        let result = execute_js("return null;", 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert!(result.value.is_null());
+    }
+
+    #[test]
+    fn test_input_is_frozen() {
+        let code = r#"
+            let threw = 0;
+            try { input.name = "changed"; } catch (error) { threw++; }
+            try { input.nested.value = 0; } catch (error) { threw++; }
+            try { input.extra = true; } catch (error) { threw++; }
+            return { threw, input };
+        "#;
+        let input = serde_json::json!({
+            "name": "test",
+            "nested": { "value": 42 }
+        });
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], Some(input), &ExecutionOptions::default()).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "threw": 3,
+                "input": { "name": "test", "nested": { "value": 42 } }
+            })
+        );
+    }
+
+    #[test]
+    fn test_env_injection() {
+        let code = r#"
+            let mutated = true;
+            try { env.REGION = "eu-west-1"; } catch (error) { mutated = false; }
+            return {
+                region: env.REGION,
+                mutated,
+                hasProcess: typeof process !== 'undefined'
+            };
+        "#;
+        let options = ExecutionOptions {
+            env: HashMap::from([("REGION".to_string(), "us-east-1".to_string())]),
+            ..Default::default()
+        };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({ "region": "us-east-1", "mutated": false, "hasProcess": false })
+        );
+    }
+
+    #[test]
+    fn test_performance_now() {
+        let code = r#"
+            const before = performance.now();
+            let sum = 0;
+            for (let i = 0; i < 100000; i++) { sum += i; }
+            const after = performance.now();
+            return {
+                nonNegative: before >= 0,
+                increasing: after > before,
+                timeOrigin: performance.timeOrigin
+            };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({ "nonNegative": true, "increasing": true, "timeOrigin": 0 })
+        );
+    }
+
+    #[test]
+    fn test_console_time() {
+        let code = r#"
+            console.time("loop");
+            let sum = 0;
+            for (let i = 0; i < 10000; i++) { sum += i; }
+            console.timeEnd("loop");
+            console.timeEnd("missing");
+            return sum;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        let timing = result
+            .console_output
+            .iter()
+            .find(|line| line.starts_with("[log] loop: "))
+            .expect("timeEnd should record a duration");
+        let ms: f64 = timing["[log] loop: ".len()..].trim_end_matches("ms").parse().unwrap();
+        assert!(ms >= 0.0);
+        assert!(result
+            .console_output
+            .contains(&"[warn] Timer 'missing' does not exist".to_string()));
+    }
+
+    #[test]
+    fn test_console_assert() {
+        let code = r#"
+            console.assert(false, "nope");
+            console.assert(true, "x");
+            console.assert(0);
+            return "still running";
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!("still running"));
+        assert_eq!(
+            result.console_output,
+            vec![
+                "[error] Assertion failed: nope".to_string(),
+                "[error] Assertion failed".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_gc_frees_unreachable_memory() {
+        let runtime = Runtime::new().unwrap();
+        let context = Context::full(&runtime).unwrap();
+        context.with(|ctx| {
+            setup_gc(&ctx, &ctx.globals()).unwrap();
+            // Cyclic garbage is only reclaimed by the cycle collector, not refcounting
+            ctx.eval::<(), _>(
+                r#"
+                let big = [];
+                for (let i = 0; i < 20000; i++) { const a = {}; const b = { a }; a.b = b; big.push(a); }
+                big = null;
+                "#,
+            )
+            .unwrap();
+        });
+        let before = runtime.memory_usage().memory_used_size;
+        context.with(|ctx| ctx.eval::<(), _>("gc();").unwrap());
+        let after = runtime.memory_usage().memory_used_size;
+        assert!(after < before, "expected gc() to free memory ({} -> {})", before, after);
+    }
+
+    #[test]
+    fn test_gc_disabled() {
+        let options = ExecutionOptions {
+            allow_gc: false,
+            ..Default::default()
+        };
+        let result = execute_js("return typeof gc;", 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!("undefined"));
+    }
+
+    #[test]
+    fn test_stack_size_option() {
+        const DEPTH: u32 = 450;
+        let code = r#"
+            function depth(n) { return n === 0 ? 0 : 1 + depth(n - 1); }
+            return depth(input);
+        "#;
+        let run = |stack_size_bytes: usize| {
+            let options = ExecutionOptions {
+                stack_size_bytes,
+                ..Default::default()
+            };
+            execute_js(code, 5000, 10 * 1024 * 1024, &[], Some(serde_json::json!(DEPTH)), &options)
+                .map(|r| r.value)
+        };
+        assert!(run(256 * 1024).is_err());
+        assert_eq!(run(2 * 1024 * 1024).unwrap(), serde_json::json!(DEPTH));
+    }
+
+    #[test]
+    fn test_result_size_limit() {
+        let code = r#"
+            return Array.from({ length: 1000 }, (_, i) => "item-" + i);
+        "#;
+        let options = ExecutionOptions {
+            max_result_bytes: 1024,
+            ..Default::default()
+        };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options);
+        assert!(result.is_err());
+        assert!(result.unwrap_err().to_string().contains("result exceeds maximum size"));
+
+        // The same result fits within the default limit
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value.as_array().unwrap().len(), 1000);
+    }
+
+    #[test]
+    fn test_builtin_prototypes_frozen() {
+        let code = r#"
+            const attempts = {
+                stringProto: () => { String.prototype.pwn = () => "pwned"; },
+                numberProto: () => { Number.prototype.pwn = 1; },
+                functionProto: () => { Object.getPrototypeOf(function() {}).pwn = 1; },
+                staticMethod: () => { String.fromCharCode = () => "pwned"; },
+                globalBinding: () => { globalThis.JSON = null; },
+            };
+            const blocked = {};
+            for (const [name, attempt] of Object.entries(attempts)) {
+                try { attempt(); blocked[name] = false; } catch (error) { blocked[name] = true; }
+            }
+            globalThis.userGlobal = "still allowed";
+            return { blocked, pwn: typeof "".pwn, userGlobal };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "blocked": {
+                    "stringProto": true,
+                    "numberProto": true,
+                    "functionProto": true,
+                    "staticMethod": true,
+                    "globalBinding": true
+                },
+                "pwn": "undefined",
+                "userGlobal": "still allowed"
+            })
+        );
+    }
+
+    #[test]
+    fn test_restrict_reflection() {
+        let code = r#"
+            return { proxy: typeof Proxy, reflect: typeof Reflect };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "proxy": "function", "reflect": "object" }));
+
+        let options = ExecutionOptions {
+            restrict_reflection: true,
+            ..Default::default()
+        };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "proxy": "undefined", "reflect": "undefined" }));
+    }
+
+    #[test]
+    fn test_webassembly_disabled() {
+        let code = r#"
+            const messages = [];
+            try {
+                await WebAssembly.instantiate(new Uint8Array([0, 97, 115, 109]));
+            } catch (error) {
+                messages.push(error.message);
+            }
+            try {
+                new WebAssembly.Module(new Uint8Array(0));
+            } catch (error) {
+                messages.push(error.message);
+            }
+            return messages;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!([
+                "WebAssembly is disabled in this sandbox",
+                "WebAssembly is disabled in this sandbox"
+            ])
+        );
+    }
+
+    #[test]
+    fn test_console_streaming_callback() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let finished = Arc::new(AtomicBool::new(false));
+        let streamed: Arc<Mutex<Vec<(String, bool)>>> = Arc::new(Mutex::new(Vec::new()));
+
+        let finished_clone = finished.clone();
+        let streamed_clone = streamed.clone();
+        let options = ExecutionOptions {
+            on_console_line: Some(Arc::new(Mutex::new(move |line: &str| {
+                let during = !finished_clone.load(Ordering::SeqCst);
+                streamed_clone.lock().unwrap().push((line.to_string(), during));
+            }))),
+            ..Default::default()
+        };
+
+        let code = r#"
+            console.log("first");
+            console.log("second", 2);
+            return "done";
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        finished.store(true, Ordering::SeqCst);
+
+        let streamed = streamed.lock().unwrap().clone();
+        assert_eq!(
+            streamed,
+            vec![("[log] first".to_string(), true), ("[log] second 2".to_string(), true)]
+        );
+        // Lines are still buffered for the final result
+        assert_eq!(result.console_output, vec!["[log] first", "[log] second 2"]);
+    }
+
+    #[test]
+    fn test_allowed_globals() {
+        let code = r#"
+            return new Function("return 1")();
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default());
+        assert!(result.is_err());
+
+        let options = ExecutionOptions {
+            allowed_globals: vec!["Function".to_string()],
+            ..Default::default()
+        };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!(1));
+    }
+
+    #[test]
+    fn test_async_generator_main() {
+        let code = r#"
+            return (async function* () {
+                yield { progress: 50 };
+                yield { progress: 100 };
+                return { total: input.total };
+            })();
+        "#;
+        let input = serde_json::json!({ "total": 2 });
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], Some(input), &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "total": 2 }));
+        assert_eq!(
+            result.yielded,
+            vec![serde_json::json!({ "progress": 50 }), serde_json::json!({ "progress": 100 })]
+        );
+    }
+
+    #[test]
+    fn test_hard_deadline_bounds_blocking_fetch() {
+        // TEST-NET-3 is unroutable, so the blocking connect hangs (or fails fast
+        // without network); either way we must return near the deadline
+        let code = r#"
+            try {
+                await fetch("http://203.0.113.1/slow");
+                return "fetched";
+            } catch (error) {
+                return error.message;
+            }
+        "#;
+        let start = Instant::now();
+        let _ = execute_js(code, 200, 10 * 1024 * 1024, &["203.0.113.1"], None, &ExecutionOptions::default());
+        assert!(
+            start.elapsed() < Duration::from_millis(200 + WATCHDOG_GRACE_MS + 500),
+            "execution overran the deadline: {:?}",
+            start.elapsed()
+        );
+    }
+
+    #[test]
+    fn test_prelude_code() {
+        let options = ExecutionOptions {
+            prelude_code: Some(
+                r#"
+                globalThis.double = (x) => x * 2;
+                globalThis.helpers = { greet: (name) => "hi " + name };
+                globalThis.preludeSawEval = typeof eval !== 'undefined';
+                "#
+                .to_string(),
+            ),
+            ..Default::default()
+        };
+        let code = r#"
+            let reassigned = true;
+            try { double = null; } catch (error) { reassigned = false; }
+            let extended = true;
+            try { helpers.evil = 1; } catch (error) { extended = false; }
+            return { value: double(21), greeting: helpers.greet("bob"), reassigned, extended, preludeSawEval };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "value": 42,
+                "greeting": "hi bob",
+                "reassigned": false,
+                "extended": false,
+                "preludeSawEval": false
+            })
+        );
+    }
+
+    #[test]
+    fn test_host_function() {
+        let reverse: HostFunction = Arc::new(|value| match value {
+            serde_json::Value::String(s) => serde_json::Value::String(s.chars().rev().collect()),
+            other => other,
+        });
+        let options = ExecutionOptions {
+            host_functions: HashMap::from([("reverse".to_string(), reverse)]),
+            ..Default::default()
+        };
+        let code = r#"return reverse("sandbox");"#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!("xobdnas"));
+    }
+
+    #[test]
+    fn test_input_schema_validation() {
+        let options = ExecutionOptions {
+            input_schema: Some(serde_json::json!({
+                "type": "object",
+                "required": ["name"],
+                "properties": { "name": { "type": "string" } }
+            })),
+            ..Default::default()
+        };
+        let code = "console.log('should not run'); return input.name;";
+
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], Some(serde_json::json!({ "age": 3 })), &options);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("Input validation failed"), "unexpected error: {}", error);
+        assert!(error.contains("\"name\""), "error should name the field: {}", error);
+
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], Some(serde_json::json!({ "name": "Ada" })), &options).unwrap();
+        assert_eq!(result.value, serde_json::json!("Ada"));
+    }
+
+    #[test]
+    fn test_fetch_response_clone() {
+        let code = r#"
+            const response = await fetch("https://httpbin.org/get");
+            const copy = response.clone();
+            const data = await copy.json();
+            const text = await response.text();
+            return {
+                sameStatus: copy.status === response.status,
+                hasJsonData: typeof data === 'object' && data !== null,
+                textMatches: JSON.stringify(JSON.parse(text)) === JSON.stringify(data)
+            };
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        // Only assert when the network is reachable
+        if let Ok(res) = result {
+            assert_eq!(
+                res.value,
+                serde_json::json!({ "sameStatus": true, "hasJsonData": true, "textMatches": true })
+            );
+        }
+    }
 
-        match stringify.call::<_, String>((value.clone(),)) {
-            Ok(json_str) => {
-                serde_json::from_str(&json_str).map_err(|e| anyhow!("Failed to parse JSON: {}", e))
+    #[test]
+    fn test_fetch_request_object_form() {
+        // The method and url must be read from the Request-like argument
+        let code = r#"
+            const request = { url: "https://httpbin.org/anything", method: "FROB", headers: {} };
+            try {
+                await fetch(request);
+                return "no error";
+            } catch (e) {
+                return e.message;
             }
-            Err(_) => {
-                // Fallback to manual conversion
-                let mut map = serde_json::Map::new();
-                for prop in obj.props::<String, Value>() {
-                    if let Ok((key, val)) = prop {
-                        map.insert(key, value_to_json(ctx, val)?);
-                    }
-                }
-                Ok(serde_json::Value::Object(map))
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!("Unsupported HTTP method: FROB"));
+
+        let code = r#"
+            try {
+                await fetch({ url: "https://example.com/" });
+                return "no error";
+            } catch (e) {
+                return e.message;
             }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!("Domain 'example.com' is not in the allowlist"));
+    }
+
+    #[test]
+    fn test_fetch_headers_object_form() {
+        let code = r#"
+            class Headers {
+                constructor() { this.map = new Map(); }
+                set(key, value) { this.map.set(key.toLowerCase(), value); }
+                forEach(callback) { this.map.forEach((value, key) => callback(value, key, this)); }
+            }
+            const headers = new Headers();
+            headers.set("X-Sandbox-Test", "headers-object");
+            const response = await fetch("https://httpbin.org/headers", { headers });
+            const data = await response.json();
+            return data.headers["X-Sandbox-Test"];
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        // Only assert when the network is reachable
+        if let Ok(res) = result {
+            assert_eq!(res.value, serde_json::json!("headers-object"));
         }
-    } else if value.is_function() {
-        Ok(serde_json::Value::String("[Function]".to_string()))
-    } else {
-        Ok(serde_json::Value::String(format!("[Unknown type]")))
     }
-}
 
-/// Format JavaScript error for better error messages
-fn format_js_error<'js>(_ctx: &Ctx<'js>, error: rquickjs::CaughtError<'js>) -> String {
-    match error {
-        rquickjs::CaughtError::Exception(e) => {
-            let message = e.message().unwrap_or_else(|| "Unknown error".to_string());
-            let stack = e.stack().unwrap_or_else(|| String::new());
+    #[test]
+    fn test_fetch_gzip_response() {
+        let code = r#"
+            const response = await fetch("https://httpbin.org/gzip");
+            const data = await response.json();
+            return data.gzipped;
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        // Only assert when the network is reachable
+        if let Ok(res) = result {
+            assert_eq!(res.value, serde_json::json!(true));
+        }
+    }
 
-            if !stack.is_empty() {
-                format!("{}\n{}", message, stack)
-            } else {
-                message
+    #[test]
+    fn test_fetch_default_headers() {
+        let options = ExecutionOptions {
+            default_headers: HashMap::from([("X-Correlation-Id".to_string(), "abc-123".to_string())]),
+            ..Default::default()
+        };
+        let code = r#"
+            const response = await fetch("https://httpbin.org/headers");
+            const data = await response.json();
+            return {
+                correlationId: data.headers["X-Correlation-Id"],
+                userAgent: data.headers["User-Agent"]
+            };
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &options);
+        // Only assert when the network is reachable
+        if let Ok(res) = result {
+            assert_eq!(
+                res.value,
+                serde_json::json!({ "correlationId": "abc-123", "userAgent": DEFAULT_USER_AGENT })
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_default_header_rejected() {
+        let options = ExecutionOptions {
+            default_headers: HashMap::from([("bad header".to_string(), "x".to_string())]),
+            ..Default::default()
+        };
+        let result = execute_js("return 1;", 5000, 10 * 1024 * 1024, &[], None, &options);
+        assert!(result.unwrap_err().to_string().contains("Invalid default header name 'bad header'"));
+    }
+
+    #[test]
+    fn test_fetch_byte_metrics() {
+        let result = execute_js("return 1;", 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!((result.bytes_sent, result.bytes_received), (0, 0));
+
+        let code = r#"
+            await fetch("https://httpbin.org/post", { method: "POST", body: "x".repeat(512) });
+            const response = await fetch("https://httpbin.org/bytes/1024");
+            return response.status;
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        // Only assert when the network is reachable
+        if let Ok(res) = result {
+            assert!(res.bytes_sent >= 512, "bytes_sent was {}", res.bytes_sent);
+            assert!(res.bytes_received >= 1024, "bytes_received was {}", res.bytes_received);
+        }
+    }
+
+    #[test]
+    fn test_cancellation_flag() {
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let options = ExecutionOptions {
+            cancel_flag: Some(cancel_flag.clone()),
+            ..Default::default()
+        };
+
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            cancel_flag.store(true, Ordering::Relaxed);
+        });
+
+        let start = Instant::now();
+        let result = execute_js("while (true) {}", 10000, 10 * 1024 * 1024, &[], None, &options);
+        canceller.join().unwrap();
+
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(ExecutionError::Cancelled)));
+        assert_eq!(error.to_string(), "Execution cancelled");
+        assert!(start.elapsed() < Duration::from_secs(2), "cancellation took {:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_require_bundled_module() {
+        let options = ExecutionOptions {
+            modules: HashMap::from([(
+                "math".to_string(),
+                "exports.add = function(a, b) { return a + b; };".to_string(),
+            )]),
+            ..Default::default()
+        };
+        let code = r#"
+            const m = require("math");
+            let frozen = false;
+            try { m.add = null; } catch (e) { frozen = true; }
+            let missing = null;
+            try { require("fs"); } catch (e) { missing = e.message; }
+            return { sum: m.add(2, 3), frozen, missing, same: require("math") === m };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({ "sum": 5, "frozen": true, "missing": "Module 'fs' is not available", "same": true })
+        );
+    }
+
+    #[test]
+    fn test_console_dir_depth() {
+        let code = r#"
+            const nested = { a: { b: { c: 1 } }, list: [1, "two"] };
+            console.dir(nested, { depth: 1 });
+            console.dir(nested);
+            return null;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(
+            result.console_output,
+            vec![
+                "[dir] {\n  a: {\n    b: [Object]\n  },\n  list: [\n    1,\n    \"two\"\n  ]\n}",
+                "[dir] {\n  a: {\n    b: {\n      c: 1\n    }\n  },\n  list: [\n    1,\n    \"two\"\n  ]\n}",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_line_numbers_match_source() {
+        let code = "const value = await Promise.resolve(1);\nconst other = null;\nreturn other.missing + value;";
+        let error = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default())
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("eval_script:3:"), "expected line 3 in: {}", error);
+    }
+
+    #[test]
+    fn test_result_variable_convention() {
+        let options = ExecutionOptions {
+            result_variable: true,
+            ..Default::default()
+        };
+        let run = |code: &str| execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap().value;
+
+        assert_eq!(run("result = 42;"), serde_json::json!(42));
+        assert_eq!(run("const result = 'local';"), serde_json::json!("local"));
+        assert_eq!(run("result = 1;\nreturn 2;"), serde_json::json!(2));
+
+        // Without the option an unreturned result stays undefined (serialized as null)
+        let result = execute_js("globalThis.result = 42;", 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_max_call_depth() {
+        let code = r#"
+            function recurse(n) { return n === 0 ? 0 : 1 + recurse(n - 1); }
+            return recurse(1000);
+        "#;
+        let run = |max_call_depth| {
+            let options = ExecutionOptions {
+                max_call_depth: Some(max_call_depth),
+                stack_size_bytes: 8 * 1024 * 1024,
+                ..Default::default()
+            };
+            execute_js(code, 10000, 10 * 1024 * 1024, &[], None, &options)
+        };
+
+        let error = run(500).unwrap_err().to_string();
+        assert!(error.contains("maximum call depth exceeded"), "unexpected error: {}", error);
+        assert_eq!(run(2000).unwrap().value, serde_json::json!(1000));
+    }
+
+    #[test]
+    fn test_out_of_memory_is_catchable() {
+        // QuickJS raises a regular InternalError when the memory limit is hit, so
+        // user code can recover once the offending allocations are unreachable
+        let code = r#"
+            try {
+                const chunks = [];
+                while (true) { chunks.push(new Array(100000).fill(1)); }
+            } catch (e) {
+                // QuickJS throws null when it cannot even allocate the error object
+                return { handled: true, outOfMemory: e === null || e.message === "out of memory" };
+            }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "handled": true, "outOfMemory": true }));
+
+        // Uncaught, it surfaces as an execution error
+        let code = "const chunks = []; while (true) { chunks.push(new Array(100000).fill(1)); }";
+        let error = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap_err();
+        assert!(error.to_string().contains("out of memory"), "unexpected error: {}", error);
+    }
+
+    #[test]
+    fn test_fallback_stringify() {
+        let options = ExecutionOptions {
+            fallback_stringify: true,
+            ..Default::default()
+        };
+        let result = execute_js("return (a, b) => a + b;", 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert!(result.stringified);
+        let text = result.value.as_str().unwrap();
+        assert!(text.starts_with("function") || text.starts_with("("), "unexpected: {}", text);
+
+        let result = execute_js("return Symbol('tag');", 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!("Symbol(tag)"));
+
+        // Serializable values are untouched
+        let result = execute_js("return { a: 1 };", 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert!(!result.stringified);
+        assert_eq!(result.value, serde_json::json!({ "a": 1 }));
+    }
+
+    #[test]
+    fn test_to_json_respected() {
+        let code = r#"return { raw: 1, toJSON() { return { v: 1 }; } };"#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "v": 1 }));
+
+        // A BigInt in the replacement makes JSON.stringify throw, forcing the manual path
+        let code = r#"return { raw: 1, toJSON() { return { v: 1, big: 2n }; } };"#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "v": 1, "big": "2" }));
+    }
+
+    #[test]
+    fn test_per_domain_fetch_limit() {
+        let options = ExecutionOptions {
+            per_domain_fetch_limit: Some(2),
+            ..Default::default()
+        };
+        // .invalid never resolves, so only the limit decides which message we get
+        let code = r#"
+            const attempt = async (url, init) => {
+                try { await fetch(url, init); return "ok"; } catch (e) { return e.message; }
+            };
+            // A request rejected by validation does not use up the host's quota
+            await attempt("https://one.invalid/", { method: "BREW" });
+            const results = [];
+            for (let i = 0; i < 3; i++) {
+                results.push(await attempt("https://one.invalid/"));
+            }
+            results.push(await attempt("https://two.invalid/"));
+            return results;
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["one.invalid", "two.invalid"], None, &options).unwrap();
+        let messages: Vec<&str> = result.value.as_array().unwrap().iter().map(|v| v.as_str().unwrap()).collect();
+        let limit_message = "Fetch limit of 2 requests to 'one.invalid' exceeded";
+        assert_ne!(messages[0], limit_message);
+        assert_ne!(messages[1], limit_message);
+        assert_eq!(messages[2], limit_message);
+        assert!(!messages[3].contains("Fetch limit"), "unexpected: {}", messages[3]);
+    }
+
+    #[test]
+    fn test_fetch_form_urlencoded_body() {
+        let code = r#"
+            const response = await fetch("https://httpbin.org/post", {
+                method: "POST",
+                headers: { "Content-Type": "application/x-www-form-urlencoded" },
+                body: { a: "b c" }
+            });
+            const data = await response.json();
+            return data.form.a;
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        // Only assert when the network is reachable
+        if let Ok(res) = result {
+            assert_eq!(res.value, serde_json::json!("b c"));
+        }
+
+        let runtime = Runtime::new().unwrap();
+        let context = Context::full(&runtime).unwrap();
+        context.with(|ctx| {
+            let body: Object = ctx.eval("({ a: 'b c', n: 1, amp: 'x&y' })").unwrap();
+            assert_eq!(encode_form_body(&body), "a=b+c&n=1&amp=x%26y");
+        });
+    }
+
+    #[test]
+    fn test_object_key_order() {
+        let keys = |value: &serde_json::Value| -> Vec<String> { value.as_object().unwrap().keys().cloned().collect() };
+
+        // JSON.stringify path keeps insertion order
+        let code = "const o = {}; o.zebra = 1; o.apple = 2; o.mango = 3; return o;";
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(keys(&result.value), ["zebra", "apple", "mango"]);
+
+        // The manual fallback (forced by a BigInt) keeps the same order
+        let code = "const o = {}; o.zebra = 1n; o.apple = 2; o.mango = 3; return o;";
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(keys(&result.value), ["zebra", "apple", "mango"]);
+
+        let options = ExecutionOptions { sort_keys: true, ..Default::default() };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(keys(&result.value), ["apple", "mango", "zebra"]);
+    }
+
+    #[test]
+    fn test_fetch_all_follows_next_links() {
+        // Stand in for the network with a prelude-installed __syncFetch that serves two
+        // pages and defers everything else to the real implementation
+        let options = ExecutionOptions {
+            prelude_code: Some(
+                r#"
+                const realFetch = globalThis.__syncFetch;
+                const pages = {
+                    "https://api.example.com/items": { items: [1, 2], link: '</items?page=2>; rel="next"' },
+                    "https://api.example.com/items?page=2": { items: [3], link: '<https://evil.example.net/items>; rel="next"' },
+                };
+                globalThis.__syncFetch = function(url, opts) {
+                    const page = pages[url];
+                    if (!page) {
+                        return realFetch(url, opts);
+                    }
+                    return { status: 200, ok: true, headers: { link: page.link }, _bodyText: JSON.stringify(page.items) };
+                };
+                "#
+                .to_string(),
+            ),
+            ..Default::default()
+        };
+        let code = r#"
+            const firstTwo = await fetchAll("https://api.example.com/items", { maxPages: 2 });
+            let blocked = null;
+            try {
+                await fetchAll("https://api.example.com/items");
+            } catch (e) {
+                blocked = e.message;
+            }
+            return { firstTwo, blocked };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "firstTwo": [1, 2, 3],
+                "blocked": "Domain 'evil.example.net' is not in the allowlist"
+            })
+        );
+    }
+
+    #[test]
+    fn test_expression_mode() {
+        let options = ExecutionOptions {
+            expression_mode: true,
+            ..Default::default()
+        };
+        let run = |code: &str| execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap().value;
+
+        assert_eq!(run("40 + 2"), serde_json::json!(42));
+        assert_eq!(run("await Promise.resolve('async')"), serde_json::json!("async"));
+        // Statement lists still need an explicit return
+        assert_eq!(run("const x = 40;\nreturn x + 2;"), serde_json::json!(42));
+
+        let result = execute_js("40 + 2", 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::Value::Null);
+
+        // Code that closes the detection wrapper early is only parsed, never run
+        let runtime = Runtime::new().unwrap();
+        let context = Context::full(&runtime).unwrap();
+        context.with(|ctx| {
+            let code = "1); }); globalThis.leaked = true; (async function () { return (0";
+            wrap_main(&ctx, code, &options);
+            let leaked: Value = ctx.globals().get("leaked").unwrap();
+            assert!(leaked.is_undefined());
+        });
+    }
+
+    #[test]
+    fn test_fetch_abort_signal_timeout() {
+        let code = r#"
+            try {
+                await fetch("https://httpbin.org/delay/3", { signal: AbortSignal.timeout(100) });
+                return "no error";
+            } catch (e) {
+                return { name: e.name, message: e.message };
             }
+        "#;
+        let start = Instant::now();
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default()).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(2), "took {:?}", start.elapsed());
+
+        // Without network access the request fails before the signal's deadline
+        let message = result.value["message"].as_str().unwrap_or_default();
+        if !message.starts_with("HTTP request failed") {
+            assert_eq!(result.value["name"], serde_json::json!("TimeoutError"));
         }
-        rquickjs::CaughtError::Error(e) => format!("Error: {}", e),
-        _ => "Unknown error".to_string(),
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn test_fetch_elapsed_ms() {
+        let code = r#"
+            const response = await fetch("https://httpbin.org/get");
+            return { elapsedMs: response.elapsedMs, cloned: response.clone().elapsedMs };
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        // Only assert when the network is reachable
+        if let Ok(res) = result {
+            let elapsed = res.value["elapsedMs"].as_f64().expect("elapsedMs should be a number");
+            assert!(elapsed >= 0.0);
+            assert_eq!(res.value["cloned"], res.value["elapsedMs"]);
+        }
+    }
 
     #[test]
-    fn test_simple_execution() {
-        let code = "return 2 + 2";
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        assert_eq!(result.value, serde_json::json!(4));
+    fn test_number_policy() {
+        // The literal 9007199254740993 already rounds to ...992 in JS; the string
+        // policy keeps the digits JS itself would print for each number
+        let code = "return { big: 9007199254740993, pow: 2 ** 60, small: 42, top: -(2 ** 55) };";
+        let options = ExecutionOptions { number_policy: NumberPolicy::String, ..Default::default() };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "big": "9007199254740992",
+                "pow": "1152921504606847000",
+                "small": 42,
+                "top": "-36028797018963970"
+            })
+        );
+
+        let result = execute_js("return 2 ** 60;", 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!("1152921504606847000"));
+
+        let options = ExecutionOptions { number_policy: NumberPolicy::Error, ..Default::default() };
+        let error = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap_err();
+        assert!(error.to_string().contains("outside the safe integer range"));
+
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value["small"], serde_json::json!(42));
+        assert!(result.value["pow"].is_number());
     }
 
     #[test]
-    fn test_console_output() {
+    fn test_emit_streams_records() {
         let code = r#"
-            console.log("Hello", "World");
-            return "done";
+            emit({ id: 1 });
+            emit('two');
+            emit([3]);
+            return 'done';
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
         assert_eq!(result.value, serde_json::json!("done"));
-        assert!(result.console_output.contains(&"[log] Hello World".to_string()));
+        assert_eq!(
+            result.emitted,
+            vec![serde_json::json!({ "id": 1 }), serde_json::json!("two"), serde_json::json!([3])]
+        );
+        assert!(result.console_output.is_empty());
     }
 
     #[test]
-    fn test_json_return() {
+    fn test_path_rules() {
+        let mut path_rules = HashMap::new();
+        path_rules.insert(
+            "example.com".to_string(),
+            PathRules { allow: vec!["/public/*".to_string()], deny: vec!["/admin".to_string()] },
+        );
+        let options = ExecutionOptions { path_rules, ..Default::default() };
         let code = r#"
+            const attempt = async (url) => {
+                try { await fetch(url); return "ok"; } catch (e) { return e.message; }
+            };
             return {
-                "foo": "bar",
-                "number": 42,
-                "nested": {
-                    "array": [1, 2, 3]
-                }
+                admin: await attempt("https://example.com/admin/users"),
+                other: await attempt("https://example.com/private"),
+                public: await attempt("https://example.com/public/data.json"),
+            };
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["example.com"], None, &options).unwrap();
+        assert_eq!(result.value["admin"], "Path '/admin/users' on 'example.com' is not allowed");
+        assert_eq!(result.value["other"], "Path '/private' on 'example.com' is not allowed");
+        // Without network access the public request still fails, but not on policy
+        assert!(!result.value["public"].as_str().unwrap().contains("is not allowed"));
+    }
+
+    #[test]
+    fn test_response_blob() {
+        // Serve a small binary body without the network
+        let options = ExecutionOptions {
+            prelude_code: Some(
+                r#"
+                globalThis.__syncFetch = function(url, opts) {
+                    return {
+                        status: 200,
+                        ok: true,
+                        headers: { 'content-type': 'Application/Octet-Stream; x=1' },
+                        _bodyText: '',
+                        _bodyBytes: new Uint8Array([0, 1, 2, 254, 255]).buffer,
+                    };
+                };
+                "#
+                .to_string(),
+            ),
+            ..Default::default()
+        };
+        let code = r#"
+            const response = await fetch("https://files.example.com/data.bin");
+            const blob = await response.clone().blob();
+            const bytes = new Uint8Array(await blob.arrayBuffer());
+            const direct = await response.arrayBuffer();
+            return { size: blob.size, type: blob.type, last: bytes[4], direct: direct.byteLength };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["files.example.com"], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({ "size": 5, "type": "application/octet-stream", "last": 255, "direct": 5 })
+        );
+    }
+
+    #[test]
+    fn test_fetch_duplicate_headers() {
+        let code = r#"
+            const response = await fetch("https://httpbin.org/headers", {
+                headers: [["X-Sandbox-Dup", "first"], ["X-Sandbox-Dup", "second"]],
+            });
+            const data = await response.json();
+            return data.headers["X-Sandbox-Dup"];
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None, &ExecutionOptions::default());
+        // Only assert when the network is reachable
+        if let Ok(res) = result {
+            let value = res.value.as_str().unwrap_or_default();
+            assert!(value.contains("first") && value.contains("second"), "got {}", res.value);
+        }
+    }
+
+    #[test]
+    fn test_parse_json5() {
+        let code = r#"
+            let strict = null;
+            try { JSON.parse("{a:1,}"); } catch (e) { strict = e.name; }
+            let invalid = null;
+            try { parseJSON5("{a:"); } catch (e) { invalid = e.name; }
+            return { lenient: parseJSON5("{a:1,}"), strict, invalid };
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({ "lenient": { "a": 1 }, "strict": "SyntaxError", "invalid": "SyntaxError" })
+        );
+    }
+
+    #[test]
+    fn test_throw_on_http_error() {
+        // Serve a 404 without the network
+        let prelude = r#"
+            globalThis.__syncFetch = function(url, opts) {
+                return { status: 404, ok: false, statusText: 'Not Found', headers: {}, _bodyText: 'missing' };
             };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
+        let code = r#"
+            try {
+                const response = await fetch("https://api.example.com/missing");
+                return { status: response.status, text: await response.text() };
+            } catch (e) {
+                return { name: e.name, message: e.message, status: e.status };
+            }
+        "#;
+
+        let options = ExecutionOptions {
+            prelude_code: Some(prelude.to_string()),
+            throw_on_http_error: true,
+            ..Default::default()
+        };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
         assert_eq!(
             result.value,
             serde_json::json!({
-                "foo": "bar",
-                "number": 42,
-                "nested": {
-                    "array": [1, 2, 3]
-                }
+                "name": "HTTPError",
+                "message": "Request failed with status 404 Not Found",
+                "status": 404
             })
         );
+
+        let options = ExecutionOptions { prelude_code: Some(prelude.to_string()), ..Default::default() };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "status": 404, "text": "missing" }));
     }
 
-    #[test]
-    fn test_infinite_loop_timeout() {
-        let code = "while(true) {}";
-        let result = execute_js(code, 100, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err_msg = result.unwrap_err().to_string();
-        // The interrupt handler should trigger and produce an error containing "interrupt"
-        assert!(err_msg.contains("timeout") || err_msg.contains("interrupt"));
+    #[test]
+    fn test_fetch_base_url() {
+        let sink = DiagnosticsSink::default();
+        let options = ExecutionOptions {
+            base_url: Some("https://api.example.com/v1/".to_string()),
+            diagnostics: Some(sink.clone()),
+            ..Default::default()
+        };
+        let code = r#"
+            const attempt = async (url) => {
+                try { await fetch(url); return "ok"; } catch (e) { return e.message; }
+            };
+            return { escaped: await attempt("//evil.example.net/x"), user: await attempt("users/5") };
+        "#;
+        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(result.value["escaped"], "Domain 'evil.example.net' is not in the allowlist");
+
+        // The resolved request passed every check and was sent (or failed on the network)
+        let fetches = sink.lock().unwrap().fetches.clone();
+        assert_eq!(fetches.len(), 1);
+        assert_eq!(fetches[0].url, "https://api.example.com/v1/users/5");
+
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &ExecutionOptions::default());
+        let message = result.unwrap().value["user"].as_str().unwrap().to_string();
+        assert!(message.starts_with("Invalid URL"), "got {}", message);
     }
 
     #[test]
-    fn test_syntax_error() {
-        let code = "invalid javascript syntax {{{";
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
+    fn test_native_time_accounting() {
+        let slow: HostFunction = Arc::new(|value| {
+            std::thread::sleep(Duration::from_millis(50));
+            value
+        });
+        let options = ExecutionOptions {
+            host_functions: HashMap::from([("slow".to_string(), slow)]),
+            ..Default::default()
+        };
+        let result = execute_js("return slow(1) + slow(2);", 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!(3));
+        assert!(result.native_time_ms >= 100.0, "native_time_ms was {}", result.native_time_ms);
+        assert!(result.js_time_ms < result.native_time_ms, "js_time_ms was {}", result.js_time_ms);
     }
 
     #[test]
-    fn test_fetch_not_allowed_domain() {
+    fn test_fetch_time_budget() {
+        // A host function stands in for a slow upstream: it blocks past the whole budget
+        // without giving the interrupt handler a chance to run
+        let stall: HostFunction = Arc::new(|value| {
+            std::thread::sleep(Duration::from_millis(250));
+            value
+        });
+        let lines: Arc<Mutex<Vec<String>>> = Arc::new(Mutex::new(Vec::new()));
+        let lines_clone = lines.clone();
+        let options = ExecutionOptions {
+            host_functions: HashMap::from([("stall".to_string(), stall)]),
+            fetch_time_budget: true,
+            on_console_line: Some(Arc::new(Mutex::new(move |line: &str| {
+                lines_clone.lock().unwrap().push(line.to_string());
+            }))),
+            ..Default::default()
+        };
         let code = r#"
-            try {
-                const response = await fetch("https://evil.com/data");
-                return "should have rejected: " + JSON.stringify(response);
-            } catch (error) {
-                return error.message;
-            }
+            stall();
+            try { await fetch("https://api.example.com/next"); } catch (e) { console.log(e.message); }
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["example.com"], None).unwrap();
-        let response_str = result.value.as_str().unwrap();
-        assert!(response_str.contains("not in the allowlist") || response_str.contains("allowlist"));
+        let error = execute_js(code, 200, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap_err();
+        assert!(error.downcast_ref::<ExecutionError>().is_some(), "got {}", error);
+        assert_eq!(*lines.lock().unwrap(), vec!["[log] Fetch rejected: time budget exhausted"]);
     }
 
     #[test]
-    fn test_fetch_blocked_localhost() {
+    fn test_symbol_metadata() {
         let code = r#"
-            try {
-                const response = await fetch("http://localhost:8080/secret");
-                return "should have rejected";
-            } catch (error) {
-                return error.message;
-            }
+            const result = { total: 3 };
+            result[Symbol.for("__meta")] = { source: "cache", hits: 2 };
+            result[Symbol("private")] = "ignored";
+            return result;
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["localhost"], None).unwrap();
-        let response_str = result.value.as_str().unwrap();
-        assert!(response_str.contains("private IP"));
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "total": 3 }));
+        assert_eq!(
+            serde_json::Value::Object(result.metadata),
+            serde_json::json!({ "__meta": { "source": "cache", "hits": 2 } })
+        );
     }
 
     #[test]
-    fn test_runtime_isolation() {
-        // First execution: set a global variable
-        let code1 = r#"
-            globalThis.sharedState = "leaked value";
-            return "first execution";
-        "#;
-        let result1 = execute_js(code1, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        assert_eq!(result1.value, serde_json::json!("first execution"));
-
-        // Second execution: try to access the global variable from first execution
-        // This should fail if runtimes are properly isolated
-        let code2 = r#"
-            return {
-                hasSharedState: typeof globalThis.sharedState !== 'undefined',
-                sharedStateValue: globalThis.sharedState || null
-            };
+    fn test_structured_console() {
+        let code = r#"
+            console.log("start");
+            const end = performance.now() + 20;
+            while (performance.now() < end) {}
+            console.assert(false, "slow", 1);
+            console.log("done");
         "#;
-        let result2 = execute_js(code2, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        let obj = result2.value.as_object().expect("Result should be an object");
+        let options = ExecutionOptions { structured_console: true, ..Default::default() };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        let levels: Vec<&str> = result.structured_console.iter().map(|e| e.level.as_str()).collect();
+        assert_eq!(levels, vec!["log", "error", "log"]);
+        assert_eq!(result.structured_console[1].message, "Assertion failed: slow 1");
+        assert!(result.structured_console.windows(2).all(|w| w[0].elapsed_ms <= w[1].elapsed_ms));
+        assert!(result.structured_console[1].elapsed_ms - result.structured_console[0].elapsed_ms >= 20.0);
+        assert_eq!(result.console_output[0], "[log] start");
 
-        // The shared state should NOT exist in the second execution
-        // This proves each execution gets a fresh runtime
-        assert_eq!(obj.get("hasSharedState").unwrap(), &serde_json::json!(false));
-        assert_eq!(obj.get("sharedStateValue").unwrap(), &serde_json::json!(null));
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert!(result.structured_console.is_empty());
     }
 
     #[test]
-    fn test_input_parameter() {
+    fn test_stack_overflow_is_catchable_range_error() {
         let code = r#"
-            return {
-                receivedInput: input,
-                type: typeof input,
-                hasName: input && 'name' in input
-            };
+            function recursive(n) {
+                return recursive(n + 1) + 1;
+            }
+            try {
+                recursive(0);
+                return "unreachable";
+            } catch (e) {
+                const isRangeError = e instanceof RangeError;
+                return { handled: true, name: e.name, message: e.message, isRangeError };
+            }
         "#;
-        let input = serde_json::json!({
-            "name": "test",
-            "value": 42
-        });
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], Some(input)).unwrap();
+        // Without the option the catch still runs, with QuickJS's own error
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.value["handled"], serde_json::json!(true));
+        assert_eq!(result.value["name"], serde_json::json!("InternalError"));
 
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("type").unwrap(), &serde_json::json!("object"));
-        assert_eq!(obj.get("hasName").unwrap(), &serde_json::json!(true));
+        let options = ExecutionOptions { stack_overflow_range_error: true, ..Default::default() };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "handled": true,
+                "name": "RangeError",
+                "message": "Maximum call stack size exceeded",
+                "isRangeError": true
+            })
+        );
 
-        let received = obj.get("receivedInput").unwrap().as_object().unwrap();
-        assert_eq!(received.get("name").unwrap(), &serde_json::json!("test"));
-        assert_eq!(received.get("value").unwrap(), &serde_json::json!(42));
+        // Other InternalErrors keep their own type
+        let code = "try { throw new InternalError('boom'); }\n\
+                    catch (e) { return [e.name, e instanceof RangeError]; }";
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!(["InternalError", false]));
+
+        let code = "function recursive() { return recursive(); } return recursive();";
+        let error = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap_err();
+        assert!(error.to_string().contains("RangeError: Maximum call stack size exceeded"), "got {}", error);
     }
 
-    #[test]
-    fn test_fetch_allowed_domain() {
-        // This test actually makes a real HTTP request to httpbin.org
-        // We verify that fetch returns a Promise and response has proper methods
-        let code = r#"
-            const response = await fetch("https://httpbin.org/get");
-            const data = await response.json();
-            return {
-                hasStatus: typeof response.status === 'number',
-                hasOk: typeof response.ok === 'boolean',
-                status: response.status,
-                hasJsonData: typeof data === 'object' && data !== null
-            };
-        "#;
-        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None);
-        // Verify fetch works - either success or valid HTTP error (not 0 which is connection error)
-        if let Ok(res) = result {
-            let obj = res.value.as_object().unwrap();
-            assert_eq!(obj.get("hasStatus").unwrap(), &serde_json::json!(true));
-            assert_eq!(obj.get("hasOk").unwrap(), &serde_json::json!(true));
-            // Status should be a real HTTP status, not 0 (which indicates our error handling)
-            let status = obj.get("status").unwrap().as_i64().unwrap();
-            assert!(status >= 200 && status < 600, "Expected valid HTTP status code, got {}", status);
-            // Should have successfully parsed JSON
-            assert_eq!(obj.get("hasJsonData").unwrap(), &serde_json::json!(true));
+    /// Records every request and answers with a canned JSON echo
+    #[derive(Default)]
+    struct MockClient {
+        requests: Mutex<Vec<crate::http::HttpRequest>>,
+    }
+
+    impl crate::http::HttpClient for MockClient {
+        fn send(
+            &self,
+            request: crate::http::HttpRequest,
+        ) -> std::result::Result<crate::http::HttpResponse, crate::http::HttpError> {
+            let echo = serde_json::json!({ "method": request.method, "body": request.body });
+            self.requests.lock().unwrap().push(request);
+            Ok(crate::http::HttpResponse {
+                status: 201,
+                headers: vec![
+                    ("content-type".to_string(), "application/json".to_string()),
+                    ("set-cookie".to_string(), "a=1".to_string()),
+                    ("set-cookie".to_string(), "b=2".to_string()),
+                ],
+                body: echo.to_string().into_bytes(),
+            })
         }
     }
 
     #[test]
-    fn test_fetch_post_with_body() {
+    fn test_fetch_with_mock_client() {
+        let mock = Arc::new(MockClient::default());
+        let options = ExecutionOptions { http_client: Some(mock.clone()), ..Default::default() };
         let code = r#"
-            const response = await fetch("https://httpbin.org/post", {
-                method: "POST",
-                headers: {
-                    "Content-Type": "application/json"
-                },
-                body: JSON.stringify({ test: "data", number: 42 })
+            const response = await fetch("https://api.example.com/items", {
+                method: "post",
+                headers: { "Content-Type": "text/plain", "User-Agent": "custom" },
+                body: "hello",
             });
-            const data = await response.json();
+            const attempt = async (url, opts) => {
+                try { await fetch(url, opts); return "sent"; } catch (e) { return e.message; }
+            };
             return {
                 status: response.status,
-                ok: response.ok,
-                hasJsonField: data.json && typeof data.json === 'object'
+                statusText: response.statusText,
+                cookies: response.headers["set-cookie"],
+                echo: await response.json(),
+                blocked: await attempt("https://evil.example.net/"),
+                method: await attempt("https://api.example.com/", { method: "TRACE" }),
             };
         "#;
-        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None);
-        if let Ok(res) = result {
-            let obj = res.value.as_object().unwrap();
-            let status = obj.get("status").unwrap().as_i64().unwrap();
-            assert!(status >= 200 && status < 300, "Expected 2xx status, got {}", status);
-            assert_eq!(obj.get("ok").unwrap(), &serde_json::json!(true));
-            assert_eq!(obj.get("hasJsonField").unwrap(), &serde_json::json!(true));
-        }
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "status": 201,
+                "statusText": "Created",
+                "cookies": "a=1, b=2",
+                "echo": { "method": "POST", "body": "hello" },
+                "blocked": "Domain 'evil.example.net' is not in the allowlist",
+                "method": "Unsupported HTTP method: TRACE"
+            })
+        );
+        assert_eq!(result.bytes_sent, 5);
+
+        // Only the allowed request reached the transport, with the script's User-Agent
+        let requests = mock.requests.lock().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].url, "https://api.example.com/items");
+        let user_agents: Vec<&str> = requests[0]
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("user-agent"))
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(user_agents, vec!["custom"]);
     }
 
     #[test]
-    fn test_fetch_put_method() {
+    fn test_max_interrupt_ticks() {
+        let options = ExecutionOptions { max_interrupt_ticks: Some(50), ..Default::default() };
+        let start = Instant::now();
+        let error = execute_js("while (true) {}", 20000, 10 * 1024 * 1024, &[], None, &options).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(ExecutionError::WorkBudgetExceeded)), "got {}", error);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        let result = execute_js("return 1 + 1;", 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!(2));
+    }
+
+    #[test]
+    fn test_allowlist_full_url_entry() {
+        let mock = Arc::new(MockClient::default());
+        let options = ExecutionOptions { http_client: Some(mock.clone()), ..Default::default() };
         let code = r#"
-            const response = await fetch("https://httpbin.org/put", {
-                method: "PUT",
-                body: "test data"
-            });
-            return {
-                status: response.status,
-                ok: response.ok
+            const attempt = async (url) => {
+                try { await fetch(url); return "sent"; } catch (e) { return e.message; }
             };
+            return [
+                await attempt("https://api.example.com/v1/data?page=2"),
+                await attempt("https://api.example.com/v1/data/items"),
+                await attempt("https://api.example.com/v1/other"),
+                await attempt("https://api.example.com/v1/database"),
+                await attempt("http://api.example.com/v1/data"),
+            ];
         "#;
-        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None);
-        if let Ok(res) = result {
-            let obj = res.value.as_object().unwrap();
-            let status = obj.get("status").unwrap().as_i64().unwrap();
-            // Accept 2xx or 5xx (service errors are ok, we're testing method support)
-            assert!(status >= 200 && status < 600, "Expected valid HTTP status for PUT, got {}", status);
+        let allowlist = ["https://api.example.com/v1/data"];
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &allowlist, None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!([
+                "sent",
+                "sent",
+                "URL 'https://api.example.com/v1/other' is not in the allowlist",
+                "URL 'https://api.example.com/v1/database' is not in the allowlist",
+                "URL 'http://api.example.com/v1/data' is not in the allowlist"
+            ])
+        );
+        assert_eq!(mock.requests.lock().unwrap().len(), 2);
+    }
+
+    /// Fails with a 503 until `failures` requests have been made, then answers 200
+    struct FlakyClient {
+        failures: usize,
+        attempts: Mutex<Vec<String>>,
+    }
+
+    impl crate::http::HttpClient for FlakyClient {
+        fn send(
+            &self,
+            request: crate::http::HttpRequest,
+        ) -> std::result::Result<crate::http::HttpResponse, crate::http::HttpError> {
+            let mut attempts = self.attempts.lock().unwrap();
+            attempts.push(request.method);
+            let status = if attempts.len() > self.failures { 200 } else { 503 };
+            Ok(crate::http::HttpResponse { status, headers: Vec::new(), body: b"ok".to_vec() })
         }
     }
 
     #[test]
-    fn test_fetch_delete_method() {
+    fn test_fetch_retry_on_server_error() {
+        let flaky = Arc::new(FlakyClient { failures: 2, attempts: Mutex::new(Vec::new()) });
+        let options = ExecutionOptions {
+            http_client: Some(flaky.clone()),
+            fetch_retry: Some(RetryPolicy { max_retries: 3, base_backoff_ms: 1 }),
+            ..Default::default()
+        };
         let code = r#"
-            const response = await fetch("https://httpbin.org/delete", {
-                method: "DELETE"
-            });
-            return {
-                status: response.status,
-                ok: response.ok
-            };
+            const response = await fetch("https://api.example.com/items");
+            return { status: response.status, body: await response.text() };
         "#;
-        let result = execute_js(code, 10000, 10 * 1024 * 1024, &["httpbin.org"], None);
-        if let Ok(res) = result {
-            let obj = res.value.as_object().unwrap();
-            let status = obj.get("status").unwrap().as_i64().unwrap();
-            // Accept 2xx or 5xx (service errors are ok, we're testing method support)
-            assert!(status >= 200 && status < 600, "Expected valid HTTP status for DELETE, got {}", status);
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!({ "status": 200, "body": "ok" }));
+        assert_eq!(flaky.attempts.lock().unwrap().len(), 3);
+
+        // POST is sent once even though it fails
+        let flaky = Arc::new(FlakyClient { failures: 2, attempts: Mutex::new(Vec::new()) });
+        let options = ExecutionOptions { http_client: Some(flaky.clone()), ..options };
+        let code = r#"
+            const response = await fetch("https://api.example.com/items", { method: "POST", body: "x" });
+            return response.status;
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!(503));
+        assert_eq!(*flaky.attempts.lock().unwrap(), vec!["POST"]);
+    }
+
+    #[test]
+    fn test_fetch_retry_backoff_bounded_by_timeout() {
+        // No fetch time budget, and a backoff longer than the whole execution
+        let flaky = Arc::new(FlakyClient { failures: 10, attempts: Mutex::new(Vec::new()) });
+        let options = ExecutionOptions {
+            http_client: Some(flaky.clone()),
+            fetch_retry: Some(RetryPolicy { max_retries: 10, base_backoff_ms: 2000 }),
+            ..Default::default()
+        };
+        let code = r#"return (await fetch("https://api.example.com/items")).status;"#;
+        let domains = ["api.example.com"];
+        let started = Instant::now();
+        let result = execute_js(code, 500, 10 * 1024 * 1024, &domains, None, &options).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(result.value, serde_json::json!(503));
+        assert_eq!(flaky.attempts.lock().unwrap().len(), 1);
+    }
+
+    /// Takes 400ms per attempt and answers 503, or times out first if the request's
+    /// timeout is shorter
+    #[derive(Default)]
+    struct SlowFailingClient {
+        timeouts: Mutex<Vec<Option<Duration>>>,
+    }
+
+    impl crate::http::HttpClient for SlowFailingClient {
+        fn send(
+            &self,
+            request: crate::http::HttpRequest,
+        ) -> std::result::Result<crate::http::HttpResponse, crate::http::HttpError> {
+            self.timeouts.lock().unwrap().push(request.timeout);
+            let work = Duration::from_millis(400);
+            match request.timeout.filter(|timeout| *timeout < work) {
+                Some(timeout) => {
+                    std::thread::sleep(timeout);
+                    Err(crate::http::HttpError::Timeout("operation timed out".to_string()))
+                }
+                None => {
+                    std::thread::sleep(work);
+                    let headers = Vec::new();
+                    Ok(crate::http::HttpResponse { status: 503, headers, body: Vec::new() })
+                }
+            }
         }
     }
 
-    // Tests for unhandled exceptions and runtime errors
     #[test]
-    fn test_accessing_property_on_undefined() {
-        let code = r#"
-            const obj = undefined;
-            return obj.name;
-        "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("TypeError") || err.contains("undefined"));
+    fn test_fetch_retries_stay_within_time_budget() {
+        let client = Arc::new(SlowFailingClient::default());
+        let options = ExecutionOptions {
+            http_client: Some(client.clone()),
+            fetch_time_budget: true,
+            fetch_retry: Some(RetryPolicy { max_retries: 5, base_backoff_ms: 50 }),
+            ..Default::default()
+        };
+        let code = r#"return (await fetch("https://api.example.com/items")).status;"#;
+        let domains = ["api.example.com"];
+        let started = Instant::now();
+        let error = execute_js(code, 1000, 10 * 1024 * 1024, &domains, None, &options).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(ExecutionError::Timeout)), "got {}", error);
+        // The last attempt ends with the budget, well before the watchdog's grace period
+        assert!(started.elapsed() < Duration::from_millis(1150), "took {:?}", started.elapsed());
+
+        // Every retry is bounded by what is left of the budget, not the first attempt's share
+        let timeouts = client.timeouts.lock().unwrap();
+        assert_eq!(timeouts.len(), 3);
+        assert!(timeouts.windows(2).all(|pair| pair[1] < pair[0]), "{:?}", timeouts);
     }
 
     #[test]
-    fn test_accessing_property_on_null() {
+    fn test_checkpoint_returned_on_timeout() {
         let code = r#"
-            const data = null;
-            return data.value;
+            console.log("starting");
+            emit({ stage: "loop" });
+            let count = 0;
+            while (true) {
+                count++;
+                if (count % 1000 === 0) checkpoint({ count });
+            }
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("TypeError") || err.contains("null"));
+        let result = execute_js(code, 200, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert!(result.timed_out);
+        let count = result.value["count"].as_u64().unwrap();
+        assert!(count >= 1000 && count.is_multiple_of(1000), "count was {}", count);
+
+        // What the run produced before the deadline is reported alongside the checkpoint
+        assert!(!result.console_output.is_empty());
+        assert!(result.console_output[0].contains("starting"), "{:?}", result.console_output);
+        assert_eq!(result.emitted, vec![serde_json::json!({ "stage": "loop" })]);
+
+        // Without a checkpoint a timeout is still an error
+        let error = execute_js("while (true) {}", 200, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default())
+            .unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(ExecutionError::Timeout)));
+    }
+
+    /// Answers every request with the same body
+    struct StaticClient {
+        body: Vec<u8>,
+    }
+
+    impl crate::http::HttpClient for StaticClient {
+        fn send(
+            &self,
+            _request: crate::http::HttpRequest,
+        ) -> std::result::Result<crate::http::HttpResponse, crate::http::HttpError> {
+            Ok(crate::http::HttpResponse { status: 200, headers: Vec::new(), body: self.body.clone() })
+        }
     }
 
     #[test]
-    fn test_accessing_nested_undefined_properties() {
+    fn test_fetch_body_chunk_reader() {
+        // Multi-byte characters straddle the 16 KiB chunk boundaries
+        let text = "héllo wörld ✓ ".repeat(3000);
+        let options = ExecutionOptions {
+            http_client: Some(Arc::new(StaticClient { body: text.clone().into_bytes() })),
+            max_response_bytes: Some(64 * 1024),
+            ..Default::default()
+        };
         let code = r#"
-            const data = { user: { name: 'John' } };
-            return data.user.profile.nested.value;
+            const response = await fetch("https://api.example.com/large");
+            const reader = response.body.getReader();
+            const decoder = new TextDecoder();
+            const sizes = [];
+            let text = "";
+            while (true) {
+                const { done, value } = await reader.read();
+                if (done) break;
+                sizes.push(value.length);
+                text += decoder.decode(value, { stream: true });
+            }
+            text += decoder.decode();
+
+            let iterated = 0;
+            for await (const chunk of response.clone().body) iterated += chunk.length;
+            return { text, sizes, iterated };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("TypeError") || err.contains("undefined"));
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(result.value["text"], serde_json::json!(text));
+        assert_eq!(result.value["sizes"], serde_json::json!([16384, 16384, 16384, text.len() - 3 * 16384]));
+        assert_eq!(result.value["iterated"], serde_json::json!(text.len()));
+
+        // Bodies over the cap are rejected rather than buffered into the script
+        let options = ExecutionOptions { max_response_bytes: Some(1024), ..options };
+        let code = r#"
+            try { await fetch("https://api.example.com/large"); return "fetched"; }
+            catch (e) { return e.message; }
+        "#;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!(format!(
+                "Response body exceeds maximum size of 1024 bytes ({} bytes)",
+                text.len()
+            ))
+        );
     }
 
     #[test]
-    fn test_reference_error_undefined_variable() {
+    fn test_clock_offset() {
+        let options = ExecutionOptions { clock_offset_ms: 3_600_000, ..Default::default() };
         let code = r#"
-            return undefinedVariable;
+            return {
+                now: Date.now(),
+                constructed: new Date().getTime(),
+                explicit: new Date(0).getTime(),
+                isDate: new Date() instanceof Date,
+                string: typeof Date(),
+            };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("ReferenceError") || err.contains("not defined"));
+        let expected = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as i64
+            + 3_600_000;
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        for key in ["now", "constructed"] {
+            let shifted = result.value[key].as_f64().unwrap() as i64;
+            assert!((shifted - expected).abs() < 1000, "{} was {}, expected about {}", key, shifted, expected);
+        }
+        assert_eq!(result.value["explicit"], serde_json::json!(0));
+        assert_eq!(result.value["isDate"], serde_json::json!(true));
+        assert_eq!(result.value["string"], serde_json::json!("string"));
     }
 
     #[test]
-    fn test_calling_non_function() {
+    fn test_track_global_access() {
+        let options = ExecutionOptions {
+            http_client: Some(Arc::new(StaticClient { body: b"ok".to_vec() })),
+            track_global_access: true,
+            allow_crypto: true,
+            ..Default::default()
+        };
         let code = r#"
-            const notAFunction = "string";
-            return notAFunction();
+            const id = crypto.randomUUID();
+            const response = await fetch("https://api.example.com/");
+            await fetch("https://api.example.com/again");
+            return { id, body: await response.text() };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("TypeError") || err.contains("not a function"));
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(result.value["id"].as_str().unwrap().len(), 36);
+        assert_eq!(result.value["body"], serde_json::json!("ok"));
+        assert_eq!(result.accessed_globals, vec!["crypto", "fetch"]);
+
+        // Nothing is recorded unless tracking is on
+        let options = ExecutionOptions { track_global_access: false, ..options };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert!(result.accessed_globals.is_empty());
+
+        // crypto is only installed when allowed
+        let options = ExecutionOptions { allow_crypto: false, ..options };
+        let result = execute_js("return typeof crypto;", 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!("undefined"));
     }
 
     #[test]
-    fn test_network_call_to_disallowed_domain() {
+    fn test_result_collection_limits() {
+        let options = ExecutionOptions {
+            max_array_length: Some(1000),
+            max_object_keys: Some(2),
+            ..Default::default()
+        };
+
+        // Top-level arrays take the manual conversion path
+        let code = "return Array.from({ length: 100000 }, (_, i) => i);";
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        let items = result.value.as_array().unwrap();
+        assert_eq!(items.len(), 1001);
+        assert_eq!(items[999], serde_json::json!(999));
+        assert_eq!(items[1000], serde_json::json!("[Truncated: 99000 more items]"));
+
+        // Nested collections go through JSON.stringify
         let code = r#"
-            try {
-                const response = await fetch("https://blocked-domain.com/api");
-                return { error: "should have failed" };
-            } catch (error) {
-                return { caught: true, message: error.message };
-            }
+            return {
+                list: Array.from({ length: 1005 }, (_, i) => i),
+                wide: { a: 1, b: 2, c: 3, d: 4 },
+            };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["allowed-domain.com"], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
-        let message = obj.get("message").unwrap().as_str().unwrap();
-        assert!(message.contains("not in the allowlist") || message.contains("allowlist"));
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value["list"].as_array().unwrap().len(), 1001);
+        assert_eq!(result.value["list"][1000], serde_json::json!("[Truncated: 5 more items]"));
+        assert_eq!(result.value["wide"], serde_json::json!({ "a": 1, "b": 2, "[Truncated]": 2 }));
     }
 
     #[test]
-    fn test_json_parse_error() {
+    fn test_compile_metrics() {
         let code = r#"
-            try {
-                return JSON.parse("{ invalid json }");
-            } catch (error) {
-                return { caught: true, type: error.constructor.name };
+            const fib = (n) => (n < 2 ? n : fib(n - 1) + fib(n - 2));
+            const squares = [];
+            for (let i = 0; i < 10; i++) {
+                squares.push(i * i);
             }
+            return { fib: fib(10), squares };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
-        assert_eq!(obj.get("type").unwrap(), &serde_json::json!("SyntaxError"));
+        let options = ExecutionOptions { compile_metrics: true, ..Default::default() };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value["fib"], serde_json::json!(55));
+        assert!(result.compile_time_ms.is_some_and(|ms| ms >= 0.0));
+        assert!(result.bytecode_bytes.is_some_and(|bytes| bytes > 0));
+
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!((result.compile_time_ms, result.bytecode_bytes), (None, None));
+    }
+
+    /// Answers 404 for paths containing "missing" and a JSON body otherwise
+    struct RoutingClient;
+
+    impl crate::http::HttpClient for RoutingClient {
+        fn send(
+            &self,
+            request: crate::http::HttpRequest,
+        ) -> std::result::Result<crate::http::HttpResponse, crate::http::HttpError> {
+            let (status, body) = if request.url.contains("missing") {
+                (404, "not found".to_string())
+            } else {
+                (200, r#"{"name":"widget","price":9}"#.to_string())
+            };
+            Ok(crate::http::HttpResponse { status, headers: Vec::new(), body: body.into_bytes() })
+        }
     }
 
     #[test]
-    fn test_array_out_of_bounds_access() {
+    fn test_fetch_json_with_default() {
+        let options =
+            ExecutionOptions { http_client: Some(Arc::new(RoutingClient)), ..Default::default() };
         let code = r#"
-            const arr = [1, 2, 3];
-            return arr[100].id;
+            const fallback = { name: "none" };
+            return {
+                found: await fetchJson("https://api.example.com/item", {}, fallback),
+                missing: await fetchJson("https://api.example.com/missing", {}, fallback),
+                blocked: await fetchJson("https://evil.example.net/item", {}, fallback),
+                noDefault: (await fetchJson("https://api.example.com/missing")) === undefined,
+            };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("TypeError") || err.contains("undefined"));
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "found": { "name": "widget", "price": 9 },
+                "missing": { "name": "none" },
+                "blocked": { "name": "none" },
+                "noDefault": true
+            })
+        );
     }
 
     #[test]
-    fn test_promise_rejection() {
+    fn test_coalesce_console() {
         let code = r#"
-            await Promise.reject(new Error("Promise rejected"));
+            for (let i = 0; i < 5; i++) console.log("msg");
+            console.log("other");
+            console.log("msg");
+            console.log("msg");
+            return null;
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("Promise rejected"));
+        let options = ExecutionOptions { coalesce_console: true, ..Default::default() };
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.console_output, vec!["[log] msg (x5)", "[log] other", "[log] msg (x2)"]);
+
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        assert_eq!(result.console_output.len(), 8);
     }
 
     #[test]
-    fn test_async_error_in_promise_chain() {
+    fn test_response_parse_csv() {
+        let csv = "sku,qty\nA-1,3\nB-2,\"1,000\"\n";
+        let options = ExecutionOptions {
+            http_client: Some(Arc::new(StaticClient { body: csv.as_bytes().to_vec() })),
+            ..Default::default()
+        };
         let code = r#"
-            const data = await Promise.resolve({ items: [] })
-                .then(d => d.items.find(i => i.id === 1))
-                .then(item => item.name);
-            return data;
+            const response = await fetch("https://api.example.com/stock.csv");
+            let unknown;
+            try { await response.parse("xml"); } catch (e) { unknown = `${e.name}: ${e.message}`; }
+            return { rows: await response.parse("csv"), unknown };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("TypeError") || err.contains("undefined"));
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "rows": [{ "sku": "A-1", "qty": "3" }, { "sku": "B-2", "qty": "1,000" }],
+                "unknown": "TypeError: Unknown response format 'xml'"
+            })
+        );
     }
 
     #[test]
-    fn test_division_by_zero() {
-        // Division by zero in JavaScript returns Infinity, which cannot be represented in JSON
+    fn test_console_disabled() {
+        let lines = Arc::new(Mutex::new(0));
+        let counter = lines.clone();
+        let options = ExecutionOptions {
+            console_enabled: false,
+            on_console_line: Some(Arc::new(Mutex::new(move |_: &str| *counter.lock().unwrap() += 1))),
+            ..Default::default()
+        };
         let code = r#"
-            return 1 / 0;
+            console.log("hidden", { a: 1 });
+            console.assert(false, "also hidden");
+            console.time("t");
+            console.timeEnd("t");
+            return "done";
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        // Infinity cannot be converted to JSON, so it should error
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("Invalid float") || err.contains("float"));
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!("done"));
+        assert!(result.console_output.is_empty());
+        assert_eq!(*lines.lock().unwrap(), 0);
     }
 
     #[test]
-    fn test_throw_custom_error() {
+    fn test_cidr_allowlist() {
+        let options = ExecutionOptions {
+            http_client: Some(Arc::new(StaticClient { body: b"ok".to_vec() })),
+            ..Default::default()
+        };
         let code = r#"
-            throw new Error("Custom error message");
+            const attempt = async (url) => {
+                try { return await (await fetch(url)).text(); } catch (e) { return e.message; }
+            };
+            return {
+                inside: await attempt("https://203.0.113.7/status"),
+                outside: await attempt("https://198.51.100.7/status"),
+                private: await attempt("http://10.1.2.3/"),
+                ipv6: await attempt("https://[2001:db8::1]/"),
+            };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("Custom error message"));
-    }
+        let allowed = ["203.0.113.0/24", "10.0.0.0/8", "2001:db8::/32"];
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &allowed, None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "inside": "ok",
+                "outside": "Domain '198.51.100.7' is not in the allowlist",
+                "private": "Requests to private IP ranges are not allowed",
+                "ipv6": "ok"
+            })
+        );
 
-    #[test]
-    fn test_throw_string() {
+        // CIDR entries never open up private, link-local or loopback addresses
         let code = r#"
-            throw "String error";
+            const attempt = async (url) => {
+                try { return await (await fetch(url)).text(); } catch (e) { return e.message; }
+            };
+            return [
+                await attempt("http://172.20.1.1/"),
+                await attempt("http://169.254.169.254/latest/meta-data/"),
+                await attempt("http://[::1]/"),
+                await attempt("http://[fd00::1]/"),
+                await attempt("http://[::ffff:10.0.0.1]/"),
+            ];
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        // String throws may have different formatting, just verify we got an error
-        assert!(result.is_err());
+        let allowed = ["172.16.0.0/12", "169.254.0.0/16", "::/0"];
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &allowed, None, &options).unwrap();
+        let blocked = "Requests to private IP ranges are not allowed";
+        assert_eq!(result.value, serde_json::json!([blocked, blocked, blocked, blocked, blocked]));
+
+        let error =
+            execute_js(code, 5000, 10 * 1024 * 1024, &["203.0.113.0/33"], None, &options).unwrap_err();
+        assert!(error.to_string().contains("Invalid allowlist CIDR '203.0.113.0/33'"), "{}", error);
     }
 
     #[test]
-    fn test_network_invalid_url() {
-        let code = r#"
-            try {
-                const response = await fetch("not-a-valid-url");
-                return { error: "should have failed" };
-            } catch (error) {
-                return { caught: true, message: error.message };
-            }
-        "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["example.com"], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
-        let message = obj.get("message").unwrap().as_str().unwrap();
-        assert!(message.contains("Invalid URL") || message.contains("URL"));
+    fn test_compile_once_run_many() {
+        let options = ExecutionOptions { compile_metrics: true, ..Default::default() };
+        let code = "const seen = globalThis.seen ?? 0; globalThis.seen = seen + 1;\n\
+                    return { doubled: input.n * 2, seen };";
+        let script = compile(code, 1000, 10 * 1024 * 1024, &options).unwrap();
+
+        for n in [1, 2, 21] {
+            let input = Some(serde_json::json!({ "n": n }));
+            let result = script.run(1000, 10 * 1024 * 1024, &[], input, &options).unwrap();
+            // Globals never carry over, since each run gets a fresh context
+            assert_eq!(result.value, serde_json::json!({ "doubled": n * 2, "seen": 0 }));
+            assert!(result.bytecode_bytes.unwrap() > 0);
+        }
+
+        let error = compile("return (", 1000, 10 * 1024 * 1024, &options).err().unwrap();
+        assert!(error.to_string().starts_with("JavaScript execution error"), "{}", error);
     }
 
     #[test]
-    fn test_network_blocked_localhost() {
+    fn test_secrets_redacted() {
+        let options = ExecutionOptions {
+            secrets: HashMap::from([("API_TOKEN".to_string(), "tok-s3cr3t".to_string())]),
+            ..Default::default()
+        };
         let code = r#"
-            try {
-                const response = await fetch("http://localhost:8080/secret");
-                return { error: "should have failed" };
-            } catch (error) {
-                return { caught: true, message: error.message };
-            }
+            console.log("token is " + secrets.API_TOKEN);
+            let frozen = true;
+            try { secrets.API_TOKEN = "other"; frozen = false; } catch (error) {}
+            return { auth: "Bearer " + secrets.API_TOKEN, frozen };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["localhost"], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
-        let message = obj.get("message").unwrap().as_str().unwrap();
-        assert!(message.contains("private IP"));
+        let result = execute_js(code, 1000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.console_output, vec!["[log] token is [REDACTED]"]);
+        let expected = serde_json::json!({ "auth": "Bearer [REDACTED]", "frozen": true });
+        assert_eq!(result.value, expected);
+
+        let code = "throw new Error('bad token ' + secrets.API_TOKEN);";
+        let error = execute_js(code, 1000, 10 * 1024 * 1024, &[], None, &options).unwrap_err();
+        assert!(error.to_string().contains("bad token [REDACTED]"), "{}", error);
+        assert!(!error.to_string().contains("tok-s3cr3t"));
     }
 
     #[test]
-    fn test_network_blocked_private_ip() {
+    fn test_fail_on_console_error() {
         let code = r#"
-            try {
-                const response = await fetch("http://192.168.1.1/admin");
-                return { error: "should have failed" };
-            } catch (error) {
-                return { caught: true, message: error.message };
-            }
+            console.error("config missing:", "region");
+            console.error("second failure");
+            let total = 0;
+            for (let i = 0; i < 1e9; i++) { total += i; }
+            return total;
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["192.168.1.1"], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
-        let message = obj.get("message").unwrap().as_str().unwrap();
-        assert!(message.contains("private IP"));
+
+        let options = ExecutionOptions { fail_on_console_error: true, ..Default::default() };
+        let error = execute_js(code, 5000, 10 * 1024 * 1024, &[], None, &options).unwrap_err();
+        assert_eq!(error.to_string(), "console.error called: config missing: region");
+        assert!(matches!(error.downcast_ref(), Some(ExecutionError::ConsoleError(_))));
+
+        // Without the flag console.error only records the line
+        let code = "console.error('config missing'); return 1;";
+        let result = execute_js(code, 1000, 10 * 1024 * 1024, &[], None, &Default::default());
+        let result = result.unwrap();
+        assert_eq!(result.value, serde_json::json!(1));
+        assert_eq!(result.console_output, vec!["[error] config missing"]);
     }
 
     #[test]
-    fn test_input_property_access_when_undefined() {
+    fn test_fetch_never_sends_cookies() {
+        let mock = Arc::new(MockClient::default());
+        let options = ExecutionOptions { http_client: Some(mock.clone()), ..Default::default() };
         let code = r#"
-            // input is undefined, accessing property should fail
-            return input.someProperty;
+            const response = await fetch("https://api.example.com/items", {
+                credentials: "include",
+                cache: "no-store",
+                keepalive: true,
+                mode: "cors",
+                headers: { "Cookie": "session=abc", "X-Trace": "1" },
+            });
+            return response.status;
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        assert!(err.contains("TypeError") || err.contains("undefined"));
+        let domains = ["api.example.com"];
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &domains, None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!(201));
+
+        let requests = mock.requests.lock().unwrap();
+        let headers = &requests[0].headers;
+        assert!(!headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("cookie")));
+        assert!(headers.contains(&("X-Trace".to_string(), "1".to_string())));
     }
 
     #[test]
-    fn test_malformed_json_response_simulation() {
-        // Simulate a case where JSON parsing fails on response
+    fn test_fetch_max_url_length() {
+        let mock = Arc::new(MockClient::default());
+        let options = ExecutionOptions {
+            http_client: Some(mock.clone()),
+            max_url_length: 64,
+            ..Default::default()
+        };
+        // "https://evil.example.net/?q=" is 28 characters
         let code = r#"
-            try {
-                const malformedJson = "<html>Not JSON</html>";
-                return JSON.parse(malformedJson);
-            } catch (error) {
-                return { caught: true, type: error.constructor.name, message: error.message };
-            }
+            const attempt = async (url) => {
+                try { await fetch(url); return "sent"; } catch (e) { return e.message; }
+            };
+            return {
+                over: await attempt("https://evil.example.net/?q=" + "a".repeat(37)),
+                under: await attempt("https://evil.example.net/?q=" + "a".repeat(36)),
+            };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
-        assert_eq!(obj.get("type").unwrap(), &serde_json::json!("SyntaxError"));
+        let domains = ["api.example.com"];
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &domains, None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "over": "URL exceeds maximum length of 64 characters (65 characters)",
+                "under": "Domain 'evil.example.net' is not in the allowlist"
+            })
+        );
+        assert!(mock.requests.lock().unwrap().is_empty());
     }
 
     #[test]
-    fn test_stack_overflow() {
+    fn test_console_sequence_across_tasks() {
+        let options = ExecutionOptions { structured_console: true, ..Default::default() };
         let code = r#"
-            function recursive() {
-                return recursive();
-            }
-            return recursive();
+            const slow = Promise.resolve()
+                .then(() => Promise.resolve())
+                .then(() => console.log("slow"));
+            const fast = Promise.resolve().then(() => console.log("fast"));
+            console.log("sync");
+            await Promise.all([slow, fast]);
+            console.log("done");
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None);
-        assert!(result.is_err());
-        let err = result.unwrap_err().to_string();
-        // Should get stack overflow or max stack size exceeded
-        assert!(err.contains("stack") || err.contains("InternalError") || err.contains("recursion"));
+        let result = execute_js(code, 1000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        let entries: Vec<(u64, &str)> = result
+            .structured_console
+            .iter()
+            .map(|entry| (entry.sequence, entry.message.as_str()))
+            .collect();
+        assert_eq!(entries, vec![(0, "sync"), (1, "fast"), (2, "slow"), (3, "done")]);
+        let lines = vec!["[log] sync", "[log] fast", "[log] slow", "[log] done"];
+        assert_eq!(result.console_output, lines);
     }
 
-    #[test]
-    fn test_try_catch_handles_error_gracefully() {
-        let code = r#"
-            try {
-                const obj = undefined;
-                return obj.name;
-            } catch (error) {
-                return { caught: true, errorType: error.constructor.name, message: error.message };
+    /// Yields a few bytes per read with a delay, like an upstream trickling its body
+    struct SlowReader {
+        remaining: usize,
+    }
+
+    impl std::io::Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.remaining == 0 {
+                return Ok(0);
             }
-        "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("caught").unwrap(), &serde_json::json!(true));
-        assert_eq!(obj.get("errorType").unwrap(), &serde_json::json!("TypeError"));
+            std::thread::sleep(Duration::from_millis(20));
+            let read = buf.len().min(self.remaining).min(8);
+            buf[..read].fill(b'x');
+            self.remaining -= read;
+            Ok(read)
+        }
+    }
+
+    #[derive(Default)]
+    struct SlowClient {
+        outcome: Mutex<Option<String>>,
+    }
+
+    impl crate::http::HttpClient for SlowClient {
+        fn send(
+            &self,
+            request: crate::http::HttpRequest,
+        ) -> std::result::Result<crate::http::HttpResponse, crate::http::HttpError> {
+            // About five seconds of body at 8 bytes per 20ms
+            let reader = SlowReader { remaining: 2000 };
+            let body = crate::http::read_body(reader, request.deadline, None);
+            *self.outcome.lock().unwrap() = Some(match &body {
+                Ok(bytes) => format!("read {} bytes", bytes.len()),
+                Err(e) => e.to_string(),
+            });
+            Ok(crate::http::HttpResponse { status: 200, headers: Vec::new(), body: body? })
+        }
     }
 
     #[test]
-    fn test_multiple_errors_first_one_caught() {
+    fn test_slow_response_body_aborts_at_deadline() {
+        let client = Arc::new(SlowClient::default());
+        let options = ExecutionOptions { http_client: Some(client.clone()), ..Default::default() };
+        let code = r#"const response = await fetch("https://api.example.com/slow");
+            return await response.text();"#;
+        let domains = ["api.example.com"];
+        let started = Instant::now();
+        let error = execute_js(code, 300, 10 * 1024 * 1024, &domains, None, &options).unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(ExecutionError::Timeout)), "got {}", error);
+
+        // The transport stopped reading at the deadline instead of draining the body
+        let waiting = || client.outcome.lock().unwrap().is_none();
+        while waiting() && started.elapsed() < Duration::from_secs(2) {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let outcome = client.outcome.lock().unwrap().clone();
+        assert_eq!(
+            outcome.as_deref(),
+            Some("HTTP request failed: response body incomplete at the deadline")
+        );
+    }
+
+    /// Serves the same kind of body as the last path segment names
+    struct ContentTypeClient;
+
+    impl crate::http::HttpClient for ContentTypeClient {
+        fn send(
+            &self,
+            request: crate::http::HttpRequest,
+        ) -> std::result::Result<crate::http::HttpResponse, crate::http::HttpError> {
+            let (content_type, body) = match request.url.rsplit('/').next() {
+                Some("json") => ("application/json; charset=utf-8", br#"{"id":1}"#.to_vec()),
+                Some("text") => ("text/plain", b"hello".to_vec()),
+                _ => ("application/octet-stream", vec![0, 1, 2, 255]),
+            };
+            let headers = vec![("content-type".to_string(), content_type.to_string())];
+            Ok(crate::http::HttpResponse { status: 200, headers, body })
+        }
+    }
+
+    #[test]
+    fn test_response_data_by_content_type() {
+        let client = Arc::new(ContentTypeClient);
+        let options = ExecutionOptions { http_client: Some(client), ..Default::default() };
         let code = r#"
-            try {
-                throw new Error("First error");
-                throw new Error("Second error");
-            } catch (error) {
-                return { message: error.message };
-            }
+            const data = async (path) => (await fetch("https://api.example.com/" + path)).data();
+            return { json: await data("json"), text: await data("text"), bin: await data("bin") };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("message").unwrap(), &serde_json::json!("First error"));
+        let domains = ["api.example.com"];
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &domains, None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({ "json": { "id": 1 }, "text": "hello", "bin": "AAEC/w==" })
+        );
     }
 
     #[test]
-    fn test_user_return_skip_reason() {
+    fn test_fetch_allowed_ports() {
+        let mock = Arc::new(MockClient::default());
+        let options = ExecutionOptions {
+            http_client: Some(mock.clone()),
+            allowed_ports: vec![443],
+            ..Default::default()
+        };
         let code = r#"
+            const attempt = async (url) => {
+                try { await fetch(url); return "sent"; } catch (e) { return e.message; }
+            };
             return {
-                skip_reason: "user_cancelled",
-                additional_data: "some info"
+                admin: await attempt("https://api.example.com:8080/admin"),
+                plain: await attempt("http://api.example.com/items"),
+                tls: await attempt("https://api.example.com/items"),
+                pinned: await attempt("https://hooks.example.org:8443/notify"),
             };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("skip_reason").unwrap(), &serde_json::json!("user_cancelled"));
-        assert_eq!(obj.get("additional_data").unwrap(), &serde_json::json!("some info"));
+        let domains = ["api.example.com", "https://hooks.example.org:8443/notify"];
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &domains, None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!({
+                "admin": "Port 8080 on 'api.example.com' is not allowed",
+                "plain": "Port 80 on 'api.example.com' is not allowed",
+                "tls": "sent",
+                "pinned": "sent"
+            })
+        );
+        assert_eq!(mock.requests.lock().unwrap().len(), 2);
     }
 
     #[test]
-    fn test_user_return_error_reason() {
+    fn test_console_group_indentation() {
         let code = r#"
-            return {
-                error_reason: "validation_failed",
-                details: "Missing required field"
-            };
+            console.log("start");
+            console.group("outer");
+            console.log("in outer");
+            console.group();
+            console.log("nested", 2);
+            console.groupEnd();
+            console.groupEnd();
+            console.groupEnd();
+            console.log("end");
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("error_reason").unwrap(), &serde_json::json!("validation_failed"));
-        assert_eq!(obj.get("details").unwrap(), &serde_json::json!("Missing required field"));
+        let result = execute_js(code, 1000, 10 * 1024 * 1024, &[], None, &Default::default());
+        assert_eq!(
+            result.unwrap().console_output,
+            vec!["[log] start", "[log] outer", "[log]   in outer", "[log]     nested 2", "[log] end"]
+        );
     }
 
     #[test]
-    fn test_user_return_both_reasons() {
+    fn test_max_result_depth() {
+        let options = ExecutionOptions { max_result_depth: Some(3), ..Default::default() };
+        let expected = serde_json::json!({ "l1": { "l2": { "l3": "[depth limit reached]" } } });
+
+        // Ten levels of nesting, serialized by JSON.stringify
         let code = r#"
-            return {
-                skip_reason: "user_skip",
-                error_reason: "also_error",
-                data: 42
-            };
+            let value = { leaf: [1, 2] };
+            for (let level = 9; level >= 1; level--) { value = { ["l" + level]: value }; }
+            return value;
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert_eq!(obj.get("skip_reason").unwrap(), &serde_json::json!("user_skip"));
-        assert_eq!(obj.get("error_reason").unwrap(), &serde_json::json!("also_error"));
-        assert_eq!(obj.get("data").unwrap(), &serde_json::json!(42));
+        let result = execute_js(code, 1000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, expected);
+
+        // A BigInt makes JSON.stringify throw, so this goes through the manual conversion
+        let code = r#"
+            let value = { leaf: 10n };
+            for (let level = 9; level >= 1; level--) { value = { ["l" + level]: value }; }
+            return value;
+        "#;
+        let result = execute_js(code, 1000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(result.value, expected);
+
+        let code = "return [[1, [2]], { a: [] }];";
+        let options = ExecutionOptions { max_result_depth: Some(2), ..Default::default() };
+        let result = execute_js(code, 1000, 10 * 1024 * 1024, &[], None, &options).unwrap();
+        assert_eq!(
+            result.value,
+            serde_json::json!([[1, "[depth limit reached]"], { "a": "[depth limit reached]" }])
+        );
     }
 
     #[test]
-    fn test_user_return_nothing() {
+    fn test_secrets_redacted_from_diagnostics_and_metadata() {
+        let mock = Arc::new(MockClient::default());
+        let sink = DiagnosticsSink::default();
+        let options = ExecutionOptions {
+            secrets: HashMap::from([("API_TOKEN".to_string(), "tok-s3cr3t".to_string())]),
+            http_client: Some(mock.clone()),
+            diagnostics: Some(sink.clone()),
+            ..Default::default()
+        };
         let code = r#"
-            // Don't return anything
+            await fetch("https://api.example.com/items?token=" + secrets.API_TOKEN);
+            return { ok: true, [Symbol.for("auth")]: secrets.API_TOKEN };
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        // Should return null/undefined
-        assert!(result.value.is_null());
+        let domains = ["api.example.com"];
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &domains, None, &options).unwrap();
+        assert_eq!(result.metadata["auth"], serde_json::json!("[REDACTED]"));
+
+        // The transport still gets the real token; only what is reported back is masked
+        assert!(mock.requests.lock().unwrap()[0].url.ends_with("token=tok-s3cr3t"));
+        let fetches = sink.lock().unwrap().fetches.clone();
+        assert_eq!(fetches[0].url, "https://api.example.com/items?token=[REDACTED]");
     }
 
     #[test]
-    fn test_user_return_empty_object() {
+    fn test_execution_logs_carry_caller_span() {
+        #[derive(Clone, Default)]
+        struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+        impl std::io::Write for LogBuffer {
+            fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().extend_from_slice(bytes);
+                Ok(bytes.len())
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buffer = LogBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("execution", execution_id = "exec-span-test");
+            let _span = span.enter();
+            execute_js("return 1;", 5000, 10 * 1024 * 1024, &[], None, &ExecutionOptions::default()).unwrap();
+        });
+
+        let logs = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("Executing JavaScript code"))
+            .unwrap_or_else(|| panic!("no execution log in {:?}", logs));
+        assert!(line.contains("execution_id=\"exec-span-test\""), "{}", line);
+    }
+
+    /// Streams an endless body through `read_body`, as the real transport does
+    struct EndlessClient;
+
+    impl crate::http::HttpClient for EndlessClient {
+        fn send(
+            &self,
+            request: crate::http::HttpRequest,
+        ) -> std::result::Result<crate::http::HttpResponse, crate::http::HttpError> {
+            let reader = std::io::repeat(b'x');
+            let body = crate::http::read_body(reader, request.deadline, request.max_body_bytes)?;
+            Ok(crate::http::HttpResponse { status: 200, headers: Vec::new(), body })
+        }
+    }
+
+    #[test]
+    fn test_oversized_body_aborts_while_reading() {
+        let options = ExecutionOptions {
+            http_client: Some(Arc::new(EndlessClient)),
+            max_response_bytes: Some(64 * 1024),
+            ..Default::default()
+        };
         let code = r#"
-            return {};
+            try { await fetch("https://api.example.com/endless"); return "fetched"; }
+            catch (e) { return e.message; }
         "#;
-        let result = execute_js(code, 5000, 10 * 1024 * 1024, &[], None).unwrap();
-        let obj = result.value.as_object().unwrap();
-        assert!(obj.is_empty());
+        let started = Instant::now();
+        let result = execute_js(code, 5000, 10 * 1024 * 1024, &["api.example.com"], None, &options).unwrap();
+        assert_eq!(result.value, serde_json::json!("Response body exceeds maximum size of 65536 bytes"));
+        assert!(started.elapsed() < Duration::from_secs(1), "took {:?}", started.elapsed());
     }
 }